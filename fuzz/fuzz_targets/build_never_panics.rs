@@ -0,0 +1,28 @@
+#![no_main]
+
+// `build` has to come back with `Ok` or `Err` for any input, never panic or blow the
+// stack. `corpus/build_never_panics/crash-*` are inputs that used to.
+// run with `cargo +nightly fuzz run build_never_panics`
+use libfuzzer_sys::fuzz_target;
+use mongorph::{format_diagnostic, format_error, token_stats, MonGod};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(s) = std::str::from_utf8(data) else {
+        return;
+    };
+    let mut m = MonGod::new(s.to_string());
+    // whatever parses has to render too
+    match m.build() {
+        Ok(()) => {
+            assert_eq!(m.validate_mql(), Ok(()));
+            m.ast2mql();
+            m.to_pipeline_json_string();
+            m.explain();
+        }
+        Err(e) => {
+            format_diagnostic(s, &e);
+            format_error(s, &e);
+        }
+    }
+    token_stats(s);
+});
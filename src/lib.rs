@@ -1,29 +1,102 @@
-use std::iter::Peekable;
 use itertools::peek_nth;
 use itertools::PeekNth;
+use std::cell::Cell;
+use std::fmt::Write;
+use std::ops::Range;
+
+/// the parser's debug logging. only prints with the `trace` feature, and then to
+/// stderr, so stdout only ever has the output asked for
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if cfg!(feature = "trace") {
+            eprintln!($($arg)*);
+        }
+    };
+}
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum TokenT {
     Literal(String),
+    Str(String),
     Number(f64),
+    Bool(bool),
     Comparator(Comparator),
+    Not,
     OpenParen,
     CloseParen,
+    OpenBracket,
+    CloseBracket,
     Dot,
+    Colon,
+    Comma,
+    Semicolon,
     Match,
-    ConditionalOperator(ConditionalOperator)
+    Set,
+    Rename,
+    Unset,
+    Sort,
+    Limit,
+    Sample,
+    Require,
+    Project,
+    Facet,
+    Lookup,
+    Group,
+    If,
+    Then,
+    Else,
+    Text,
+    All,
+    ElemMatch,
+    Is,
+    Empty,
+    In,
+    Range,
+    Has,
+    Comment,
+    Matches,
+    ConditionalOperator(ConditionalOperator),
+    ArithmeticOperator(ArithmeticOperator),
+    /// whitespace or a `//` comment, only produced by `MonGod::tokenize_with_trivia`
+    Trivia(String)
 }
 
 #[derive(Debug, Clone)]
 pub struct Token {
     ty: TokenT,
     idx: usize,
+    end: usize,
 }
 
-#[derive(Debug, PartialEq)]
+impl Token {
+    // `end` gets filled in by the lexer once it knows how much it consumed
+    fn new(ty: TokenT, idx: usize) -> Self {
+        Self { ty, idx, end: idx }
+    }
+
+    pub fn ty(&self) -> &TokenT {
+        &self.ty
+    }
+
+    /// byte range of the token in the source
+    pub fn span(&self) -> Range<usize> {
+        self.idx..self.end
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum ASTNode {
     Literal(String),
+    /// `tags.length`, the number of elements in the array field `tags`
+    Length(String),
+    Str(String),
     Number(f64),
+    Bool(bool),
+    /// `null` in an `in [...]` list, everywhere else `null` is just a field name
+    Null,
+    /// `:p0`, standing in for the value at that index of the params from
+    /// `ast2mql_parameterized`. never comes out of the parser
+    Param(usize),
     Condition {
         op: Comparator,
         left: Box<ASTNode>,
@@ -33,391 +106,8073 @@ pub enum ASTNode {
         op: ConditionalOperator,
         conditions: Vec<Box<ASTNode>>
     },
+    Arithmetic {
+        op: ArithmeticOperator,
+        left: Box<ASTNode>,
+        right: Box<ASTNode>,
+    },
+    Cond {
+        condition: Box<ASTNode>,
+        then: Box<ASTNode>,
+        otherwise: Box<ASTNode>,
+    },
+    /// `text("coffee shop", language: es, case_sensitive: true)`
+    Text {
+        search: String,
+        language: Option<String>,
+        case_sensitive: Option<bool>,
+    },
+    /// `tags all [a, elem_match((qty > 5))]`, values are plain values or `ElemMatch`
+    All {
+        field: String,
+        values: Vec<ASTNode>,
+    },
+    /// `elem_match(...)` inside an `all` list, the condition is on the array element
+    ElemMatch(Box<ASTNode>),
+    /// `id in [1, 2, "x"]`, or `id in range(1, 3)` with the range already expanded
+    In {
+        field: String,
+        values: Vec<ASTNode>,
+    },
+    /// `email has value`, the field is there and isn't null
+    HasValue(String),
+    /// `name matches "^a"`, or `name matches field(pattern)` / `name matches $pattern`
+    /// with the pattern read from another field, or `name matches regex("^a", "i")`
+    /// with options. `pattern` is a `Str`, a `$` literal or a `Regex`
+    Matches {
+        field: String,
+        pattern: Box<ASTNode>,
+    },
+    /// `value type string` or `value type [string, int]`, matching any of the listed bson
+    /// types. `types` always holds at least one of the names in `TYPE_NAMES`
+    Type {
+        field: String,
+        types: Vec<String>,
+    },
+    /// `match(...).comment("why")`, only ever directly inside a `Match`. the comment
+    /// shows up in the server's profiler and logs
+    Comment {
+        condition: Box<ASTNode>,
+        comment: String,
+    },
     Match(Box<ASTNode>),
+    Set(Vec<(String, ASTNode)>),
+    Rename {
+        from: String,
+        to: String,
+    },
+    Unset(Vec<String>),
+    /// `sort(age desc, name)`, keys in priority order
+    Sort(Vec<(String, SortOrder)>),
+    Limit(u64),
+    /// `sample(100)`, that many documents picked at random
+    Sample(u64),
+    /// `require(name, email)`, a `$match` on `$jsonSchema` that only lets through
+    /// documents having every one of these top-level fields
+    Require(Vec<String>),
+    /// `project(email: 1, fullName: name, _id: 0)`, reshaping documents down to these
+    /// fields. `0`/`1` drop or keep a field, anything else computes its new value
+    Project(Vec<(String, ASTNode)>),
+    /// `slice(5)`/`slice(-5)` for the first/last 5 elements of an array, `slice(10, 5)`
+    /// for 5 elements after skipping 10. only ever a `Project` value
+    Slice {
+        skip: Option<i64>,
+        count: i64,
+    },
+    /// `regex("^a", "im")`, a pattern with `$options`, made of `imsx`. only ever the
+    /// pattern of a `Matches`
+    Regex {
+        pattern: String,
+        options: String,
+    },
+    /// `facet(byAge: [sort(age)], adults: [match(age >= 18).limit(5)])`, named
+    /// pipelines each run over the same input documents
+    Facet(Vec<(String, Vec<ASTNode>)>),
+    /// `lookup(from: orders, local: _id, foreign: userId, as: orders)`, every document
+    /// in `from` whose `foreign` equals this one's `local`, as an array in `as_`
+    Lookup {
+        from: String,
+        local: String,
+        foreign: String,
+        as_: String,
+    },
+    /// `group([branch, year], count: sum(1))`, one document per distinct key with each
+    /// accumulator worked out over that key's documents. the key is one field, several
+    /// in `[...]`, or `[]` for everything in one group
+    Group {
+        keys: Vec<String>,
+        accumulators: Vec<(String, ASTNode)>,
+    },
+    /// `sum(price)`, `avg(price * quantity)`, `push(name)`, ... only ever a `Group` value
+    Accumulator {
+        op: AccumulatorOperator,
+        arg: Box<ASTNode>,
+    },
     Unexpected
 }
 
-impl std::ops::Deref for ASTNode {
-    type Target = ASTNode;
+// no `Deref` here on purpose: peeling a `Match` implicitly through `&**node` while every
+// other variant deref'd to itself made it too easy to silently look at the wrong node
+impl ASTNode {
+    /// a field reference, `ASTNode::field("age")` is `age` in the dsl
+    pub fn field(name: &str) -> Self {
+        ASTNode::Literal(name.to_string())
+    }
 
-    fn deref(&self) -> &Self::Target {
-        match self {
-            ASTNode::Match(inner) => &**inner,
-            _ => self,
+    /// `field op value`, e.g. `ASTNode::condition("age", Comparator::GT, 18)`.
+    /// pass `ASTNode::field("$other")` as the value to compare against another field
+    pub fn condition(field: &str, op: Comparator, value: impl Into<ASTNode>) -> Self {
+        ASTNode::Condition {
+            op,
+            left: Box::new(ASTNode::field(field)),
+            right: Box::new(value.into()),
         }
     }
-}
 
-#[derive(Debug, PartialEq, Copy, Clone)]
-pub enum Comparator {
-    GTE,
-    GT,
-    EQ,
-    NEQ,
-    LT,
-    LTE
-}
+    pub fn and(conditions: Vec<ASTNode>) -> Self {
+        Self::group(ConditionalOperator::AND, conditions)
+    }
 
-#[derive(Debug, PartialEq, Copy, Clone)]
-pub enum ConditionalOperator {
-    AND,
-    OR
-}
+    pub fn or(conditions: Vec<ASTNode>) -> Self {
+        Self::group(ConditionalOperator::OR, conditions)
+    }
 
-#[derive(Debug)]
-pub enum ParseErrorT {
-    RHSofComparatorMustBeLiteralOrNumber,
-    NoDotBetweenFns,
-    InvalidBinopStructure,
-    Unexpected, // TODO: add the token that is unexpected later
-    UnmatchedParenthesis,
-    MissingComparator, 
-    MissingOpenParen,
-    EndOfTokenStream
-}
+    pub fn nor(conditions: Vec<ASTNode>) -> Self {
+        Self::group(ConditionalOperator::NOR, conditions)
+    }
 
-#[derive(Debug)]
-pub struct ParseError {
-    pub ty: ParseErrorT,
-    pub cursor: usize
-}
+    fn group(op: ConditionalOperator, conditions: Vec<ASTNode>) -> Self {
+        ASTNode::ConditionalOperator {
+            op,
+            conditions: conditions.into_iter().map(Box::new).collect(),
+        }
+    }
 
-pub struct MonGod {
-    s: String,
-    pub ast: Vec<ASTNode>,
-}
+    /// a `match` stage, trailing `_` because `match` is taken
+    pub fn match_(condition: ASTNode) -> Self {
+        ASTNode::Match(Box::new(condition))
+    }
 
-impl MonGod {
-    pub fn new(s: String) -> Self {
-        Self {
-            s,
-            ast: Vec::new(),
+    pub fn set(assignments: Vec<(&str, ASTNode)>) -> Self {
+        ASTNode::Set(assignments.into_iter().map(|(field, value)| (field.to_string(), value)).collect())
+    }
+
+    pub fn project(fields: Vec<(&str, ASTNode)>) -> Self {
+        ASTNode::Project(fields.into_iter().map(|(field, value)| (field.to_string(), value)).collect())
+    }
+
+    pub fn facet(branches: Vec<(&str, Vec<ASTNode>)>) -> Self {
+        ASTNode::Facet(branches.into_iter().map(|(name, stages)| (name.to_string(), stages)).collect())
+    }
+
+    /// a `group` stage, `_by` because `group` builds `&`/`|` groups
+    pub fn group_by(keys: Vec<&str>, accumulators: Vec<(&str, ASTNode)>) -> Self {
+        ASTNode::Group {
+            keys: keys.into_iter().map(str::to_string).collect(),
+            accumulators: accumulators.into_iter().map(|(name, value)| (name.to_string(), value)).collect(),
         }
     }
 
-    pub fn build(&mut self) -> Result<(), ParseError> {
-        let tokens = MonGod::tokenize(&self.s);
-        self.parse_tokens(&tokens)
+    /// `op(arg)` as a `group` value, e.g. `ASTNode::accumulator(AccumulatorOperator::SUM, 1)`
+    pub fn accumulator(op: AccumulatorOperator, arg: impl Into<ASTNode>) -> Self {
+        ASTNode::Accumulator { op, arg: Box::new(arg.into()) }
     }
 
-    fn tokenize(s: &str) -> Vec<Token> {
-        let mut tokens = Vec::new();
-        let mut chars = s.chars().peekable();
-        let mut idx: usize = 0;
-        while let Some(&c) = chars.peek() {
-            match c {
-                ' ' | '\t' | '\n' => {
-                    chars.next();
-                    idx+=1;
+    pub fn sort(keys: Vec<(&str, SortOrder)>) -> Self {
+        ASTNode::Sort(keys.into_iter().map(|(field, order)| (field.to_string(), order)).collect())
+    }
+
+    pub fn limit(n: u64) -> Self {
+        ASTNode::Limit(n)
+    }
+
+    pub fn sample(n: u64) -> Self {
+        ASTNode::Sample(n)
+    }
+
+    /// the node wrapped by a stage node (currently only `Match`), `None` for everything else
+    pub fn inner(&self) -> Option<&ASTNode> {
+        self.as_match()
+    }
+
+    /// the condition inside a `Match` stage, `None` if this isn't a match
+    pub fn as_match(&self) -> Option<&ASTNode> {
+        match self {
+            ASTNode::Match(inner) => Some(inner),
+            _ => None,
+        }
+    }
+
+    /// replaces every `$and`/`$or` holding exactly one condition with that condition.
+    /// a one-condition `$nor` is a negation, so it stays
+    pub fn collapse_single_child_groups(&mut self) {
+        match self {
+            ASTNode::ConditionalOperator { op, conditions } => {
+                for condition in conditions.iter_mut() {
+                    condition.collapse_single_child_groups();
                 }
-                '>' => {
-                    chars.next();
-                    if chars.peek() == Some(&'=') {
-                        chars.next();
-                        tokens.push(Token {ty: TokenT::Comparator(Comparator::GTE), idx});
-                        idx+=2;
-                    } else {
-                        tokens.push(Token {ty: TokenT::Comparator(Comparator::GT), idx});
-                        idx+=1;
-                    }
+                if conditions.len() == 1 && *op != ConditionalOperator::NOR {
+                    let only = *conditions.pop().unwrap();
+                    *self = only;
                 }
-                '<' => {
-                    chars.next();
-                    if chars.peek() == Some(&'=') {
-                        chars.next();
-                        tokens.push(Token {ty: TokenT::Comparator(Comparator::LTE), idx});
-                        idx+=2;
-                    } else {
-                        tokens.push(Token {ty: TokenT::Comparator(Comparator::LT), idx});
-                        idx+=1;
-                    }
+            }
+            ASTNode::Match(inner) => inner.collapse_single_child_groups(),
+            ASTNode::Comment { condition, .. } => condition.collapse_single_child_groups(),
+            ASTNode::Set(assignments) | ASTNode::Project(assignments) | ASTNode::Group { accumulators: assignments, .. } => {
+                for (_, value) in assignments.iter_mut() {
+                    value.collapse_single_child_groups();
                 }
-                '=' => {
-                    chars.next();
-                    if chars.peek() == Some(&'=') {
-                        chars.next();
-                        tokens.push(Token {ty: TokenT::Comparator(Comparator::EQ), idx});
-                        idx+=2;
+            }
+            ASTNode::Accumulator { arg, .. } => arg.collapse_single_child_groups(),
+            ASTNode::Cond { condition, then, otherwise } => {
+                condition.collapse_single_child_groups();
+                then.collapse_single_child_groups();
+                otherwise.collapse_single_child_groups();
+            }
+            ASTNode::All { values, .. } => {
+                for value in values.iter_mut() {
+                    value.collapse_single_child_groups();
+                }
+            }
+            ASTNode::ElemMatch(condition) => condition.collapse_single_child_groups(),
+            ASTNode::Facet(branches) => {
+                for (_, stages) in branches.iter_mut() {
+                    for stage in stages.iter_mut() {
+                        stage.collapse_single_child_groups();
                     }
                 }
-                '!' => {
-                    chars.next();
-                    if chars.peek() == Some(&'=') {
-                        chars.next();
-                        tokens.push(Token {ty: TokenT::Comparator(Comparator::NEQ), idx});
-                        idx+=2;
+            }
+            _ => {}
+        }
+    }
+
+    /// splices `&`s directly inside an `&` (and `|`s inside an `|`) into their parent,
+    /// so `&((a == 1)(&((b == 2)(c == 3))))` renders as one three element `$and`.
+    /// a `nor` inside a `nor` means something else, those stay
+    pub fn flatten_groups(&mut self) {
+        match self {
+            ASTNode::ConditionalOperator { op, conditions } => {
+                let mut flat = Vec::with_capacity(conditions.len());
+                for mut condition in conditions.drain(..) {
+                    condition.flatten_groups();
+                    match *condition {
+                        ASTNode::ConditionalOperator { op: inner_op, conditions: inner } if inner_op == *op && *op != ConditionalOperator::NOR => {
+                            flat.extend(inner);
+                        }
+                        other => flat.push(Box::new(other)),
                     }
                 }
-                '&' => {
-                    tokens.push(Token {ty: TokenT::ConditionalOperator(ConditionalOperator::AND), idx});
-                    chars.next();
-                    idx+=1;
+                *conditions = flat;
+            }
+            ASTNode::Match(inner) => inner.flatten_groups(),
+            ASTNode::Comment { condition, .. } => condition.flatten_groups(),
+            ASTNode::Set(assignments) | ASTNode::Project(assignments) | ASTNode::Group { accumulators: assignments, .. } => {
+                for (_, value) in assignments.iter_mut() {
+                    value.flatten_groups();
                 }
-                '|' => {
-                    tokens.push(Token {ty: TokenT::ConditionalOperator(ConditionalOperator::OR), idx});
-                    chars.next();
-                    idx+=1;
+            }
+            ASTNode::Accumulator { arg, .. } => arg.flatten_groups(),
+            ASTNode::Cond { condition, then, otherwise } => {
+                condition.flatten_groups();
+                then.flatten_groups();
+                otherwise.flatten_groups();
+            }
+            ASTNode::All { values, .. } => {
+                for value in values.iter_mut() {
+                    value.flatten_groups();
                 }
-                '(' => {
-                    tokens.push(Token {ty: TokenT::OpenParen, idx});
-                    chars.next();
-                    idx+=1;
+            }
+            ASTNode::ElemMatch(condition) => condition.flatten_groups(),
+            ASTNode::Facet(branches) => {
+                for (_, stages) in branches.iter_mut() {
+                    for stage in stages.iter_mut() {
+                        stage.flatten_groups();
+                    }
                 }
-                ')' => {
-                    tokens.push(Token {ty: TokenT::CloseParen, idx});
-                    chars.next();
-                    idx+=1;
+            }
+            _ => {}
+        }
+    }
+
+    /// simplifies `&`/`|` groups with constant conditions (`1 == 2`) in them. a false
+    /// one decides an `&` and a true one an `|`, the group becomes that condition. the
+    /// other way round they change nothing and are dropped, unless nothing else is left.
+    /// only comparisons between two constants count, `nor` is left alone
+    pub fn fold_constants(&mut self) {
+        match self {
+            ASTNode::ConditionalOperator { op, conditions } => {
+                for condition in conditions.iter_mut() {
+                    condition.fold_constants();
                 }
-                '.' => {
-                    tokens.push(Token {ty: TokenT::Dot, idx});
-                    chars.next();
-                    idx+=1;
+                let (decides, neutral) = match op {
+                    ConditionalOperator::AND => (false, true),
+                    ConditionalOperator::OR => (true, false),
+                    ConditionalOperator::NOR => return,
+                };
+                if let Some(i) = conditions.iter().position(|c| c.constant() == Some(decides)) {
+                    *self = *conditions.swap_remove(i);
+                } else if !conditions.is_empty() && conditions.iter().all(|c| c.constant() == Some(neutral)) {
+                    *self = *conditions.swap_remove(0);
+                } else {
+                    conditions.retain(|c| c.constant() != Some(neutral));
                 }
-                'a'..='z' | 'A'..='Z' | '_' => {
-                    let mut literal = String::new();
-                    while let Some(&c1) = chars.peek() {
-                        if c1.is_alphanumeric() || c1 == '_' || c1 == '.' {
-                            literal.push(c1);
-                            chars.next();
-                        } else {
-                            break;
-                        }
+            }
+            ASTNode::Match(inner) => inner.fold_constants(),
+            ASTNode::Comment { condition, .. } => condition.fold_constants(),
+            ASTNode::Set(assignments) | ASTNode::Project(assignments) | ASTNode::Group { accumulators: assignments, .. } => {
+                for (_, value) in assignments.iter_mut() {
+                    value.fold_constants();
+                }
+            }
+            ASTNode::Accumulator { arg, .. } => arg.fold_constants(),
+            ASTNode::Cond { condition, then, otherwise } => {
+                condition.fold_constants();
+                then.fold_constants();
+                otherwise.fold_constants();
+            }
+            ASTNode::All { values, .. } => {
+                for value in values.iter_mut() {
+                    value.fold_constants();
+                }
+            }
+            ASTNode::ElemMatch(condition) => condition.fold_constants(),
+            ASTNode::Facet(branches) => {
+                for (_, stages) in branches.iter_mut() {
+                    for stage in stages.iter_mut() {
+                        stage.fold_constants();
                     }
+                }
+            }
+            _ => {}
+        }
+    }
 
-                    if literal == "match" {
-                        tokens.push(Token { ty: TokenT::Match, idx});
-                        idx+=5;
-                    } else {
-                        let literal_size = literal.len();
-                        tokens.push(Token { ty: TokenT::Literal(literal), idx});
-                        idx+=literal_size;
-                    }
+    /// drops conditions that show up more than once in the same `&`/`|` group, keeping
+    /// the first. nested groups are deduped first, so duplicates that only differ in
+    /// their own duplicates still count as the same
+    pub fn dedupe_groups(&mut self) {
+        match self {
+            ASTNode::ConditionalOperator { op, conditions } => {
+                for condition in conditions.iter_mut() {
+                    condition.dedupe_groups();
                 }
-                '0'..='9' => {
-                    let mut number = String::new();
-                    while let Some(&c1) = chars.peek() {
-                        if c1.is_numeric() || c1 == '.' {
-                            number.push(c1);
-                            chars.next();
-                        } else {
-                            break;
+                if *op != ConditionalOperator::NOR {
+                    let mut kept: Vec<Box<ASTNode>> = Vec::with_capacity(conditions.len());
+                    for condition in conditions.drain(..) {
+                        if !kept.contains(&condition) {
+                            kept.push(condition);
                         }
                     }
-                    let number_size = number.len();
-                    tokens.push(Token { ty: TokenT::Number(number.parse().unwrap()), idx});
-                    idx+=number_size;
+                    *conditions = kept;
                 }
-                _ => {
-                    panic!("unexpected character while parsing string: {}", c);
+            }
+            ASTNode::Match(inner) => inner.dedupe_groups(),
+            ASTNode::Comment { condition, .. } => condition.dedupe_groups(),
+            ASTNode::Set(assignments) | ASTNode::Project(assignments) | ASTNode::Group { accumulators: assignments, .. } => {
+                for (_, value) in assignments.iter_mut() {
+                    value.dedupe_groups();
+                }
+            }
+            ASTNode::Accumulator { arg, .. } => arg.dedupe_groups(),
+            ASTNode::Cond { condition, then, otherwise } => {
+                condition.dedupe_groups();
+                then.dedupe_groups();
+                otherwise.dedupe_groups();
+            }
+            ASTNode::All { values, .. } => {
+                for value in values.iter_mut() {
+                    value.dedupe_groups();
+                }
+            }
+            ASTNode::ElemMatch(condition) => condition.dedupe_groups(),
+            ASTNode::Facet(branches) => {
+                for (_, stages) in branches.iter_mut() {
+                    for stage in stages.iter_mut() {
+                        stage.dedupe_groups();
+                    }
                 }
             }
+            _ => {}
         }
-        println!("{:#?}", tokens);
-        tokens
     }
 
-    fn parse_condition<I>(
-        iter: &mut PeekNth<I>,
-    ) -> Result<ASTNode, ParseError>
-    where
-        I: Iterator<Item = Token>,
-    {
-        match iter.peek() {
-            Some(Token{ ty: TokenT::ConditionalOperator(_), ..}) => {
-                println!("entering a conditional operator (AND/OR)");
-                let conditional_operation = Self::parse_logical_op(iter);
-                println!("log:conditional_operation: {:?}", conditional_operation);
-                conditional_operation
+    /// puts the conditions of every `&`/`|`/`nor` group in a fixed order, by the first
+    /// field they look at and then by how they're written, so queries that only differ
+    /// in the order of their conditions render the same mql. groups are sorted inside
+    /// out, so nested ones are already in order when their parent compares them
+    pub fn sort_groups(&mut self) {
+        match self {
+            ASTNode::ConditionalOperator { conditions, .. } => {
+                for condition in conditions.iter_mut() {
+                    condition.sort_groups();
+                }
+                conditions.sort_by_cached_key(|c| c.sort_key());
             }
-            Some(Token{ ty: TokenT::Literal(_), idx}) => {
-                let idx_clone = idx.clone();
-                if let Some(Token{ ty: TokenT::Literal(literal), ..}) = iter.next() {
-                    Ok(ASTNode::Literal(literal))
-                } else {
-                    println!("here1");
-                    Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: idx_clone})
+            ASTNode::Match(inner) => inner.sort_groups(),
+            ASTNode::Comment { condition, .. } => condition.sort_groups(),
+            ASTNode::Set(assignments) | ASTNode::Project(assignments) | ASTNode::Group { accumulators: assignments, .. } => {
+                for (_, value) in assignments.iter_mut() {
+                    value.sort_groups();
                 }
             }
-            Some(Token{ ty: TokenT::Number(_), idx}) => {
-                let idx_clone = idx.clone();
-                if let Some(Token{ ty: TokenT::Number(num), ..}) = iter.next() {
-                    Ok(ASTNode::Number(num))
-                } else {
-                    Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: idx_clone})
+            ASTNode::Accumulator { arg, .. } => arg.sort_groups(),
+            ASTNode::Cond { condition, then, otherwise } => {
+                condition.sort_groups();
+                then.sort_groups();
+                otherwise.sort_groups();
+            }
+            ASTNode::All { values, .. } => {
+                for value in values.iter_mut() {
+                    value.sort_groups();
                 }
             }
-            Some(Token{ ty: TokenT::OpenParen, idx}) => {
-                println!("parsing inside brackets");
-                iter.next();
-                println!("entering leftside of condition");
-                let left = Self::parse_condition(iter)?;
-                println!("log:left: {:?}", left);
-                let op = match iter.next() {
-                    Some(Token{ ty: TokenT::Comparator(cmp), ..}) => cmp,
-                    Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::MissingComparator, cursor: idx/*TODO*/}),
-                    None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/})
-                };
-                println!("log:comparator: {:?}", op);
-                println!("entering right side of condition");
-                let right = Self::parse_condition(iter)?;
-                println!("log:right: {:?}", right);
-                match iter.next() {
-                    Some(Token { ty: TokenT::CloseParen, ..}) => {
-                        let cond_node = Ok(ASTNode::Condition {
-                            op,
-                            left: Box::new(left),
-                            right: Box::new(right),
-                        });
-                        println!("log:cond_node: {:?}", cond_node);
-                        return cond_node;
-                    }
-                    Some(Token{idx, ..}) => {
-                        println!("here3");
-                        return Err(ParseError{ ty: ParseErrorT::UnmatchedParenthesis, cursor: idx /*TODO!!!*/});
-                    }
-                    None => {
-                        return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0 /*TODO!!!*/});
+            ASTNode::ElemMatch(condition) => condition.sort_groups(),
+            ASTNode::Facet(branches) => {
+                for (_, stages) in branches.iter_mut() {
+                    for stage in stages.iter_mut() {
+                        stage.sort_groups();
                     }
                 }
             }
-    
-            _ => {
-                println!("here6");
-                return Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: 0 /*TODO!!!*/});
-            }
+            _ => {}
         }
     }
-    
-    fn parse_logical_op<I>(
-        iter: &mut PeekNth<I>,
-    ) -> Result<ASTNode, ParseError>
-    where
-        I: Iterator<Item = Token>,
-    {
-        let op = match iter.next() {
-            Some(Token {ty: TokenT::ConditionalOperator(cond_op), idx}) => cond_op,
-            _ => panic!("expected conditional operator")
+
+    /// how many `text` searches a `Match` (or a condition on its own) has, mongo only
+    /// takes one
+    fn text_searches(&self) -> usize {
+        match self {
+            ASTNode::Text { .. } => 1,
+            ASTNode::Match(condition) | ASTNode::Comment { condition, .. } => condition.text_searches(),
+            ASTNode::ConditionalOperator { conditions, .. } => conditions.iter().map(|c| c.text_searches()).sum(),
+            _ => 0,
+        }
+    }
+
+    /// the fields a `Match` (or a condition on its own) filters on, each once, in order
+    /// of first appearance
+    pub fn referenced_fields(&self) -> Vec<&str> {
+        let condition = match self {
+            ASTNode::Match(condition) => condition,
+            condition => condition,
         };
-    
-        match iter.next() {
-            Some(Token {ty: TokenT::OpenParen, idx}) => {}
-            Some(Token { idx, ..}) => return Err(ParseError {ty: ParseErrorT::MissingOpenParen, cursor: idx/*TODO*/}),
-            None => return Err(ParseError {ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+        let mut fields = Vec::new();
+        MonGod::match_fields(condition, &mut fields);
+        let mut seen = Vec::with_capacity(fields.len());
+        for field in fields {
+            if !seen.contains(&field) {
+                seen.push(field);
+            }
         }
-        let mut conditions = Vec::new();
+        seen
+    }
 
-        loop {
-            let condition = Self::parse_condition(iter)?;
-            conditions.push(Box::new(condition));
-            println!("{:?}", conditions);
-            println!("{:?}", iter.peek());
-            match iter.peek() {
-                Some(Token{ ty: TokenT::CloseParen, ..}) => {
-                    iter.next();
-                    break;
+    /// swaps every value a condition compares against for a `Param`, pushing the value
+    /// onto `params`. field references and computed comparisons are left alone
+    fn extract_params(&mut self, params: &mut Vec<ASTNode>) {
+        match self {
+            ASTNode::Condition { left, right, .. } if !MonGod::needs_expr(left, right) => {
+                let value = match (&**left, &**right) {
+                    (ASTNode::Number(_) | ASTNode::Str(_) | ASTNode::Bool(_), ASTNode::Literal(_) | ASTNode::Length(_)) => left,
+                    (ASTNode::Literal(_) | ASTNode::Length(_), _) => right,
+                    _ => return,
+                };
+                value.take_param(params);
+            }
+            ASTNode::ConditionalOperator { conditions, .. } => {
+                for condition in conditions.iter_mut() {
+                    condition.extract_params(params);
                 }
-                Some(Token{ ty: TokenT::OpenParen, ..}) => {
-                    println!("{:?}", iter.peek_nth(1));
-                    if let Some(Token { ty: TokenT::ConditionalOperator(_), .. }) = iter.peek_nth(1) {
-                        iter.next();
+            }
+            ASTNode::Match(inner) => inner.extract_params(params),
+            ASTNode::Comment { condition, .. } => condition.extract_params(params),
+            ASTNode::All { values, .. } => {
+                for value in values.iter_mut() {
+                    match value {
+                        ASTNode::ElemMatch(condition) => condition.extract_params(params),
+                        value => value.take_param(params),
                     }
-                    continue;
                 }
-                Some(Token { idx, ..}) => {
-                    println!("here5");
-                    return Err(ParseError {ty: ParseErrorT::Unexpected, cursor: *idx /*TODO:handle index of this properly*/});
+            }
+            ASTNode::In { values, .. } => {
+                for value in values.iter_mut() {
+                    value.take_param(params);
                 }
-                None => {
-                    return Err(ParseError {ty: ParseErrorT::EndOfTokenStream, cursor: 0 /*TODO:handle index of this properly*/});
+            }
+            ASTNode::ElemMatch(condition) => condition.extract_params(params),
+            ASTNode::Facet(branches) => {
+                for (_, stages) in branches.iter_mut() {
+                    for stage in stages.iter_mut() {
+                        stage.extract_params(params);
+                    }
                 }
             }
+            _ => {}
         }
-        let ret_node = ASTNode::ConditionalOperator {
-            op,
-            conditions,
-        };
-        println!("{:?}", ret_node);
-        Ok(ret_node)
-    }    
+    }
 
-    fn parse_match<I>(
-        iter: &mut PeekNth<I>,
-    ) -> Result<ASTNode, ParseError>
-    where
-        I: Iterator<Item = Token>,
-    {
-        match iter.next() {
-            Some(Token{ ty: TokenT::Match, idx}) => {
-                match iter.next() {
-                    Some(Token{ ty: TokenT::OpenParen, ..}) => {}
-                    _ => return Err(ParseError{ ty: ParseErrorT::MissingOpenParen, cursor: idx}),
+    fn take_param(&mut self, params: &mut Vec<ASTNode>) {
+        if matches!(self, ASTNode::Str(_) | ASTNode::Number(_) | ASTNode::Bool(_) | ASTNode::Null)
+            || matches!(self, ASTNode::Literal(l) if !l.starts_with('$'))
+        {
+            params.push(std::mem::replace(self, ASTNode::Param(params.len())));
+        }
+    }
+
+    /// what `sort_groups` orders a condition by
+    fn sort_key(&self) -> (String, String) {
+        let mut fields = Vec::new();
+        MonGod::match_fields(self, &mut fields);
+        (fields.first().map_or_else(String::new, |f| f.to_string()), self.to_string())
+    }
+
+    /// what a comparison of two constants always comes out as
+    fn constant(&self) -> Option<bool> {
+        match self {
+            ASTNode::Condition { op, left, right } => MonGod::compare_constants(*op, left, right),
+            _ => None,
+        }
+    }
+
+    /// which variant this is, without the data
+    pub fn kind(&self) -> ASTNodeKind {
+        match self {
+            ASTNode::Literal(_) => ASTNodeKind::Literal,
+            ASTNode::Length(_) => ASTNodeKind::Length,
+            ASTNode::Str(_) => ASTNodeKind::Str,
+            ASTNode::Number(_) => ASTNodeKind::Number,
+            ASTNode::Bool(_) => ASTNodeKind::Bool,
+            ASTNode::Null => ASTNodeKind::Null,
+            ASTNode::Param(_) => ASTNodeKind::Param,
+            ASTNode::Condition { .. } => ASTNodeKind::Condition,
+            ASTNode::ConditionalOperator { .. } => ASTNodeKind::ConditionalOperator,
+            ASTNode::Arithmetic { .. } => ASTNodeKind::Arithmetic,
+            ASTNode::Cond { .. } => ASTNodeKind::Cond,
+            ASTNode::Text { .. } => ASTNodeKind::Text,
+            ASTNode::All { .. } => ASTNodeKind::All,
+            ASTNode::In { .. } => ASTNodeKind::In,
+            ASTNode::HasValue(_) => ASTNodeKind::HasValue,
+            ASTNode::Matches { .. } => ASTNodeKind::Matches,
+            ASTNode::Type { .. } => ASTNodeKind::Type,
+            ASTNode::Comment { .. } => ASTNodeKind::Comment,
+            ASTNode::ElemMatch(_) => ASTNodeKind::ElemMatch,
+            ASTNode::Match(_) => ASTNodeKind::Match,
+            ASTNode::Set(_) => ASTNodeKind::Set,
+            ASTNode::Rename { .. } => ASTNodeKind::Rename,
+            ASTNode::Unset(_) => ASTNodeKind::Unset,
+            ASTNode::Require(_) => ASTNodeKind::Require,
+            ASTNode::Project(_) => ASTNodeKind::Project,
+            ASTNode::Slice { .. } => ASTNodeKind::Slice,
+            ASTNode::Regex { .. } => ASTNodeKind::Regex,
+            ASTNode::Facet(_) => ASTNodeKind::Facet,
+            ASTNode::Lookup { .. } => ASTNodeKind::Lookup,
+            ASTNode::Group { .. } => ASTNodeKind::Group,
+            ASTNode::Accumulator { .. } => ASTNodeKind::Accumulator,
+            ASTNode::Sort(_) => ASTNodeKind::Sort,
+            ASTNode::Limit(_) => ASTNodeKind::Limit,
+            ASTNode::Sample(_) => ASTNodeKind::Sample,
+            ASTNode::Unexpected => ASTNodeKind::Unexpected,
+        }
+    }
+}
+
+/// a string converts to a string value, not a field, use `ASTNode::field` for that
+impl From<&str> for ASTNode {
+    fn from(s: &str) -> Self {
+        ASTNode::Str(s.to_string())
+    }
+}
+
+impl From<String> for ASTNode {
+    fn from(s: String) -> Self {
+        ASTNode::Str(s)
+    }
+}
+
+impl From<f64> for ASTNode {
+    fn from(n: f64) -> Self {
+        ASTNode::Number(n)
+    }
+}
+
+impl From<i64> for ASTNode {
+    fn from(n: i64) -> Self {
+        ASTNode::Number(n as f64)
+    }
+}
+
+impl From<i32> for ASTNode {
+    fn from(n: i32) -> Self {
+        ASTNode::Number(n as f64)
+    }
+}
+
+impl From<bool> for ASTNode {
+    fn from(b: bool) -> Self {
+        ASTNode::Bool(b)
+    }
+}
+
+/// one per `ASTNode` variant, for switching on node types without destructuring
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ASTNodeKind {
+    Literal,
+    Length,
+    Str,
+    Number,
+    Bool,
+    Null,
+    Param,
+    Condition,
+    ConditionalOperator,
+    Arithmetic,
+    Cond,
+    Text,
+    All,
+    ElemMatch,
+    In,
+    HasValue,
+    Matches,
+    Type,
+    Comment,
+    Match,
+    Set,
+    Rename,
+    Unset,
+    Sort,
+    Limit,
+    Sample,
+    Require,
+    Project,
+    Slice,
+    Regex,
+    Facet,
+    Lookup,
+    Group,
+    Accumulator,
+    Unexpected,
+}
+
+/// the node back in the dsl, in a form the parser reads back to an equal node
+impl std::fmt::Display for ASTNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ASTNode::Literal(l) => f.write_str(&dsl_name(l)),
+            // the whole thing goes in the backticks, `` `match`.length `` wouldn't lex as one name
+            ASTNode::Length(field) => f.write_str(&dsl_name(&format!("{}.length", field))),
+            ASTNode::Str(v) => f.write_str(&dsl_quote(v)),
+            ASTNode::Number(n) => write!(f, "{}", n),
+            ASTNode::Bool(b) => write!(f, "{}", b),
+            ASTNode::Null => f.write_str("null"),
+            ASTNode::Param(i) => write!(f, ":p{}", i),
+            ASTNode::Condition { op, left, right } => write!(f, "({} {} {})", left, op, right),
+            ASTNode::ConditionalOperator { op, conditions } => {
+                write!(f, "{}(", op)?;
+                for condition in conditions {
+                    match &**condition {
+                        ASTNode::ConditionalOperator { .. } | ASTNode::Text { .. } => write!(f, "({})", condition)?,
+                        _ => write!(f, "{}", condition)?,
+                    }
                 }
-    
-                let condition_chain = Self::parse_condition(iter)?;
-                println!("{:?}", condition_chain);
-                match iter.peek() {
-                    Some(Token{ ty: TokenT::CloseParen, idx}) => {
-                        iter.next();
-                        Ok(ASTNode::Match(Box::new(condition_chain)))
+                f.write_str(")")
+            }
+            ASTNode::Arithmetic { .. } | ASTNode::Cond { .. } => fmt_expression(self, f, 0),
+            ASTNode::Text { search, language, case_sensitive } => {
+                write!(f, "text({}", dsl_quote(search))?;
+                if let Some(language) = language {
+                    write!(f, ", language: {}", dsl_quote(language))?;
+                }
+                if let Some(case_sensitive) = case_sensitive {
+                    write!(f, ", case_sensitive: {}", case_sensitive)?;
+                }
+                f.write_str(")")
+            }
+            ASTNode::All { field, values } => {
+                write!(f, "({} all [", dsl_name(field))?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                f.write_str("])")
+            }
+            ASTNode::In { field, values } => {
+                write!(f, "({} in [", dsl_name(field))?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
                     }
-                    Some(Token {idx, ..}) => {
-                        println!("here4");
-                        return Err(ParseError{ ty: ParseErrorT::UnmatchedParenthesis, cursor: *idx/*TODO*/});
+                    write!(f, "{}", value)?;
+                }
+                f.write_str("])")
+            }
+            ASTNode::HasValue(field) => write!(f, "({} has value)", dsl_name(field)),
+            ASTNode::Matches { field, pattern } => write!(f, "({} matches {})", dsl_name(field), pattern),
+            ASTNode::Type { field, types } => match types.as_slice() {
+                [ty] => write!(f, "({} type {})", dsl_name(field), ty),
+                types => write!(f, "({} type [{}])", dsl_name(field), types.join(", ")),
+            },
+            ASTNode::ElemMatch(condition) => write!(f, "elem_match({})", Predicate(condition)),
+            ASTNode::Match(condition) => match &**condition {
+                ASTNode::Comment { condition, comment } => write!(f, "match({}).comment({})", Predicate(condition), dsl_quote(comment)),
+                condition => write!(f, "match({})", Predicate(condition)),
+            },
+            // the comment itself only has somewhere to go as part of the match above
+            ASTNode::Comment { condition, .. } => write!(f, "{}", condition),
+            ASTNode::Set(assignments) => {
+                f.write_str("set(")?;
+                for (i, (field, value)) in assignments.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
                     }
-                    None => {return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/});}
+                    write!(f, "{}: {}", dsl_name(field), value)?;
                 }
+                f.write_str(")")
             }
-            Some(Token {idx, ..}) => {
-                println!("here2");
-                return Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: idx/*0*/});
+            ASTNode::Project(fields) => {
+                f.write_str("project(")?;
+                for (i, (field, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}: {}", dsl_name(field), value)?;
+                }
+                f.write_str(")")
             }
-            None => {return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/});}
-        }
-    }
-    
-    pub fn parse_tokens(&mut self, tokens: &Vec<Token>) -> Result<(), ParseError>{
-        let mut nodes = Vec::new();
-        let mut iter = peek_nth(tokens.iter().cloned());
-        while let Some(t) = iter.peek() {
-            match t.ty {
-                TokenT::Match => {
-                    match Self::parse_match(&mut iter){
-                        Ok(node) => nodes.push(node),
-                        Err(e) => return Err(e)
-                    };
+            ASTNode::Slice { skip: Some(skip), count } => write!(f, "slice({}, {})", skip, count),
+            ASTNode::Slice { skip: None, count } => write!(f, "slice({})", count),
+            ASTNode::Group { keys, accumulators } => {
+                match keys.as_slice() {
+                    [key] => write!(f, "group({}", dsl_name(key))?,
+                    keys => {
+                        let keys: Vec<String> = keys.iter().map(|key| dsl_name(key)).collect();
+                        write!(f, "group([{}]", keys.join(", "))?
+                    }
+                }
+                for (name, value) in accumulators {
+                    write!(f, ", {}: {}", dsl_name(name), value)?;
                 }
-                _ => return Err(ParseError {ty: ParseErrorT::Unexpected, cursor: t.idx}),
+                f.write_str(")")
             }
-        }
-        self.ast = nodes;
-        Ok(())
-    }
-
-    pub fn ast2mql(&self) -> String {
-        let mut s = String::from("db.collection.aggregate{[");
-        for node in self.ast.iter() {
-            if let ASTNode::Match(inner) = node {
-                if let ASTNode::Condition { op, left, right } = &**inner {
-                    if let (ASTNode::Literal(left), ASTNode::Literal(right)) = (&**left, &**right) {
-                        let op_str = match op {
-                            Comparator::GTE => "$gte",
-                            Comparator::GT => "$gt",
-                            Comparator::EQ => "$eq",
-                            Comparator::NEQ => "$neq",
-                            Comparator::LT => "$lt",
-                            Comparator::LTE => "$lte",
-                        };
-                        s.push_str(&format!(
-                            "{{ $match: {{ {}: {{ {}: {} }} }} }},",
-                            left, op_str, right
-                        ));
+            ASTNode::Accumulator { op, arg } => write!(f, "{}({})", op.name(), arg),
+            ASTNode::Regex { pattern, options } if options.is_empty() => write!(f, "regex({})", dsl_quote(pattern)),
+            ASTNode::Regex { pattern, options } => write!(f, "regex({}, {})", dsl_quote(pattern), dsl_quote(options)),
+            ASTNode::Facet(branches) => {
+                f.write_str("facet(")?;
+                for (i, (name, stages)) in branches.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}: [", dsl_name(name))?;
+                    for (j, stage) in stages.iter().enumerate() {
+                        if j > 0 {
+                            f.write_str(".")?;
+                        }
+                        write!(f, "{}", stage)?;
                     }
+                    f.write_str("]")?;
                 }
-            } else {
-                panic!("Unexpected node type!");
+                f.write_str(")")
+            }
+            ASTNode::Rename { from, to } => write!(f, "rename({}, {})", dsl_name(from), dsl_name(to)),
+            ASTNode::Lookup { from, local, foreign, as_ } => write!(
+                f,
+                "lookup(from: {}, local: {}, foreign: {}, as: {})",
+                dsl_name(from),
+                dsl_name(local),
+                dsl_name(foreign),
+                dsl_name(as_)
+            ),
+            ASTNode::Unset(fields) => {
+                let fields: Vec<String> = fields.iter().map(|field| dsl_name(field)).collect();
+                write!(f, "unset({})", fields.join(", "))
+            }
+            ASTNode::Require(fields) => {
+                let fields: Vec<String> = fields.iter().map(|field| dsl_name(field)).collect();
+                write!(f, "require({})", fields.join(", "))
+            }
+            ASTNode::Sort(keys) => {
+                f.write_str("sort(")?;
+                for (i, (field, order)) in keys.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    match order {
+                        SortOrder::ASC => f.write_str(&dsl_name(field))?,
+                        SortOrder::DESC => write!(f, "{} desc", dsl_name(field))?,
+                    }
+                }
+                f.write_str(")")
             }
+            ASTNode::Limit(n) => write!(f, "limit({})", n),
+            ASTNode::Sample(n) => write!(f, "sample({})", n),
+            ASTNode::Unexpected => f.write_str("?"),
         }
-        s.push_str("]}");
-        s
     }
-}
\ No newline at end of file
+}
+
+/// the inside of `match(...)`/`elem_match(...)`, where a lone comparison drops its parens
+struct Predicate<'a>(&'a ASTNode);
+
+impl std::fmt::Display for Predicate<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rendered = self.0.to_string();
+        match self.0 {
+            ASTNode::Condition { .. } | ASTNode::All { .. } | ASTNode::In { .. } | ASTNode::HasValue(_) | ASTNode::Matches { .. }
+            | ASTNode::Type { .. } => f.write_str(&rendered[1..rendered.len() - 1]),
+            _ => f.write_str(&rendered),
+        }
+    }
+}
+
+/// an expression, parenthesized only where precedence needs it. `min_prec` is how
+/// tightly the surrounding operator binds
+fn fmt_expression(node: &ASTNode, f: &mut std::fmt::Formatter<'_>, min_prec: u8) -> std::fmt::Result {
+    let prec = match node {
+        ASTNode::Cond { .. } => 0,
+        ASTNode::Arithmetic { op: ArithmeticOperator::ADD | ArithmeticOperator::SUB, .. } => 1,
+        ASTNode::Arithmetic { .. } => 2,
+        _ => return write!(f, "{}", node),
+    };
+    if prec < min_prec {
+        f.write_str("(")?;
+    }
+    match node {
+        ASTNode::Arithmetic { op, left, right } => {
+            fmt_expression(left, f, prec)?;
+            write!(f, " {} ", op.symbol())?;
+            // left associative, so an equal-precedence right side keeps its parens
+            fmt_expression(right, f, prec + 1)?;
+        }
+        ASTNode::Cond { condition, then, otherwise } => {
+            write!(f, "if {} then ", condition)?;
+            fmt_expression(then, f, 0)?;
+            f.write_str(" else ")?;
+            fmt_expression(otherwise, f, 0)?;
+        }
+        _ => unreachable!(),
+    }
+    if prec < min_prec {
+        f.write_str(")")?;
+    }
+    Ok(())
+}
+
+/// a field name or bare value as the lexer would read it back, in backticks when it's
+/// a keyword or has characters a bare name can't
+fn dsl_name(name: &str) -> String {
+    let bare = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && name.chars().all(|c| c.is_alphanumeric() || matches!(c, '_' | '.' | '$'))
+        && MonGod::keyword(name).is_none();
+    if bare {
+        name.to_string()
+    } else {
+        format!("`{}`", name)
+    }
+}
+
+/// `v` as a dsl string literal, escaped the way `MonGod::lex_string` unescapes
+fn dsl_quote(v: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in v.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Comparator {
+    GTE,
+    GT,
+    EQ,
+    NEQ,
+    LT,
+    LTE
+}
+
+impl Comparator {
+    /// the comparator that means the same thing with the operands swapped
+    pub fn flipped(self) -> Self {
+        match self {
+            Comparator::GTE => Comparator::LTE,
+            Comparator::GT => Comparator::LT,
+            Comparator::EQ => Comparator::EQ,
+            Comparator::NEQ => Comparator::NEQ,
+            Comparator::LT => Comparator::GT,
+            Comparator::LTE => Comparator::GTE,
+        }
+    }
+
+    /// the query operator this comparator renders to. every output mode goes through
+    /// here so they can't disagree (`!=` used to come out as the nonexistent `$neq`)
+    pub fn mql_op(self) -> &'static str {
+        match self {
+            Comparator::GTE => "$gte",
+            Comparator::GT => "$gt",
+            Comparator::EQ => "$eq",
+            Comparator::NEQ => "$ne",
+            Comparator::LT => "$lt",
+            Comparator::LTE => "$lte",
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            Comparator::GTE => ">=",
+            Comparator::GT => ">",
+            Comparator::EQ => "==",
+            Comparator::NEQ => "!=",
+            Comparator::LT => "<",
+            Comparator::LTE => "<=",
+        }
+    }
+}
+
+/// the dsl spelling (`>=`), not the mql one, see `mql_op` for that
+impl std::fmt::Display for Comparator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.symbol())
+    }
+}
+
+/// the inverse of `Display`, `">=".parse::<Comparator>()`
+impl std::str::FromStr for Comparator {
+    type Err = UnknownOperator;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Comparator::GTE, Comparator::GT, Comparator::EQ, Comparator::NEQ, Comparator::LT, Comparator::LTE]
+            .into_iter()
+            .find(|op| op.symbol() == s)
+            .ok_or_else(|| UnknownOperator(s.to_string()))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ConditionalOperator {
+    AND,
+    OR,
+    /// none of the conditions hold, `nor((a == 1)(b == 2))`
+    NOR
+}
+
+impl ConditionalOperator {
+    pub fn mql_op(self) -> &'static str {
+        match self {
+            ConditionalOperator::AND => "$and",
+            ConditionalOperator::OR => "$or",
+            ConditionalOperator::NOR => "$nor",
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            ConditionalOperator::AND => "&",
+            ConditionalOperator::OR => "|",
+            ConditionalOperator::NOR => "nor",
+        }
+    }
+}
+
+/// the dsl spelling (`&`), not the mql one, see `mql_op` for that
+impl std::fmt::Display for ConditionalOperator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.symbol())
+    }
+}
+
+/// the inverse of `Display`, `"|".parse::<ConditionalOperator>()`
+impl std::str::FromStr for ConditionalOperator {
+    type Err = UnknownOperator;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [ConditionalOperator::AND, ConditionalOperator::OR, ConditionalOperator::NOR]
+            .into_iter()
+            .find(|op| op.symbol() == s)
+            .ok_or_else(|| UnknownOperator(s.to_string()))
+    }
+}
+
+/// a string that isn't the dsl spelling of any operator, from the `FromStr` impls
+#[derive(Debug, PartialEq, Clone)]
+pub struct UnknownOperator(pub String);
+
+impl std::fmt::Display for UnknownOperator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown operator `{}`", self.0)
+    }
+}
+
+impl std::error::Error for UnknownOperator {}
+
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ArithmeticOperator {
+    ADD,
+    SUB,
+    MUL,
+    DIV
+}
+
+impl ArithmeticOperator {
+    pub fn mql_op(self) -> &'static str {
+        match self {
+            ArithmeticOperator::ADD => "$add",
+            ArithmeticOperator::SUB => "$subtract",
+            ArithmeticOperator::MUL => "$multiply",
+            ArithmeticOperator::DIV => "$divide",
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            ArithmeticOperator::ADD => "+",
+            ArithmeticOperator::SUB => "-",
+            ArithmeticOperator::MUL => "*",
+            ArithmeticOperator::DIV => "/",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum SortOrder {
+    ASC,
+    DESC
+}
+
+impl SortOrder {
+    /// `1` or `-1`, as `$sort` wants it
+    pub fn mql_value(self) -> i8 {
+        match self {
+            SortOrder::ASC => 1,
+            SortOrder::DESC => -1,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum AccumulatorOperator {
+    SUM,
+    AVG,
+    MIN,
+    MAX,
+    PUSH,
+    ADDTOSET
+}
+
+impl AccumulatorOperator {
+    const ALL: [AccumulatorOperator; 6] = [
+        AccumulatorOperator::SUM,
+        AccumulatorOperator::AVG,
+        AccumulatorOperator::MIN,
+        AccumulatorOperator::MAX,
+        AccumulatorOperator::PUSH,
+        AccumulatorOperator::ADDTOSET,
+    ];
+
+    pub fn mql_op(self) -> &'static str {
+        match self {
+            AccumulatorOperator::SUM => "$sum",
+            AccumulatorOperator::AVG => "$avg",
+            AccumulatorOperator::MIN => "$min",
+            AccumulatorOperator::MAX => "$max",
+            AccumulatorOperator::PUSH => "$push",
+            AccumulatorOperator::ADDTOSET => "$addToSet",
+        }
+    }
+
+    /// how it's spelled in the dsl, `sum(...)`
+    fn name(self) -> &'static str {
+        match self {
+            AccumulatorOperator::SUM => "sum",
+            AccumulatorOperator::AVG => "avg",
+            AccumulatorOperator::MIN => "min",
+            AccumulatorOperator::MAX => "max",
+            AccumulatorOperator::PUSH => "push",
+            AccumulatorOperator::ADDTOSET => "add_to_set",
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            AccumulatorOperator::SUM => "the sum of",
+            AccumulatorOperator::AVG => "the average of",
+            AccumulatorOperator::MIN => "the smallest",
+            AccumulatorOperator::MAX => "the largest",
+            AccumulatorOperator::PUSH => "every",
+            AccumulatorOperator::ADDTOSET => "every distinct",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseErrorT {
+    RHSofComparatorMustBeLiteralOrNumber,
+    NoDotBetweenFns,
+    InvalidBinopStructure,
+    Unexpected, // TODO: add the token that is unexpected later
+    UnmatchedParenthesis,
+    MissingCloseParen,
+    MissingComparator, 
+    MissingOpenParen,
+    EndOfTokenStream,
+    InvalidFieldName,
+    DanglingDot,
+    MissingColon,
+    MissingThen,
+    MissingElse,
+    TooManyStages,
+    NumberTooLong,
+    IdentifierTooLong,
+    TooManyTokens,
+    InvalidSortOrder,
+    InvalidLimit,
+    InvalidSampleSize,
+    InvalidSlice,
+    MissingOpenBracket,
+    UnmatchedBracket,
+    InvalidNumber,
+    UnterminatedIdentifier,
+    InvalidRange,
+    RangeTooLarge,
+    InvalidComment,
+    UnknownType,
+    MissingLeftOperand,
+    MissingRightOperand,
+    UnexpectedCharacter,
+    TooDeeplyNested,
+    UnterminatedString,
+    InvalidEscape,
+    DuplicateText,
+    NestedFacet,
+    InvalidLookup,
+    InvalidRegexOptions,
+    InvalidAccumulator,
+    DuplicateGroupKey,
+    DuplicateFacetName
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub ty: ParseErrorT,
+    pub cursor: usize
+}
+
+impl std::fmt::Display for ParseErrorT {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let msg = match self {
+            ParseErrorT::RHSofComparatorMustBeLiteralOrNumber => "right side of a comparator must be a literal or a number",
+            ParseErrorT::NoDotBetweenFns => "expected `.` between stages",
+            ParseErrorT::InvalidBinopStructure => "invalid binary operation",
+            ParseErrorT::Unexpected => "unexpected token",
+            ParseErrorT::UnmatchedParenthesis => "unmatched parenthesis",
+            ParseErrorT::MissingCloseParen => "input ends before a `(` is closed",
+            ParseErrorT::MissingComparator => "expected a comparator",
+            ParseErrorT::MissingOpenParen => "expected `(`",
+            ParseErrorT::EndOfTokenStream => "unexpected end of input",
+            ParseErrorT::InvalidFieldName => "invalid field name",
+            ParseErrorT::DanglingDot => "expected a stage after `.`",
+            ParseErrorT::MissingColon => "expected `:` after field name",
+            ParseErrorT::MissingThen => "expected `then` after the `if` condition",
+            ParseErrorT::MissingElse => "expected `else` after the `then` branch",
+            ParseErrorT::TooManyStages => "query has more stages than allowed",
+            ParseErrorT::NumberTooLong => "number literal has more digits than allowed",
+            ParseErrorT::IdentifierTooLong => "name is longer than allowed",
+            ParseErrorT::TooManyTokens => "query has more tokens than allowed",
+            ParseErrorT::InvalidSortOrder => "sort order must be `asc` or `desc`",
+            ParseErrorT::InvalidLimit => "limit must be a positive whole number",
+            ParseErrorT::InvalidSampleSize => "sample size must be a positive whole number",
+            ParseErrorT::InvalidSlice => "`slice` takes whole numbers, and a count after a skip has to be positive",
+            ParseErrorT::MissingOpenBracket => "expected `[`",
+            ParseErrorT::UnmatchedBracket => "unmatched bracket",
+            ParseErrorT::InvalidNumber => "malformed number",
+            ParseErrorT::UnterminatedIdentifier => "backtick-quoted name is never closed",
+            ParseErrorT::InvalidRange => "range bounds must be smallest first, and whole numbers in `range(..)`",
+            ParseErrorT::RangeTooLarge => "range expands to too many values",
+            ParseErrorT::InvalidComment => "comment must be a string",
+            ParseErrorT::UnknownType => "not a bson type name",
+            ParseErrorT::MissingLeftOperand => "comparator has nothing on its left",
+            ParseErrorT::MissingRightOperand => "comparator has nothing on its right",
+            ParseErrorT::UnexpectedCharacter => "unexpected character",
+            ParseErrorT::TooDeeplyNested => "query is nested too deeply",
+            ParseErrorT::UnterminatedString => "string is never closed",
+            ParseErrorT::InvalidEscape => "unknown escape sequence",
+            ParseErrorT::DuplicateText => "a match can only contain one `text` search",
+            ParseErrorT::NestedFacet => "`facet` can't go inside another `facet`",
+            ParseErrorT::InvalidLookup => "`lookup` takes `from`, `local`, `foreign` and `as`, each exactly once",
+            ParseErrorT::InvalidRegexOptions => "regex options can only be `i`, `m`, `s` and `x`",
+            ParseErrorT::InvalidAccumulator => "a `group` value has to be `sum`, `avg`, `min`, `max`, `push` or `add_to_set` of something",
+            ParseErrorT::DuplicateGroupKey => "two `group` keys or values end up with the same name",
+            ParseErrorT::DuplicateFacetName => "two `facet` pipelines have the same name",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at index {}", self.ty, self.cursor)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// lets `?` on a build inside io-returning code (e.g. reading queries from files)
+impl From<ParseError> for std::io::Error {
+    fn from(e: ParseError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// a short note attached to a stretch of the source, see `ParseError::label`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub span: Range<usize>,
+    pub message: &'static str,
+}
+
+impl ParseErrorT {
+    /// what to say right under the offending source, the headline being `Display`
+    pub fn label(&self) -> &'static str {
+        match self {
+            ParseErrorT::RHSofComparatorMustBeLiteralOrNumber => "not a literal or a number",
+            ParseErrorT::NoDotBetweenFns => "add a `.` before this stage",
+            ParseErrorT::InvalidBinopStructure => "can't combine these",
+            ParseErrorT::Unexpected => "didn't expect this here",
+            ParseErrorT::UnmatchedParenthesis => "expected `)` here",
+            ParseErrorT::MissingCloseParen => "expected `)` by here",
+            ParseErrorT::MissingComparator => "expected `==`, `!=`, `<`, `<=`, `>` or `>=` here",
+            ParseErrorT::MissingOpenParen => "expected `(` here",
+            ParseErrorT::EndOfTokenStream => "input ends here",
+            ParseErrorT::InvalidFieldName => "not a valid field name",
+            ParseErrorT::DanglingDot => "nothing follows this `.`",
+            ParseErrorT::MissingColon => "expected `:` here",
+            ParseErrorT::MissingThen => "expected `then` here",
+            ParseErrorT::MissingElse => "expected `else` here",
+            ParseErrorT::TooManyStages => "this stage is over the limit",
+            ParseErrorT::NumberTooLong => "this number is too long",
+            ParseErrorT::IdentifierTooLong => "this name is too long",
+            ParseErrorT::TooManyTokens => "this token is over the limit",
+            ParseErrorT::InvalidSortOrder => "expected `asc` or `desc`",
+            ParseErrorT::InvalidLimit | ParseErrorT::InvalidSampleSize => "expected a positive whole number",
+            ParseErrorT::InvalidSlice => "not a valid slice argument",
+            ParseErrorT::MissingOpenBracket => "expected `[` here",
+            ParseErrorT::UnmatchedBracket => "expected `]` here",
+            ParseErrorT::InvalidNumber => "not a number",
+            ParseErrorT::UnterminatedIdentifier => "name starts here",
+            ParseErrorT::InvalidRange => "not a valid range",
+            ParseErrorT::RangeTooLarge => "this range is too long",
+            ParseErrorT::InvalidComment => "expected a quoted string",
+            ParseErrorT::UnknownType => "expected a type like `string`, `int` or `array`",
+            ParseErrorT::MissingLeftOperand => "expected a field before this",
+            ParseErrorT::MissingRightOperand => "expected a value after this",
+            ParseErrorT::UnexpectedCharacter => "not part of the language",
+            ParseErrorT::TooDeeplyNested => "nesting goes past the limit here",
+            ParseErrorT::UnterminatedString => "string starts here",
+            ParseErrorT::InvalidEscape => "unknown escape",
+            ParseErrorT::DuplicateText => "second `text` search",
+            ParseErrorT::NestedFacet => "this `facet` is inside another one",
+            ParseErrorT::InvalidLookup => "expected `from`, `local`, `foreign` or `as` here",
+            ParseErrorT::InvalidRegexOptions => "expected a string of `imsx`",
+            ParseErrorT::InvalidAccumulator => "expected `sum(...)`, `avg(...)`, `min(...)`, `max(...)`, `push(...)` or `add_to_set(...)` here",
+            ParseErrorT::DuplicateGroupKey => "this name is already taken",
+            ParseErrorT::DuplicateFacetName => "this name is already taken",
+        }
+    }
+}
+
+impl ParseError {
+    /// the error's label, spanning the whole token at `cursor` when `source` still
+    /// lexes that far, a single character otherwise
+    pub fn label(&self, source: &str) -> Label {
+        let message = self.ty.label();
+        if let ParseErrorT::EndOfTokenStream = self.ty {
+            // the parser doesn't track where it ran out, but it's always the end
+            return Label { span: source.len()..source.len(), message };
+        }
+        let token_end = MonGod::lex(source, false)
+            .ok()
+            .and_then(|tokens| tokens.iter().find(|t| t.idx == self.cursor).map(|t| t.end));
+        let end = match token_end {
+            Some(end) if end > self.cursor => end,
+            _ => self.cursor + 1,
+        };
+        Label { span: self.cursor..end.min(source.len()).max(self.cursor), message }
+    }
+}
+
+/// the plain one-caret rendering of `e` against the source it came from
+pub fn format_error(s: &str, e: &ParseError) -> String {
+    format_error_window(s, e, None)
+}
+
+/// `format_error`, except only `context` characters either side of the error column are
+/// shown, with `...` where the line was cut. for sources with very long lines
+pub fn format_error_windowed(s: &str, e: &ParseError, context: usize) -> String {
+    format_error_window(s, e, Some(context))
+}
+
+fn format_error_window(s: &str, e: &ParseError, context: Option<usize>) -> String {
+    let start = char_floor(s, e.cursor);
+    let lines: Vec<&str> = s.lines().collect();
+    // a cursor past the last line (or an empty source) points just after the end
+    let mut line_num = lines.len().max(1);
+    let mut byte_col = lines.last().map_or(0, |line| line.len());
+    let mut current_index = 0;
+
+    for (i, line) in lines.iter().enumerate() {
+        if current_index + line.len() >= start {
+            line_num = i + 1;
+            byte_col = start - current_index;
+            break;
+        }
+        current_index += line.len() + 1;
+    }
+    let error_line = lines.get(line_num - 1).unwrap_or(&"");
+    // columns count characters, like `format_diagnostic`'s
+    let col_num = error_line.get(..byte_col).unwrap_or(error_line).chars().count() + 1;
+    let (error_line, mut marker_line) = match context {
+        Some(context) => {
+            let (before, after) = error_line.split_at(byte_col.min(error_line.len()));
+            let skipped = before.chars().count().saturating_sub(context);
+            let before: String = before.chars().skip(skipped).collect();
+            let mut after_chars = after.chars();
+            let after: String = after_chars.by_ref().take(context + 1).collect();
+            let lead = if skipped > 0 { "..." } else { "" };
+            let trail = if after_chars.next().is_some() { "..." } else { "" };
+            let marker = format!("{}{}", " ".repeat(lead.len()), marker_indent(&before, before.len()));
+            (format!("{}{}{}{}", lead, before, after, trail), marker)
+        }
+        None => (error_line.to_string(), marker_indent(error_line, byte_col)),
+    };
+    marker_line.push('^');
+
+    format!(
+        "ParseError::{:?}\n   --> line {}, column {}\n   |\n{:3}| {}\n   | {}\n",
+        e.ty,
+        line_num,
+        col_num,
+        line_num,
+        error_line,
+        marker_line
+    )
+}
+
+/// `cursor` clamped to `s` and backed off to the start of the character it's inside,
+/// since `ParseError`s built by hand can point anywhere
+fn char_floor(s: &str, cursor: usize) -> usize {
+    let mut cursor = cursor.min(s.len());
+    while !s.is_char_boundary(cursor) {
+        cursor -= 1;
+    }
+    cursor
+}
+
+/// blanks lining up with the first `byte_col` bytes of `line`. tabs are kept as tabs,
+/// so the caret lands in the right place however wide the terminal draws them
+fn marker_indent(line: &str, byte_col: usize) -> String {
+    line.get(..byte_col)
+        .unwrap_or(line)
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect()
+}
+
+/// rustc-style rendering of `e`: the message, the offending line, and the label
+/// underlined beneath the span it covers
+///
+/// ```
+/// use mongorph::{format_diagnostic, MonGod};
+///
+/// let mut m = MonGod::new("match a == 1".to_string());
+/// let e = m.build().unwrap_err();
+/// assert_eq!(format_diagnostic(m.source(), &e), "\
+/// error: expected `(`
+///   --> line 1, column 7
+///   |
+/// 1 | match a == 1
+///   |       ^ expected `(` here
+/// ");
+/// ```
+pub fn format_diagnostic(s: &str, e: &ParseError) -> String {
+    let label = e.label(s);
+    let start = char_floor(s, label.span.start);
+    // the line holding the start of the span, and where that line starts
+    let line_start = s[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = s[line_start..].find('\n').map_or(s.len(), |i| line_start + i);
+    let line = &s[line_start..line_end];
+    let line_num = s[..line_start].matches('\n').count() + 1;
+    let col = s[line_start..start].chars().count();
+    // a span running past the end of the line only gets underlined up to it
+    let width = s
+        .get(start..label.span.end.min(line_end))
+        .map_or(0, |spanned| spanned.chars().count())
+        .max(1);
+
+    let gutter = " ".repeat(line_num.to_string().len());
+    format!(
+        "error: {}\n{} --> line {}, column {}\n{} |\n{} | {}\n{} | {}{} {}\n",
+        e.ty,
+        gutter,
+        line_num,
+        col + 1,
+        gutter,
+        line_num,
+        line,
+        gutter,
+        marker_indent(line, start - line_start),
+        "^".repeat(width),
+        label.message
+    )
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MqlError {
+    InvalidCollationStrength(u8),
+    /// something other than a stage at the top level of the pipeline
+    NotAStage(ASTNodeKind),
+    /// something other than a condition where one is filtered on
+    NotACondition(ASTNodeKind),
+    /// the field side of a condition isn't a field (`null == 6`)
+    NotAField(ASTNodeKind),
+    /// something other than a plain value where one is compared against
+    NotAValue(ASTNodeKind),
+    /// an `&`/`|`/`nor` with no conditions, mongo rejects the empty array
+    EmptyGroup(ConditionalOperator),
+    /// a `set`/`unset`/`require`/`project`/`sort`/`facet` with nothing in it, or a
+    /// `facet` pipeline without stages
+    EmptyStage(ASTNodeKind),
+    /// a `type [...]` with no type names
+    EmptyTypes,
+    /// a `project` dropping some fields (`0`) while keeping or computing others, which
+    /// mongo only allows for `_id`
+    MixedProjection,
+    /// a `slice(skip, count)` with a count below 1
+    InvalidSlice,
+    /// a `facet` inside one of another `facet`'s pipelines, which mongo rejects
+    NestedFacet,
+    /// a `regex` with an option other than `i`, `m`, `s` and `x`
+    InvalidRegexOptions(String),
+    /// something other than an accumulator as a `group` value
+    NotAnAccumulator(ASTNodeKind),
+    /// two `group` keys or values with the same output name. a dotted key is named
+    /// after its last segment, and `_id` is where the keys go
+    DuplicateGroupKey(String),
+    /// more than one `text` search in a match
+    DuplicateText,
+}
+
+impl std::fmt::Display for MqlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MqlError::InvalidCollationStrength(n) => write!(f, "collation strength must be between 1 and 5, got {}", n),
+            MqlError::NotAStage(kind) => write!(f, "expected a pipeline stage, got {:?}", kind),
+            MqlError::NotACondition(kind) => write!(f, "expected a condition, got {:?}", kind),
+            MqlError::NotAField(kind) => write!(f, "one side of a condition has to be a field, got {:?}", kind),
+            MqlError::NotAValue(kind) => write!(f, "expected a value to compare against, got {:?}", kind),
+            MqlError::EmptyGroup(op) => write!(f, "`{}` needs at least one condition", op),
+            MqlError::EmptyStage(kind) => write!(f, "{:?} stage is empty", kind),
+            MqlError::EmptyTypes => f.write_str("`type` needs at least one type name"),
+            MqlError::MixedProjection => f.write_str("`project` can't drop fields and keep others at once, `_id` aside"),
+            MqlError::InvalidSlice => f.write_str("`slice` needs a positive count after a skip"),
+            MqlError::NestedFacet => f.write_str("`facet` can't go inside another `facet`"),
+            MqlError::InvalidRegexOptions(options) => write!(f, "regex options can only be `i`, `m`, `s` and `x`, got {:?}", options),
+            MqlError::NotAnAccumulator(kind) => write!(f, "expected an accumulator like `sum(...)`, got {:?}", kind),
+            MqlError::DuplicateGroupKey(name) => write!(f, "`group` has more than one `{}`", name),
+            MqlError::DuplicateText => f.write_str("a match can only contain one `text` search"),
+        }
+    }
+}
+
+impl std::error::Error for MqlError {}
+
+/// something that parses and renders fine but is probably not what was meant, see
+/// `MonGod::warnings`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Warning {
+    pub ty: WarningT,
+    /// index into `MonGod::ast` of the stage it's in
+    pub stage: usize,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum WarningT {
+    /// a clause no document can satisfy (`(5 == 6)`, `&((a == 1)(a == 2))`), in the dsl
+    AlwaysFalse(String),
+    /// a field compared against values of two different types in the same pipeline
+    /// (`age == 5` in one place, `age == "5"` in another), only one of which can match
+    MixedTypes {
+        field: String,
+        first: &'static str,
+        second: &'static str,
+    },
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.ty {
+            WarningT::AlwaysFalse(clause) => write!(f, "stage {}: `{}` is always false", self.stage + 1, clause),
+            WarningT::MixedTypes { field, first, second } => write!(
+                f,
+                "stage {}: `{}` is compared to a {} here but to a {} before",
+                self.stage + 1,
+                field,
+                second,
+                first
+            ),
+        }
+    }
+}
+
+/// backslash-escapes every regex metacharacter (`. * + ? ( ) [ ] { } ^ $ | \`) so
+/// `literal` matches only itself inside a `$regex` pattern
+pub fn escape_regex(literal: &str) -> String {
+    let mut escaped = String::with_capacity(literal.len());
+    for c in literal.chars() {
+        if matches!(c, '.' | '*' | '+' | '?' | '(' | ')' | '[' | ']' | '{' | '}' | '^' | '$' | '|' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// per-category token counts for a source, see `token_stats`
+#[derive(Debug, PartialEq, Default, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TokenStats {
+    /// bare identifiers, i.e. field names and unquoted values
+    pub literals: usize,
+    pub strings: usize,
+    pub numbers: usize,
+    pub bools: usize,
+    /// comparators, `!`, `&`/`|` and arithmetic operators
+    pub operators: usize,
+    /// open and close parens and brackets together
+    pub parens: usize,
+    /// stage and `if`/`then`/`else`/`text`/`all`/`elem_match` keywords
+    pub keywords: usize,
+    /// `.`, `:` and `,`
+    pub punctuation: usize,
+    /// false if lexing failed, in which case every count is zero
+    pub complete: bool,
+}
+
+/// counts tokens by category without parsing, cheap enough to run on every
+/// keystroke for complexity heuristics. never fails, check `complete` instead
+pub fn token_stats(source: &str) -> TokenStats {
+    let tokens = match MonGod::lex(source, false) {
+        Ok(tokens) => tokens,
+        Err(_) => return TokenStats::default(),
+    };
+    let mut stats = TokenStats { complete: true, ..TokenStats::default() };
+    for token in &tokens {
+        let count = match token_class(token) {
+            Some(HighlightKind::Literal) => &mut stats.literals,
+            Some(HighlightKind::String) => &mut stats.strings,
+            Some(HighlightKind::Number) => &mut stats.numbers,
+            Some(HighlightKind::Bool) => &mut stats.bools,
+            Some(HighlightKind::Operator) => &mut stats.operators,
+            Some(HighlightKind::Paren) => &mut stats.parens,
+            Some(HighlightKind::Keyword) => &mut stats.keywords,
+            Some(HighlightKind::Punctuation) => &mut stats.punctuation,
+            Some(HighlightKind::Comment) | None => continue,
+        };
+        *count += 1;
+    }
+    stats
+}
+
+/// what a span of source is, for syntax highlighting, see `highlight`
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum HighlightKind {
+    Keyword,
+    Operator,
+    /// a bare word: a field name, or a value written without quotes
+    Literal,
+    String,
+    Number,
+    Bool,
+    /// `(`, `)`, `[` and `]`
+    Paren,
+    Punctuation,
+    Comment,
+}
+
+/// the category `token` falls in, shared by `highlight` and `token_stats` so the two
+/// can't disagree. `None` for whitespace
+fn token_class(token: &Token) -> Option<HighlightKind> {
+    let kind = match &token.ty {
+        TokenT::Literal(_) => HighlightKind::Literal,
+        TokenT::Str(_) => HighlightKind::String,
+        TokenT::Number(_) => HighlightKind::Number,
+        TokenT::Bool(_) => HighlightKind::Bool,
+        TokenT::Comparator(_) | TokenT::Not | TokenT::ConditionalOperator(_) | TokenT::ArithmeticOperator(_) => HighlightKind::Operator,
+        TokenT::OpenParen | TokenT::CloseParen | TokenT::OpenBracket | TokenT::CloseBracket => HighlightKind::Paren,
+        TokenT::Match | TokenT::Set | TokenT::Rename | TokenT::Unset | TokenT::Sort | TokenT::Limit | TokenT::Sample | TokenT::Require | TokenT::Project | TokenT::Facet | TokenT::Lookup | TokenT::Group
+        | TokenT::If | TokenT::Then | TokenT::Else | TokenT::Text
+        | TokenT::All | TokenT::ElemMatch | TokenT::Is | TokenT::Empty
+        | TokenT::In | TokenT::Range | TokenT::Has | TokenT::Comment
+        | TokenT::Matches => HighlightKind::Keyword,
+        TokenT::Dot | TokenT::Colon | TokenT::Comma | TokenT::Semicolon => HighlightKind::Punctuation,
+        TokenT::Trivia(trivia) if trivia.starts_with("//") => HighlightKind::Comment,
+        TokenT::Trivia(_) => return None,
+    };
+    Some(kind)
+}
+
+/// byte range and kind of every token and `//` comment in `source`, in order. whitespace
+/// isn't included. source that doesn't lex gets highlighted up to where it stops lexing
+pub fn highlight(source: &str) -> Vec<(Range<usize>, HighlightKind)> {
+    let tokens = match MonGod::tokenize_with_trivia(source) {
+        Ok(tokens) => tokens,
+        Err(e) if e.cursor > 0 && e.cursor < source.len() && source.is_char_boundary(e.cursor) => return highlight(&source[..e.cursor]),
+        Err(_) => return Vec::new(),
+    };
+    tokens.iter().filter_map(|token| Some((token.span(), token_class(token)?))).collect()
+}
+
+/// one line per token, its span, kind and the source it was read from, e.g.
+/// `6..9     Literal             "age"`. `tokens` have to come from lexing `source`
+pub fn debug_tokens(tokens: &[Token], source: &str) -> String {
+    let mut out = String::new();
+    for token in tokens {
+        let kind = format!("{:?}", token.ty);
+        // the variant alone, the lexeme already shows what's inside it
+        let kind = kind.split('(').next().unwrap_or_default();
+        let span = format!("{}..{}", token.idx, token.end);
+        let lexeme = source.get(token.span()).unwrap_or_default();
+        let _ = writeln!(out, "{:<8} {:<19} {:?}", span, kind, lexeme);
+    }
+    out
+}
+
+/// parses a bare predicate, i.e. what would go inside `match(...)`, e.g.
+/// `&((age > 18)(branch == CSE))`. a single comparison or truthiness test can
+/// drop its parens (`age > 18`, `active`)
+pub fn parse_condition_str(source: &str) -> Result<ASTNode, ParseError> {
+    let mut tokens = MonGod::lex(source, false)?;
+    MonGod::check_nesting(&tokens)?;
+    if let Some(Token{ ty: TokenT::Literal(_) | TokenT::Number(_) | TokenT::Str(_) | TokenT::Bool(_), idx, ..}) = tokens.first() {
+        // a stray `)` would close the wrapping paren instead, and get blamed on the end of the source
+        let mut depth: usize = 0;
+        for t in &tokens {
+            match t.ty {
+                TokenT::OpenParen => depth += 1,
+                TokenT::CloseParen if depth == 0 => return Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: t.idx}),
+                TokenT::CloseParen => depth -= 1,
+                _ => {}
+            }
+        }
+        let open = Token::new(TokenT::OpenParen, *idx);
+        tokens.insert(0, open);
+        tokens.push(Token::new(TokenT::CloseParen, source.len()));
+    }
+    let mut iter = peek_nth(tokens);
+    let (condition, _) = MonGod::parse_condition(&mut iter)?;
+    match iter.next() {
+        None => Ok(condition),
+        Some(Token{ idx, ..}) => Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: idx}),
+    }
+}
+
+/// summary of what a parsed pipeline does, without the values. see `MonGod::describe`
+#[derive(Debug, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct QueryShape {
+    pub stages: Vec<&'static str>,
+    pub fields: Vec<String>,
+    pub comparators: Vec<Comparator>,
+    pub conditional_operators: Vec<ConditionalOperator>,
+}
+
+impl QueryShape {
+    fn visit(&mut self, node: &ASTNode) {
+        match node {
+            ASTNode::Match(inner) => {
+                self.stages.push("match");
+                self.visit(inner);
+            }
+            ASTNode::Condition { op, left, right } => {
+                match &**left {
+                    ASTNode::Literal(field) | ASTNode::Length(field) => self.add_field(field),
+                    left => self.visit_expression(left),
+                }
+                if let ASTNode::Arithmetic { .. } | ASTNode::Length(_) = &**right {
+                    self.visit_expression(right);
+                }
+                if !self.comparators.contains(op) {
+                    self.comparators.push(*op);
+                }
+            }
+            ASTNode::ConditionalOperator { op, conditions } => {
+                if !self.conditional_operators.contains(op) {
+                    self.conditional_operators.push(*op);
+                }
+                for condition in conditions {
+                    self.visit(condition);
+                }
+            }
+            ASTNode::Set(assignments) => {
+                self.stages.push("set");
+                for (field, value) in assignments {
+                    self.add_field(field);
+                    self.visit_expression(value);
+                }
+            }
+            ASTNode::Project(fields) => {
+                self.stages.push("project");
+                for (field, value) in fields {
+                    self.add_field(field);
+                    self.visit_expression(value);
+                }
+            }
+            ASTNode::Group { keys, accumulators } => {
+                self.stages.push("group");
+                for key in keys {
+                    self.add_field(key);
+                }
+                for (name, value) in accumulators {
+                    self.add_field(name);
+                    self.visit_expression(value);
+                }
+            }
+            ASTNode::Lookup { local, as_, .. } => {
+                self.stages.push("lookup");
+                self.add_field(local);
+                self.add_field(as_);
+            }
+            ASTNode::Rename { from, to } => {
+                self.stages.push("rename");
+                self.add_field(from);
+                self.add_field(to);
+            }
+            ASTNode::Unset(fields) => {
+                self.stages.push("unset");
+                for field in fields {
+                    self.add_field(field);
+                }
+            }
+            ASTNode::Require(fields) => {
+                self.stages.push("require");
+                for field in fields {
+                    self.add_field(field);
+                }
+            }
+            ASTNode::Sort(keys) => {
+                self.stages.push("sort");
+                for (field, _) in keys {
+                    self.add_field(field);
+                }
+            }
+            ASTNode::Limit(_) => self.stages.push("limit"),
+            ASTNode::Sample(_) => self.stages.push("sample"),
+            ASTNode::Facet(branches) => {
+                self.stages.push("facet");
+                // fields and operators count wherever they are, the stages in the
+                // branches aren't stages of this pipeline though
+                let stages = self.stages.len();
+                for (_, branch) in branches {
+                    for stage in branch {
+                        self.visit(stage);
+                    }
+                }
+                self.stages.truncate(stages);
+            }
+            ASTNode::Comment { condition, .. } => self.visit(condition),
+            ASTNode::All { field, .. } | ASTNode::In { field, .. } | ASTNode::HasValue(field) | ASTNode::Type { field, .. } => {
+                self.add_field(field)
+            }
+            ASTNode::Matches { field, pattern } => {
+                self.add_field(field);
+                if let ASTNode::Literal(other) = &**pattern {
+                    self.add_field(other.trim_start_matches('$'));
+                }
+            }
+            ASTNode::Literal(_)
+            | ASTNode::Length(_)
+            | ASTNode::Str(_)
+            | ASTNode::Number(_)
+            | ASTNode::Bool(_)
+            | ASTNode::Null
+            | ASTNode::Param(_)
+            | ASTNode::Text { .. }
+            | ASTNode::ElemMatch(_)
+            | ASTNode::Arithmetic { .. }
+            | ASTNode::Cond { .. }
+            | ASTNode::Slice { .. }
+            | ASTNode::Regex { .. }
+            | ASTNode::Accumulator { .. }
+            | ASTNode::Unexpected => {}
+        }
+    }
+
+    // inside an expression every bare literal is a field reference
+    fn visit_expression(&mut self, node: &ASTNode) {
+        match node {
+            ASTNode::Literal(field) => self.add_field(field.trim_start_matches('$')),
+            ASTNode::Length(field) => self.add_field(field),
+            ASTNode::Arithmetic { left, right, .. } => {
+                self.visit_expression(left);
+                self.visit_expression(right);
+            }
+            ASTNode::Cond { condition, then, otherwise } => {
+                self.visit(condition);
+                self.visit_expression(then);
+                self.visit_expression(otherwise);
+            }
+            ASTNode::Accumulator { arg, .. } => self.visit_expression(arg),
+            _ => {}
+        }
+    }
+
+    fn add_field(&mut self, field: &str) {
+        if !self.fields.iter().any(|f| f == field) {
+            self.fields.push(field.to_string());
+        }
+    }
+}
+
+/// knobs for `MonGod::ast2mql_with`. `MqlOptions::default()` gives the same compact
+/// output as `ast2mql`
+#[derive(Debug, Clone, PartialEq)]
+pub struct MqlOptions {
+    pretty: bool,
+    indent: String,
+    rename_via_unset: bool,
+    integral_floats: bool,
+    normalize_field_case: bool,
+    eq_shorthand: bool,
+}
+
+impl Default for MqlOptions {
+    fn default() -> Self {
+        Self {
+            pretty: false,
+            indent: String::from("  "),
+            rename_via_unset: false,
+            integral_floats: false,
+            normalize_field_case: false,
+            eq_shorthand: false,
+        }
+    }
+}
+
+impl MqlOptions {
+    /// spread documents and arrays over multiple indented lines
+    pub fn pretty(mut self, pretty: bool) -> Self {
+        self.pretty = pretty;
+        self
+    }
+
+    /// indent pretty output by `n` spaces per level (default 2)
+    pub fn indent(mut self, n: usize) -> Self {
+        self.indent = " ".repeat(n);
+        self
+    }
+
+    /// indent pretty output with one tab per level
+    pub fn indent_tabs(mut self) -> Self {
+        self.indent = String::from("\t");
+        self
+    }
+
+    /// render `rename(a, b)` as `$set` + `$unset` instead of `$addFields` + `$project`
+    pub fn rename_via_unset(mut self, rename_via_unset: bool) -> Self {
+        self.rename_via_unset = rename_via_unset;
+        self
+    }
+
+    /// render whole numbers as `5.0` rather than `5`. the shell reads both as a double,
+    /// but tooling that types `5` as an integer sees the difference
+    pub fn integral_floats(mut self, on: bool) -> Self {
+        self.integral_floats = on;
+        self
+    }
+
+    /// lowercase every field name, as keys and as `"$field"` references, for
+    /// collections whose field names are case-insensitive by convention
+    pub fn normalize_field_case(mut self, on: bool) -> Self {
+        self.normalize_field_case = on;
+        self
+    }
+
+    /// render `a == 1` as `{ a: 1 }` rather than `{ a: { $eq: 1 } }`. other
+    /// comparisons keep their operator
+    pub fn eq_shorthand(mut self, on: bool) -> Self {
+        self.eq_shorthand = on;
+        self
+    }
+}
+
+/// where an `ASTNode` came from in the source, as a byte range. kept in a tree of its
+/// own next to the ast so node equality stays purely structural. `children` line up with
+/// the node's child nodes in order: `Condition` → [left, right], `ConditionalOperator` →
+/// its conditions, `Arithmetic` → [left, right], `Cond` → [condition, then, otherwise],
+/// `Match` → \[condition\], `Set`/`Project` → one per value, `Facet` → one per pipeline,
+/// each with one per stage. leaves and stages without child nodes have none
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpanNode {
+    pub span: Range<usize>,
+    pub children: Vec<SpanNode>,
+}
+
+impl SpanNode {
+    fn leaf(span: Range<usize>) -> Self {
+        Self { span, children: Vec::new() }
+    }
+
+    /// a span stretching over all of `children`
+    fn covering(children: Vec<SpanNode>) -> Self {
+        let start = children.iter().map(|c| c.span.start).min().unwrap_or(0);
+        let end = children.iter().map(|c| c.span.end).max().unwrap_or(0);
+        Self { span: start..end, children }
+    }
+
+    /// grows the span to also cover `outer`, e.g. the parens around a condition
+    fn widened(mut self, outer: Range<usize>) -> Self {
+        self.span = self.span.start.min(outer.start)..self.span.end.max(outer.end);
+        self
+    }
+}
+
+/// which rewrites `MonGod::optimize_with` applies. all of them are on by default
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptimizeOptions {
+    hoist_matches: bool,
+    merge_matches: bool,
+    collapse_single_child_groups: bool,
+    flatten_groups: bool,
+    fold_constants: bool,
+    sort_groups: bool,
+    dedupe_groups: bool,
+}
+
+impl Default for OptimizeOptions {
+    fn default() -> Self {
+        Self {
+            hoist_matches: true,
+            merge_matches: true,
+            collapse_single_child_groups: true,
+            flatten_groups: true,
+            fold_constants: true,
+            sort_groups: false,
+            dedupe_groups: false,
+        }
+    }
+}
+
+impl OptimizeOptions {
+    /// move each `$match` in front of preceding stages that don't touch any field it
+    /// looks at, so documents get filtered out before work is spent on them
+    pub fn hoist_matches(mut self, on: bool) -> Self {
+        self.hoist_matches = on;
+        self
+    }
+
+    /// fold back-to-back `$match` stages into one `$match` over an `$and`
+    pub fn merge_matches(mut self, on: bool) -> Self {
+        self.merge_matches = on;
+        self
+    }
+
+    /// see `MonGod::collapse_single_child_groups`
+    pub fn collapse_single_child_groups(mut self, on: bool) -> Self {
+        self.collapse_single_child_groups = on;
+        self
+    }
+
+    /// see `ASTNode::flatten_groups`
+    pub fn flatten_groups(mut self, on: bool) -> Self {
+        self.flatten_groups = on;
+        self
+    }
+
+    /// see `ASTNode::fold_constants`. a `$match` that folds down to always true lets
+    /// everything through, so it's dropped
+    pub fn fold_constants(mut self, on: bool) -> Self {
+        self.fold_constants = on;
+        self
+    }
+
+    /// see `ASTNode::sort_groups`. off by default, it's for comparing or caching
+    /// queries and reorders conditions away from how they were written
+    pub fn sort_groups(mut self, on: bool) -> Self {
+        self.sort_groups = on;
+        self
+    }
+
+    /// see `ASTNode::dedupe_groups`. off by default, the ast otherwise keeps every
+    /// condition that was written
+    pub fn dedupe_groups(mut self, on: bool) -> Self {
+        self.dedupe_groups = on;
+        self
+    }
+}
+
+/// limits and knobs for parsing, see `MonGod::with_options`. the defaults accept
+/// anything `MonGod::new` always has
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ParseOptions {
+    max_stages: Option<usize>,
+    max_number_literal_digits: Option<usize>,
+    max_identifier_length: Option<usize>,
+    max_tokens: Option<usize>,
+    max_range_len: Option<usize>,
+    aliases: Vec<(String, TokenT)>,
+}
+
+impl ParseOptions {
+    /// refuse sources with more than `n` stages, for input you don't trust. counts every
+    /// `;` separated pipeline together, so splitting them up doesn't get around it
+    pub fn max_stages(mut self, n: usize) -> Self {
+        self.max_stages = Some(n);
+        self
+    }
+
+    /// refuse number literals longer than `n` characters (the `.` counts)
+    pub fn max_number_literal_digits(mut self, n: usize) -> Self {
+        self.max_number_literal_digits = Some(n);
+        self
+    }
+
+    /// refuse field names and other bare words longer than `n` characters, not counting
+    /// the backticks of a quoted one
+    pub fn max_identifier_length(mut self, n: usize) -> Self {
+        self.max_identifier_length = Some(n);
+        self
+    }
+
+    /// refuse sources lexing to more than `n` tokens, whitespace and comments not counted
+    pub fn max_tokens(mut self, n: usize) -> Self {
+        self.max_tokens = Some(n);
+        self
+    }
+
+    /// refuse `range(a, b)`s expanding to more than `n` values. there's a hard cap of
+    /// 10 000 regardless, so a stray `range(0, 1e12)` can't eat all the memory
+    pub fn max_range_len(mut self, n: usize) -> Self {
+        self.max_range_len = Some(n);
+        self
+    }
+
+    /// also accept `alias` wherever `keyword` goes, e.g. `alias("filter", "match")`. the
+    /// alias stops being usable as a field name. unknown keywords are ignored
+    pub fn alias(mut self, alias: &str, keyword: &str) -> Self {
+        if let Some(ty) = MonGod::keyword(keyword) {
+            self.aliases.push((alias.to_string(), ty));
+        }
+        self
+    }
+
+    fn resolve_alias(&self, token: Token) -> Token {
+        match &token.ty {
+            // a backtick-quoted name spans two more bytes than its word, and is always a field
+            TokenT::Literal(word) if token.span().len() == word.len() => match self.aliases.iter().find(|(alias, _)| alias == word) {
+                Some((_, ty)) => Token { ty: ty.clone(), ..token },
+                None => token,
+            },
+            _ => token,
+        }
+    }
+}
+
+/// reserved words and the tokens they lex to, wherever they appear. a field
+/// spelled like one of them (`text`, `group`, `in`, ...) has to be quoted in
+/// backticks, `` match(`text` == "a") ``, which lexes to a plain literal
+const KEYWORDS: &[(&str, TokenT)] = &[
+    ("match", TokenT::Match),
+    ("set", TokenT::Set),
+    ("rename", TokenT::Rename),
+    ("unset", TokenT::Unset),
+    ("require", TokenT::Require),
+    ("project", TokenT::Project),
+    ("facet", TokenT::Facet),
+    ("lookup", TokenT::Lookup),
+    ("group", TokenT::Group),
+    ("sort", TokenT::Sort),
+    ("limit", TokenT::Limit),
+    ("sample", TokenT::Sample),
+    ("if", TokenT::If),
+    ("then", TokenT::Then),
+    ("else", TokenT::Else),
+    ("text", TokenT::Text),
+    ("nor", TokenT::ConditionalOperator(ConditionalOperator::NOR)),
+    ("all", TokenT::All),
+    ("elem_match", TokenT::ElemMatch),
+    ("is", TokenT::Is),
+    ("empty", TokenT::Empty),
+    ("not", TokenT::Not),
+    ("in", TokenT::In),
+    ("range", TokenT::Range),
+    ("has", TokenT::Has),
+    ("comment", TokenT::Comment),
+    ("matches", TokenT::Matches),
+    ("true", TokenT::Bool(true)),
+    ("false", TokenT::Bool(false)),
+];
+
+/// the type aliases `$type` accepts
+const TYPE_NAMES: &[&str] = &[
+    "double", "string", "object", "array", "binData", "undefined", "objectId", "bool", "date", "null", "regex",
+    "dbPointer", "javascript", "symbol", "javascriptWithScope", "int", "timestamp", "long", "decimal", "minKey",
+    "maxKey", "number",
+];
+
+/// how many parens, brackets, `if`s and arithmetic operators deep a query may go, see
+/// `MonGod::check_nesting`
+const MAX_NESTING: usize = 128;
+
+/// the most values a `range(a, b)` may expand to, `ParseOptions::max_range_len` can lower it
+const MAX_RANGE_LEN: usize = 10_000;
+
+pub struct MonGod {
+    s: String,
+    opts: ParseOptions,
+    /// the first pipeline in the source. everything that works on "the" pipeline
+    /// (`optimize`, `ast2mql`, `explain`, ...) works on this one
+    pub ast: Vec<ASTNode>,
+    /// source extents of `ast`, one tree per stage. reflects the ast as parsed, so it goes
+    /// stale once the ast is rewritten (e.g. by `collapse_single_child_groups`)
+    pub spans: Vec<SpanNode>,
+    /// every `;` separated pipeline in the source, in order, as parsed. `pipelines[0]`
+    /// is what `ast` starts out as, a source without `;` has just that one
+    pub pipelines: Vec<Vec<ASTNode>>,
+}
+
+impl MonGod {
+    pub fn new(s: String) -> Self {
+        Self::with_options(s, ParseOptions::default())
+    }
+
+    pub fn with_options(s: String, opts: ParseOptions) -> Self {
+        Self {
+            s,
+            opts,
+            ast: Vec::new(),
+            spans: Vec::new(),
+            pipelines: Vec::new(),
+        }
+    }
+
+    /// a pipeline built in code rather than parsed, ready for `ast2mql` and friends.
+    /// `source()` is the dsl printed from it
+    pub fn from_ast(ast: Vec<ASTNode>) -> Self {
+        let mut m = Self::new(String::new());
+        m.pipelines = vec![ast.clone()];
+        m.ast = ast;
+        m.s = m.to_dsl();
+        m
+    }
+
+    /// the pipeline back in the dsl, one stage per `ASTNode` `Display`, chained with `.`
+    pub fn to_dsl(&self) -> String {
+        let stages: Vec<String> = self.ast.iter().map(|stage| stage.to_string()).collect();
+        stages.join(".")
+    }
+
+    /// the source this was constructed with
+    pub fn source(&self) -> &str {
+        &self.s
+    }
+
+    pub fn build(&mut self) -> Result<(), ParseError> {
+        let tokens = MonGod::tokenize(&self.s)?;
+        self.parse_tokens(&tokens)
+    }
+
+    /// `build`, handing back the freshly parsed pipeline instead of making the
+    /// caller go read `ast`
+    pub fn build_ast(&mut self) -> Result<&[ASTNode], ParseError> {
+        self.build()?;
+        Ok(&self.ast)
+    }
+
+    fn tokenize(s: &str) -> Result<Vec<Token>, ParseError> {
+        let tokens = Self::lex(s, false)?;
+        trace!("{}", debug_tokens(&tokens, s));
+        Ok(tokens)
+    }
+
+    /// the same tokens the parser sees, plus whitespace runs and `//` comments as
+    /// `TokenT::Trivia`, so the source can be rebuilt from the tokens exactly
+    pub fn tokenize_with_trivia(s: &str) -> Result<Vec<Token>, ParseError> {
+        Self::lex(s, true)
+    }
+
+    fn lex(s: &str, keep_trivia: bool) -> Result<Vec<Token>, ParseError> {
+        let mut tokens = Vec::new();
+        let mut chars = s.chars().peekable();
+        let mut idx: usize = 0;
+        while let Some(&c) = chars.peek() {
+            let token_count = tokens.len();
+            match c {
+                ' ' | '\t' | '\n' | '\r' => {
+                    let mut trivia = String::new();
+                    while let Some(&c1) = chars.peek() {
+                        if !matches!(c1, ' ' | '\t' | '\n' | '\r') {
+                            break;
+                        }
+                        trivia.push(c1);
+                        chars.next();
+                    }
+                    if keep_trivia {
+                        tokens.push(Token::new(TokenT::Trivia(trivia.clone()), idx));
+                    }
+                    idx+=trivia.len();
+                }
+                '/' if s[idx..].starts_with("//") => {
+                    let mut comment = String::new();
+                    while let Some(&c1) = chars.peek() {
+                        if c1 == '\n' {
+                            break;
+                        }
+                        comment.push(c1);
+                        chars.next();
+                    }
+                    if keep_trivia {
+                        tokens.push(Token::new(TokenT::Trivia(comment.clone()), idx));
+                    }
+                    idx+=comment.len();
+                }
+                '>' => {
+                    chars.next();
+                    if chars.peek() == Some(&'=') {
+                        chars.next();
+                        tokens.push(Token::new(TokenT::Comparator(Comparator::GTE), idx));
+                        idx+=2;
+                    } else {
+                        tokens.push(Token::new(TokenT::Comparator(Comparator::GT), idx));
+                        idx+=1;
+                    }
+                }
+                '<' => {
+                    chars.next();
+                    if chars.peek() == Some(&'=') {
+                        chars.next();
+                        tokens.push(Token::new(TokenT::Comparator(Comparator::LTE), idx));
+                        idx+=2;
+                    } else {
+                        tokens.push(Token::new(TokenT::Comparator(Comparator::LT), idx));
+                        idx+=1;
+                    }
+                }
+                '=' => {
+                    chars.next();
+                    if chars.peek() == Some(&'=') {
+                        chars.next();
+                        tokens.push(Token::new(TokenT::Comparator(Comparator::EQ), idx));
+                        idx+=2;
+                    } else {
+                        // a lone `=`, most likely meant as `==`
+                        return Err(ParseError{ ty: ParseErrorT::UnexpectedCharacter, cursor: idx});
+                    }
+                }
+                '!' => {
+                    chars.next();
+                    if chars.peek() == Some(&'=') {
+                        chars.next();
+                        tokens.push(Token::new(TokenT::Comparator(Comparator::NEQ), idx));
+                        idx+=2;
+                    } else {
+                        tokens.push(Token::new(TokenT::Not, idx));
+                        idx+=1;
+                    }
+                }
+                '&' => {
+                    tokens.push(Token::new(TokenT::ConditionalOperator(ConditionalOperator::AND), idx));
+                    chars.next();
+                    idx+=1;
+                }
+                '|' => {
+                    tokens.push(Token::new(TokenT::ConditionalOperator(ConditionalOperator::OR), idx));
+                    chars.next();
+                    idx+=1;
+                }
+                '+' | '-' | '*' | '/' => {
+                    let op = match c {
+                        '+' => ArithmeticOperator::ADD,
+                        '-' => ArithmeticOperator::SUB,
+                        '*' => ArithmeticOperator::MUL,
+                        _ => ArithmeticOperator::DIV,
+                    };
+                    tokens.push(Token::new(TokenT::ArithmeticOperator(op), idx));
+                    chars.next();
+                    idx+=1;
+                }
+                ':' => {
+                    tokens.push(Token::new(TokenT::Colon, idx));
+                    chars.next();
+                    idx+=1;
+                }
+                ',' => {
+                    tokens.push(Token::new(TokenT::Comma, idx));
+                    chars.next();
+                    idx+=1;
+                }
+                ';' => {
+                    tokens.push(Token::new(TokenT::Semicolon, idx));
+                    chars.next();
+                    idx+=1;
+                }
+                '"' => {
+                    chars.next();
+                    let (value, consumed) = Self::lex_string(&mut chars, idx)?;
+                    tokens.push(Token::new(TokenT::Str(value), idx));
+                    idx+=1+consumed;
+                }
+                // `match`, a field name even if it's spelled like a keyword
+                '`' => {
+                    chars.next();
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('`') => break,
+                            Some(c1) => name.push(c1),
+                            None => return Err(ParseError{ ty: ParseErrorT::UnterminatedIdentifier, cursor: idx}),
+                        }
+                    }
+                    let name_size = name.len();
+                    tokens.push(Token::new(TokenT::Literal(name), idx));
+                    idx+=name_size+2;
+                }
+                '(' => {
+                    tokens.push(Token::new(TokenT::OpenParen, idx));
+                    chars.next();
+                    idx+=1;
+                }
+                ')' => {
+                    tokens.push(Token::new(TokenT::CloseParen, idx));
+                    chars.next();
+                    idx+=1;
+                }
+                '[' => {
+                    tokens.push(Token::new(TokenT::OpenBracket, idx));
+                    chars.next();
+                    idx+=1;
+                }
+                ']' => {
+                    tokens.push(Token::new(TokenT::CloseBracket, idx));
+                    chars.next();
+                    idx+=1;
+                }
+                '.' => {
+                    tokens.push(Token::new(TokenT::Dot, idx));
+                    chars.next();
+                    idx+=1;
+                }
+                'a'..='z' | 'A'..='Z' | '_' | '$' => {
+                    let mut literal = String::new();
+                    while let Some(&c1) = chars.peek() {
+                        if c1.is_alphanumeric() || c1 == '_' || c1 == '.' || c1 == '$' {
+                            literal.push(c1);
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+
+                    // `user.` or `user..name`, a path with an empty segment. point at the
+                    // dot in front of it
+                    let empty_segment = literal.find("..").map(|at| at + 1).or_else(|| literal.ends_with('.').then(|| literal.len() - 1));
+                    if let Some(at) = empty_segment {
+                        return Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: idx + at});
+                    }
+                    let literal_size = literal.len();
+                    let ty = Self::keyword(&literal).unwrap_or(TokenT::Literal(literal));
+                    tokens.push(Token::new(ty, idx));
+                    idx+=literal_size;
+                }
+                '0'..='9' => {
+                    let mut number = String::new();
+                    while let Some(&c1) = chars.peek() {
+                        if c1.is_ascii_digit() || c1 == '.' {
+                            number.push(c1);
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                    let number_size = number.len();
+                    // `1.2.3`, or so many digits it overflows to `inf`, which isn't valid mql
+                    let value = number.parse().ok().filter(|n: &f64| n.is_finite()).ok_or(ParseError{ ty: ParseErrorT::InvalidNumber, cursor: idx})?;
+                    tokens.push(Token::new(TokenT::Number(value), idx));
+                    idx+=number_size;
+                }
+                _ => {
+                    return Err(ParseError{ ty: ParseErrorT::UnexpectedCharacter, cursor: idx});
+                }
+            }
+            if tokens.len() > token_count {
+                tokens.last_mut().unwrap().end = idx;
+            }
+        }
+        Ok(tokens)
+    }
+
+    /// the token a reserved word lexes to, `None` for everything else
+    fn keyword(word: &str) -> Option<TokenT> {
+        KEYWORDS.iter().find(|(keyword, _)| *keyword == word).map(|(_, ty)| ty.clone())
+    }
+
+    /// body of a `"..."` string, the opening quote already consumed. returns the
+    /// unescaped value and how many source bytes the body plus closing quote took
+    fn lex_string<C>(chars: &mut std::iter::Peekable<C>, start: usize) -> Result<(String, usize), ParseError>
+    where
+        C: Iterator<Item = char>,
+    {
+        let mut value = String::new();
+        let mut consumed = 0;
+        loop {
+            let c = match chars.next() {
+                Some(c) => c,
+                None => return Err(ParseError{ ty: ParseErrorT::UnterminatedString, cursor: start}),
+            };
+            consumed += c.len_utf8();
+            match c {
+                '"' => return Ok((value, consumed)),
+                '\\' => {
+                    let escape_idx = start + consumed;
+                    let escaped = match chars.next() {
+                        Some(e) => e,
+                        None => return Err(ParseError{ ty: ParseErrorT::UnterminatedString, cursor: start}),
+                    };
+                    consumed += escaped.len_utf8();
+                    let invalid = ParseError{ ty: ParseErrorT::InvalidEscape, cursor: escape_idx};
+                    value.push(match escaped {
+                        '"' => '"',
+                        '\\' => '\\',
+                        '/' => '/',
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        // `\x41`, exactly two hex digits, ascii only like rust
+                        'x' => {
+                            let mut digits = String::new();
+                            for _ in 0..2 {
+                                match chars.next() {
+                                    Some(d) => {
+                                        consumed += d.len_utf8();
+                                        digits.push(d);
+                                    }
+                                    None => return Err(ParseError{ ty: ParseErrorT::UnterminatedString, cursor: start}),
+                                }
+                            }
+                            match u8::from_str_radix(&digits, 16) {
+                                Ok(byte) if digits.chars().all(|d| d.is_ascii_hexdigit()) && byte <= 0x7f => byte as char,
+                                _ => return Err(invalid),
+                            }
+                        }
+                        // `\u{1F600}`, one to six hex digits naming a unicode scalar value
+                        'u' => {
+                            match chars.next() {
+                                Some('{') => {}
+                                Some(_) => return Err(invalid),
+                                None => return Err(ParseError{ ty: ParseErrorT::UnterminatedString, cursor: start}),
+                            }
+                            consumed += 1;
+                            let mut digits = String::new();
+                            loop {
+                                match chars.next() {
+                                    Some('}') => {
+                                        consumed += 1;
+                                        break;
+                                    }
+                                    Some(d) => {
+                                        consumed += d.len_utf8();
+                                        digits.push(d);
+                                    }
+                                    None => return Err(ParseError{ ty: ParseErrorT::UnterminatedString, cursor: start}),
+                                }
+                            }
+                            if digits.is_empty() || digits.len() > 6 || !digits.chars().all(|d| d.is_ascii_hexdigit()) {
+                                return Err(invalid);
+                            }
+                            match u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32) {
+                                Some(c) => c,
+                                None => return Err(invalid),
+                            }
+                        }
+                        _ => return Err(invalid),
+                    });
+                }
+                _ => value.push(c),
+            }
+        }
+    }
+
+    fn parse_condition<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        match iter.peek() {
+            Some(Token{ ty: TokenT::ConditionalOperator(_), ..}) => {
+                trace!("entering a conditional operator (AND/OR)");
+                let conditional_operation = Self::parse_logical_op(iter);
+                trace!("log:conditional_operation: {:?}", conditional_operation);
+                conditional_operation
+            }
+            Some(Token{ ty: TokenT::Literal(_), idx, ..}) => {
+                let idx_clone = *idx;
+                if let Some(Token{ ty: TokenT::Literal(literal), idx, end}) = iter.next() {
+                    // `.length` is a pseudo-property, not a path into the document
+                    if let Some(field) = literal.strip_suffix(".length") {
+                        if !field.is_empty() {
+                            return Ok((ASTNode::Length(field.to_string()), SpanNode::leaf(idx..end)));
+                        }
+                    }
+                    Ok((ASTNode::Literal(literal), SpanNode::leaf(idx..end)))
+                } else {
+                    trace!("here1");
+                    Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: idx_clone})
+                }
+            }
+            Some(Token{ ty: TokenT::Number(_), idx, ..}) => {
+                let idx_clone = *idx;
+                if let Some(Token{ ty: TokenT::Number(num), idx, end}) = iter.next() {
+                    Ok((ASTNode::Number(num), SpanNode::leaf(idx..end)))
+                } else {
+                    Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: idx_clone})
+                }
+            }
+            Some(Token{ ty: TokenT::Bool(b), idx, end}) => {
+                let (b, span) = (*b, *idx..*end);
+                iter.next();
+                Ok((ASTNode::Bool(b), SpanNode::leaf(span)))
+            }
+            Some(Token{ ty: TokenT::Str(_), ..}) => {
+                match iter.next() {
+                    Some(Token{ ty: TokenT::Str(value), idx, end}) => Ok((ASTNode::Str(value), SpanNode::leaf(idx..end))),
+                    _ => unreachable!(),
+                }
+            }
+            Some(Token{ ty: TokenT::Text, ..}) => Self::parse_text(iter),
+            Some(Token{ ty: TokenT::Not, idx, ..}) => {
+                let not_idx = *idx;
+                iter.next();
+                let field_idx = Self::peek_idx(iter);
+                match iter.next() {
+                    // a length is a number, not something true or false, same as a bare `a.length`
+                    Some(Token{ ty: TokenT::Literal(field), ..}) if field.strip_suffix(".length").is_some_and(|array| !array.is_empty() && !array.starts_with('$')) => {
+                        Err(ParseError{ ty: ParseErrorT::MissingComparator, cursor: Self::peek_idx(iter)})
+                    }
+                    Some(Token{ ty: TokenT::Literal(field), end, ..}) => {
+                        Self::validate_field_name(&field, field_idx)?;
+                        Ok(Self::truthiness(field, false, SpanNode::leaf(field_idx..end), not_idx))
+                    }
+                    Some(Token{ idx, ..}) => Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: idx}),
+                    None => Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/})
+                }
+            }
+            Some(Token{ ty: TokenT::OpenParen, idx, ..}) => {
+                let open_idx = *idx;
+                trace!("parsing inside brackets");
+                iter.next();
+                trace!("entering leftside of condition");
+                Self::check_left_operand(iter)?;
+                let left_idx = Self::peek_idx(iter);
+                let (left, left_span) = Self::parse_condition(iter)?;
+                let (left, left_span) = Self::parse_operand_arithmetic(iter, left, left_span)?;
+                trace!("log:left: {:?}", left);
+                // `(a - b) / c > 2`: the parens only group arithmetic, whoever called
+                // us carries on with the rest of the expression and the comparison
+                if let (ASTNode::Arithmetic { .. }, Some(TokenT::CloseParen)) = (&left, Self::peek_kind(iter)) {
+                    let end = Self::expect(iter, TokenT::CloseParen)?.end;
+                    return Ok((left, left_span.widened(open_idx..end)));
+                }
+                if let ASTNode::Literal(field) | ASTNode::Length(field) = &left {
+                    Self::validate_field_name(field, left_idx)?;
+                }
+                if matches!(Self::peek_kind(iter), Some(TokenT::All | TokenT::Is | TokenT::In | TokenT::Has | TokenT::Matches))
+                    || Self::at_operator_word(iter)
+                {
+                    let (node, span) = match Self::peek_kind(iter) {
+                        Some(TokenT::All) => Self::parse_all(iter, left, left_span, left_idx)?,
+                        Some(TokenT::Literal(word)) if word == "type" => Self::parse_type(iter, left, left_span, left_idx)?,
+                        Some(TokenT::Literal(_)) => Self::parse_between(iter, left, left_span, left_idx)?,
+                        Some(TokenT::In) => Self::parse_in(iter, left, left_span, left_idx)?,
+                        Some(TokenT::Has) => Self::parse_has_value(iter, left, left_span, left_idx)?,
+                        Some(TokenT::Matches) => Self::parse_matches(iter, left, left_span, left_idx)?,
+                        _ => Self::parse_is_empty(iter, left, left_span, left_idx)?,
+                    };
+                    return Self::close_group(iter, node, span, open_idx);
+                }
+                // `(active)` / `(!active)` on their own are truthiness tests
+                if let Some(TokenT::CloseParen | TokenT::Comma | TokenT::ConditionalOperator(ConditionalOperator::AND | ConditionalOperator::OR)) = Self::peek_kind(iter) {
+                    match left {
+                        ASTNode::Literal(field) => {
+                            let (node, span) = Self::truthiness(field, true, left_span, open_idx);
+                            return Self::close_group(iter, node, span, open_idx);
+                        }
+                        ASTNode::Condition { .. } | ASTNode::ConditionalOperator { .. } | ASTNode::Text { .. } | ASTNode::All { .. } | ASTNode::In { .. }
+                        | ASTNode::HasValue(_) | ASTNode::Matches { .. } | ASTNode::Type { .. } => {
+                            return Self::close_group(iter, left, left_span, open_idx);
+                        }
+                        _ => {}
+                    }
+                }
+                let (op, op_idx) = match iter.next() {
+                    Some(Token{ ty: TokenT::Comparator(cmp), idx, ..}) => (cmp, idx),
+                    Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::MissingComparator, cursor: idx/*TODO*/}),
+                    None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/})
+                };
+                trace!("log:comparator: {:?}", op);
+                let (cond, cond_span) = Self::parse_comparison(iter, op, op_idx, left, left_span, left_idx)?;
+                let cond_node = Self::close_group(iter, cond, cond_span, open_idx);
+                trace!("log:cond_node: {:?}", cond_node);
+                cond_node
+            }
+    
+            Some(Token{ ty: TokenT::Dot, idx, ..}) => {
+                Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: *idx})
+            }
+            Some(Token{ idx, ..}) => Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: *idx}),
+            None => Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+        }
+    }
+    
+    /// the rest of a comparison once `left` and its comparator are consumed. another
+    /// comparator after the right side chains: `18 < age < 65` de-sugars to
+    /// `&((18 < age)(age < 65))`, parenthesized or not
+    fn parse_comparison<I>(
+        iter: &mut PeekNth<I>,
+        op: Comparator,
+        op_idx: usize,
+        left: ASTNode,
+        left_span: SpanNode,
+        left_idx: usize,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        trace!("entering right side of condition");
+        Self::check_right_operand(iter, op_idx)?;
+        let right_idx = Self::peek_idx(iter);
+        let (right, right_span) = Self::parse_condition(iter)?;
+        Self::validate_field_ref(&right, right_idx)?;
+        let (right, right_span) = Self::parse_operand_arithmetic(iter, right, right_span)?;
+        Self::check_field_side(&left, left_idx, &right)?;
+        trace!("log:right: {:?}", right);
+        let cond = ASTNode::Condition {
+            op,
+            left: Box::new(left),
+            right: Box::new(right.clone()),
+        };
+        let cond_span = SpanNode::covering(vec![left_span, right_span.clone()]);
+        let op2 = match Self::peek_kind(iter) {
+            Some(TokenT::Comparator(op2)) => *op2,
+            _ => return Ok((cond, cond_span)),
+        };
+        let op2_idx = Self::peek_idx(iter);
+        iter.next();
+        Self::check_right_operand(iter, op2_idx)?;
+        let third_idx = Self::peek_idx(iter);
+        let (third, third_span) = Self::parse_condition(iter)?;
+        Self::validate_field_ref(&third, third_idx)?;
+        let (third, third_span) = Self::parse_operand_arithmetic(iter, third, third_span)?;
+        Self::check_field_side(&right, right_idx, &third)?;
+        let chained = ASTNode::Condition {
+            op: op2,
+            left: Box::new(right),
+            right: Box::new(third),
+        };
+        let node = ASTNode::ConditionalOperator { op: ConditionalOperator::AND, conditions: vec![Box::new(cond), Box::new(chained)] };
+        Ok((node, SpanNode::covering(vec![cond_span, SpanNode::covering(vec![right_span, third_span])])))
+    }
+
+    /// a comparison operand that turns out to be the start of arithmetic
+    /// (`price * quantity > 100`) parsed out in full, any other one as it is
+    fn parse_operand_arithmetic<I>(
+        iter: &mut PeekNth<I>,
+        operand: ASTNode,
+        span: SpanNode,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        match (&operand, Self::peek_kind(iter)) {
+            (ASTNode::Literal(_) | ASTNode::Length(_) | ASTNode::Number(_) | ASTNode::Arithmetic { .. }, Some(TokenT::ArithmeticOperator(_))) => {
+                Self::parse_expression_from(iter, operand, span)
+            }
+            _ => Ok((operand, span)),
+        }
+    }
+
+    /// where a condition starts, a comparator means its left side was left out (`match(== 1)`)
+    fn check_left_operand<I>(iter: &mut PeekNth<I>) -> Result<(), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        match iter.peek() {
+            Some(Token{ ty: TokenT::Comparator(_), idx, ..}) => Err(ParseError{ ty: ParseErrorT::MissingLeftOperand, cursor: *idx}),
+            _ => Ok(()),
+        }
+    }
+
+    /// right after a comparator, the end of the condition means its right side was left
+    /// out (`match(a ==)`). points at the comparator
+    fn check_right_operand<I>(iter: &mut PeekNth<I>, op_idx: usize) -> Result<(), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        match Self::peek_kind(iter) {
+            None | Some(TokenT::CloseParen | TokenT::Comma | TokenT::Comparator(_)) => {
+                Err(ParseError{ ty: ParseErrorT::MissingRightOperand, cursor: op_idx})
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// a bare `field` means `field == true`, `!field` means `field == false`.
+    /// the implied boolean has no source of its own, it gets the span from `start` to the field
+    fn truthiness(field: String, value: bool, field_span: SpanNode, start: usize) -> (ASTNode, SpanNode) {
+        let node = ASTNode::Condition {
+            op: Comparator::EQ,
+            left: Box::new(ASTNode::Literal(field)),
+            right: Box::new(ASTNode::Bool(value)),
+        };
+        let bool_span = SpanNode::leaf(start..field_span.span.end);
+        (node, SpanNode::covering(vec![field_span, bool_span]))
+    }
+
+    /// kind of the next token without consuming it
+    fn peek_kind<I>(iter: &mut PeekNth<I>) -> Option<&TokenT>
+    where
+        I: Iterator<Item = Token>,
+    {
+        iter.peek().map(|t| &t.ty)
+    }
+
+    /// kind of the token `n` positions ahead, `peek_kind_nth(iter, 0)` is `peek_kind(iter)`
+    fn peek_kind_nth<I>(iter: &mut PeekNth<I>, n: usize) -> Option<&TokenT>
+    where
+        I: Iterator<Item = Token>,
+    {
+        iter.peek_nth(n).map(|t| &t.ty)
+    }
+
+    /// the next token, which has to be a `kind`. anything else is reported at that token,
+    /// as whatever error `kind` going missing is (`MissingOpenParen` for a `(`, ...).
+    /// running out of input is `MissingCloseParen` when it's a `)` that never came
+    fn expect<I>(iter: &mut PeekNth<I>, kind: TokenT) -> Result<Token, ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        match iter.next() {
+            Some(token) if token.ty == kind => Ok(token),
+            Some(Token{ idx, ..}) => Err(ParseError{ ty: Self::missing(&kind), cursor: idx}),
+            None if kind == TokenT::CloseParen => Err(ParseError{ ty: ParseErrorT::MissingCloseParen, cursor: 0/*TODO*/}),
+            None => Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+        }
+    }
+
+    /// the error for a `kind` token that should be there but isn't
+    fn missing(kind: &TokenT) -> ParseErrorT {
+        match kind {
+            TokenT::OpenParen => ParseErrorT::MissingOpenParen,
+            TokenT::CloseParen => ParseErrorT::UnmatchedParenthesis,
+            TokenT::OpenBracket => ParseErrorT::MissingOpenBracket,
+            TokenT::CloseBracket => ParseErrorT::UnmatchedBracket,
+            TokenT::Colon => ParseErrorT::MissingColon,
+            TokenT::Then => ParseErrorT::MissingThen,
+            TokenT::Else => ParseErrorT::MissingElse,
+            _ => ParseErrorT::Unexpected,
+        }
+    }
+
+    /// source index of the next token, 0 if the stream is exhausted
+    fn peek_idx<I>(iter: &mut PeekNth<I>) -> usize
+    where
+        I: Iterator<Item = Token>,
+    {
+        iter.peek().map(|t| t.idx).unwrap_or(0)
+    }
+
+    /// a field path is one or more `.`-separated segments, none of them empty
+    /// and none starting with `$` (that would make it an operator, not a key)
+    fn validate_field_name(field: &str, idx: usize) -> Result<(), ParseError> {
+        let mut offset = 0;
+        for segment in field.split('.') {
+            if segment.is_empty() || segment.starts_with('$') {
+                return Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: idx + offset});
+            }
+            // a segment starting with a digit is an array index (`items.0.price`), so it
+            // has to be all digits and without leading zeros
+            if segment.starts_with(|c: char| c.is_ascii_digit())
+                && (!segment.chars().all(|c| c.is_ascii_digit()) || (segment.len() > 1 && segment.starts_with('0')))
+            {
+                return Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: idx + offset});
+            }
+            offset += segment.len() + 1;
+        }
+        Ok(())
+    }
+
+    /// a comparison needs a field on one side for mql to have a key to put it under,
+    /// `5 < $b` has none. two constants (`1 == 2`, `"a" == "b"`) are fine, they go in
+    /// `$expr` and `warnings`/`fold_constants` deal with them. points at the left side
+    fn check_field_side(left: &ASTNode, left_idx: usize, right: &ASTNode) -> Result<(), ParseError> {
+        let is_field = |node: &ASTNode| match node {
+            ASTNode::Literal(l) => !l.starts_with('$'),
+            ASTNode::Length(_) | ASTNode::Arithmetic { .. } => true,
+            _ => false,
+        };
+        let is_constant = |node: &ASTNode| matches!(node, ASTNode::Number(_) | ASTNode::Str(_) | ASTNode::Bool(_));
+        if is_field(left) || is_field(right) || (is_constant(left) && is_constant(right)) {
+            Ok(())
+        } else {
+            Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: left_idx})
+        }
+    }
+
+    /// `$b` on the right of a comparator is the field `b`, not a string, so it has to
+    /// be a valid field name
+    fn validate_field_ref(node: &ASTNode, idx: usize) -> Result<(), ParseError> {
+        match node {
+            ASTNode::Literal(l) if l.starts_with('$') => Self::validate_field_name(&l[1..], idx + 1),
+            _ => Ok(()),
+        }
+    }
+
+    fn parse_logical_op<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let (op, op_idx) = match iter.next() {
+            Some(Token {ty: TokenT::ConditionalOperator(cond_op), idx, ..}) => (cond_op, idx),
+            Some(Token {idx, ..}) => return Err(ParseError {ty: ParseErrorT::Unexpected, cursor: idx}),
+            None => return Err(ParseError {ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+        };
+    
+        Self::expect(iter, TokenT::OpenParen)?;
+        let mut conditions = Vec::new();
+        let mut spans = Vec::new();
+
+        let close_end = loop {
+            let (condition, span) = Self::parse_condition(iter)?;
+            conditions.push(Box::new(condition));
+            spans.push(span);
+            trace!("{:?}", conditions);
+            match iter.peek() {
+                Some(Token{ ty: TokenT::CloseParen, end, ..}) => {
+                    let end = *end;
+                    iter.next();
+                    break end;
+                }
+                // the next condition, or a group `(&(...))` that `parse_condition` unwraps
+                Some(Token{ ty: TokenT::OpenParen, ..}) => continue,
+                Some(Token{ idx, ..}) => {
+                    trace!("here5");
+                    return Err(ParseError {ty: ParseErrorT::Unexpected, cursor: *idx /*TODO:handle index of this properly*/});
+                }
+                None => {
+                    return Err(ParseError {ty: ParseErrorT::MissingCloseParen, cursor: 0 /*TODO:handle index of this properly*/});
+                }
+            }
+        };
+        let ret_node = ASTNode::ConditionalOperator {
+            op,
+            conditions,
+        };
+        trace!("{:?}", ret_node);
+        Ok((ret_node, SpanNode { span: op_idx..close_end, children: spans }))
+    }    
+
+    /// the parser recurses once per paren, bracket and `if`, and every arithmetic operator
+    /// nests the expression so far one deeper (`1 + 1 + 1` is `(1 + 1) + 1`), so cap how
+    /// deep those go before starting rather than overflowing the stack partway through
+    fn check_nesting(tokens: &[Token]) -> Result<(), ParseError> {
+        let mut depth: usize = 0;
+        // `if`s nest without closing, count every one of them
+        let mut ifs = 0;
+        // arithmetic operators in the expression open at each depth, a new operand or
+        // condition starts over
+        let mut chains: Vec<usize> = vec![0];
+        for t in tokens {
+            match t.ty {
+                TokenT::OpenParen | TokenT::OpenBracket => {
+                    depth += 1;
+                    chains.push(0);
+                }
+                TokenT::CloseParen | TokenT::CloseBracket => {
+                    depth = depth.saturating_sub(1);
+                    if chains.len() > 1 {
+                        chains.pop();
+                    }
+                }
+                TokenT::If => ifs += 1,
+                TokenT::ArithmeticOperator(_) => *chains.last_mut().unwrap() += 1,
+                TokenT::Comma | TokenT::Colon | TokenT::Comparator(_) | TokenT::ConditionalOperator(_) | TokenT::Dot | TokenT::Semicolon => {
+                    *chains.last_mut().unwrap() = 0;
+                    continue;
+                }
+                _ => continue,
+            }
+            if depth + ifs + chains.iter().sum::<usize>() > MAX_NESTING {
+                return Err(ParseError {ty: ParseErrorT::TooDeeplyNested, cursor: t.idx});
+            }
+        }
+        Ok(())
+    }
+
+    /// rejects `range(a, b)`s spanning more than `max` values. anything that isn't a well formed
+    /// range is left for the parser to report
+    fn check_range_lens(tokens: &[Token], max: usize) -> Result<(), ParseError> {
+        for window in tokens.windows(6) {
+            if let [Token{ ty: TokenT::Range, idx, ..}, Token{ ty: TokenT::OpenParen, ..}, Token{ ty: TokenT::Number(from), ..}, Token{ ty: TokenT::Comma, ..}, Token{ ty: TokenT::Number(to), ..}, Token{ ty: TokenT::CloseParen, ..}] = window {
+                if to - from >= max as f64 {
+                    return Err(ParseError {ty: ParseErrorT::RangeTooLarge, cursor: *idx});
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// one stage, whichever keyword it starts with. anything but a stage keyword is an error
+    fn parse_stage<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        match Self::peek_kind(iter) {
+            Some(TokenT::Match) => Self::parse_match(iter),
+            Some(TokenT::Set) => Self::parse_set(iter),
+            Some(TokenT::Rename) => Self::parse_rename(iter),
+            Some(TokenT::Unset) => Self::parse_unset(iter),
+            Some(TokenT::Require) => Self::parse_require(iter),
+            Some(TokenT::Project) => Self::parse_project(iter),
+            Some(TokenT::Facet) => Self::parse_facet(iter),
+            Some(TokenT::Lookup) => Self::parse_lookup(iter),
+            Some(TokenT::Group) => Self::parse_group(iter),
+            Some(TokenT::Sort) => Self::parse_sort(iter),
+            Some(TokenT::Limit) => Self::parse_limit(iter),
+            Some(TokenT::Sample) => Self::parse_sample(iter),
+            _ => Err(ParseError {ty: ParseErrorT::Unexpected, cursor: Self::peek_idx(iter)}),
+        }
+    }
+
+    fn parse_match<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        match iter.next() {
+            Some(Token{ ty: TokenT::Match, idx, ..}) => {
+                Self::expect(iter, TokenT::OpenParen)?;
+    
+                let (condition_chain, chain_span) = Self::parse_predicate(iter)?;
+                Self::check_single_text(&condition_chain, &chain_span, &mut false)?;
+                let mut span = idx..Self::expect(iter, TokenT::CloseParen)?.end;
+                let mut condition_chain = condition_chain;
+                // `.comment("why")` goes with the match in front of it
+                if matches!(Self::peek_kind(iter), Some(TokenT::Dot)) && matches!(Self::peek_kind_nth(iter, 1), Some(TokenT::Comment)) {
+                    iter.next();
+                    let (comment, end) = Self::parse_comment(iter)?;
+                    condition_chain = ASTNode::Comment { condition: Box::new(condition_chain), comment };
+                    span.end = end;
+                }
+                Ok((ASTNode::Match(Box::new(condition_chain)), SpanNode { span, children: vec![chain_span] }))
+            }
+            Some(Token {idx, ..}) => {
+                trace!("here2");
+                Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: idx/*0*/})
+            }
+            None => Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/})
+        }
+    }
+    
+    /// `comment("why")`, giving back the comment and where it ends
+    fn parse_comment<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(String, usize), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        iter.next();
+        Self::expect(iter, TokenT::OpenParen)?;
+        let comment = match iter.next() {
+            Some(Token{ ty: TokenT::Str(comment), ..}) => comment,
+            Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::InvalidComment, cursor: idx}),
+            None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+        };
+        let end = Self::expect(iter, TokenT::CloseParen)?.end;
+        Ok((comment, end))
+    }
+
+    /// the inside of `match(...)` or `elem_match(...)`: one or more predicates, commas
+    /// between them meaning and
+    fn parse_predicate<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let (first, first_span) = Self::parse_single_predicate(iter)?;
+        Self::parse_comma_and(iter, first, first_span)
+    }
+
+    /// any `, b == 2, c` following `first`, and-ing it all together. a comma binds looser
+    /// than everything else, infix `&`/`|` included: it only ever splits a match body or
+    /// a paren group, and `&(...)`/`|(...)` keep their operands in their own parens, so
+    /// `(a, |((b)(c)))` and `(a, b | c)` are both `a` and (`b` or `c`)
+    fn parse_comma_and<I>(
+        iter: &mut PeekNth<I>,
+        first: ASTNode,
+        first_span: SpanNode,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let (first, first_span) = Self::parse_infix(iter, ConditionalOperator::OR, first, first_span)?;
+        if !matches!(Self::peek_kind(iter), Some(TokenT::Comma)) {
+            return Ok((first, first_span));
+        }
+        let mut conditions = vec![Box::new(first)];
+        let mut spans = vec![first_span];
+        while let Some(TokenT::Comma) = Self::peek_kind(iter) {
+            iter.next();
+            let (condition, span) = Self::parse_single_predicate(iter)?;
+            let (condition, span) = Self::parse_infix(iter, ConditionalOperator::OR, condition, span)?;
+            conditions.push(Box::new(condition));
+            spans.push(span);
+        }
+        Ok((ASTNode::ConditionalOperator { op: ConditionalOperator::AND, conditions }, SpanNode::covering(spans)))
+    }
+
+    /// `first` and whatever `op` chains onto it infix (`a == 1 & b == 2`). `&` binds
+    /// tighter than `|`, so `a & b | c & d` is (`a` and `b`) or (`c` and `d`): the
+    /// operands of an `|` are `&` chains, those of an `&` single predicates. a run of the
+    /// same operator makes one group, not a nested pair per operator
+    fn parse_infix<I>(
+        iter: &mut PeekNth<I>,
+        op: ConditionalOperator,
+        first: ASTNode,
+        first_span: SpanNode,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let (first, first_span) = match op {
+            ConditionalOperator::OR => Self::parse_infix(iter, ConditionalOperator::AND, first, first_span)?,
+            _ => (first, first_span),
+        };
+        if !matches!(Self::peek_kind(iter), Some(TokenT::ConditionalOperator(next)) if *next == op) {
+            return Ok((first, first_span));
+        }
+        let mut conditions = vec![Box::new(first)];
+        let mut spans = vec![first_span];
+        while matches!(Self::peek_kind(iter), Some(TokenT::ConditionalOperator(next)) if *next == op) {
+            iter.next();
+            let (condition, span) = Self::parse_single_predicate(iter)?;
+            let (condition, span) = match op {
+                ConditionalOperator::OR => Self::parse_infix(iter, ConditionalOperator::AND, condition, span)?,
+                _ => (condition, span),
+            };
+            conditions.push(Box::new(condition));
+            spans.push(span);
+        }
+        Ok((ASTNode::ConditionalOperator { op, conditions }, SpanNode::covering(spans)))
+    }
+
+    /// the rest of a paren group once `first` is parsed: more comma separated predicates,
+    /// then the `)`
+    fn close_group<I>(
+        iter: &mut PeekNth<I>,
+        first: ASTNode,
+        first_span: SpanNode,
+        open_idx: usize,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let (node, span) = Self::parse_comma_and(iter, first, first_span)?;
+        let end = Self::expect(iter, TokenT::CloseParen)?.end;
+        Ok((node, span.widened(open_idx..end)))
+    }
+
+    /// a single condition, or one that skips its own parens (`a == 1`, `tags all [x, y]`,
+    /// `active`)
+    fn parse_single_predicate<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        Self::check_left_operand(iter)?;
+        let chain_idx = Self::peek_idx(iter);
+        let (condition_chain, chain_span) = Self::parse_condition(iter)?;
+        let (mut condition_chain, mut chain_span) = Self::parse_operand_arithmetic(iter, condition_chain, chain_span)?;
+        trace!("{:?}", condition_chain);
+        match Self::peek_kind(iter) {
+            Some(TokenT::Comparator(op)) => {
+                let op = *op;
+                if let ASTNode::Literal(field) | ASTNode::Length(field) = &condition_chain {
+                    Self::validate_field_name(field, chain_idx)?;
+                }
+                let op_idx = Self::peek_idx(iter);
+                iter.next();
+                (condition_chain, chain_span) = Self::parse_comparison(iter, op, op_idx, condition_chain, chain_span, chain_idx)?;
+            }
+            Some(TokenT::All) => {
+                (condition_chain, chain_span) = Self::parse_all(iter, condition_chain, chain_span, chain_idx)?;
+            }
+            Some(TokenT::Is) => {
+                (condition_chain, chain_span) = Self::parse_is_empty(iter, condition_chain, chain_span, chain_idx)?;
+            }
+            Some(TokenT::In) => {
+                (condition_chain, chain_span) = Self::parse_in(iter, condition_chain, chain_span, chain_idx)?;
+            }
+            Some(TokenT::Has) => {
+                (condition_chain, chain_span) = Self::parse_has_value(iter, condition_chain, chain_span, chain_idx)?;
+            }
+            Some(TokenT::Matches) => {
+                (condition_chain, chain_span) = Self::parse_matches(iter, condition_chain, chain_span, chain_idx)?;
+            }
+            Some(TokenT::Literal(word)) if word == "type" => {
+                (condition_chain, chain_span) = Self::parse_type(iter, condition_chain, chain_span, chain_idx)?;
+            }
+            Some(TokenT::Literal(word)) if word == "between" => {
+                (condition_chain, chain_span) = Self::parse_between(iter, condition_chain, chain_span, chain_idx)?;
+            }
+            _ => {}
+        }
+        if let ASTNode::Literal(field) = condition_chain {
+            Self::validate_field_name(&field, chain_idx)?;
+            (condition_chain, chain_span) = Self::truthiness(field, true, chain_span, chain_idx);
+        }
+        // a bare value (`match(true)`) isn't something documents can be filtered on
+        match condition_chain {
+            ASTNode::Condition { .. } | ASTNode::ConditionalOperator { .. } | ASTNode::Text { .. } | ASTNode::All { .. } | ASTNode::In { .. } | ASTNode::HasValue(_)
+            | ASTNode::Matches { .. } | ASTNode::Type { .. } => {
+                Ok((condition_chain, chain_span))
+            }
+            _ => Err(ParseError{ ty: ParseErrorT::MissingComparator, cursor: Self::peek_idx(iter)}),
+        }
+    }
+
+    /// `is empty` / `is not empty`, `left` being the array field in front of it. sugar
+    /// for `left.length == 0` / `left.length != 0`
+    fn parse_is_empty<I>(
+        iter: &mut PeekNth<I>,
+        left: ASTNode,
+        left_span: SpanNode,
+        left_idx: usize,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let field = match left {
+            ASTNode::Literal(field) => {
+                Self::validate_field_name(&field, left_idx)?;
+                field
+            }
+            _ => return Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: left_idx}),
+        };
+        let is_idx = Self::peek_idx(iter);
+        iter.next();
+        let op = if let Some(TokenT::Not) = Self::peek_kind(iter) {
+            iter.next();
+            Comparator::NEQ
+        } else {
+            Comparator::EQ
+        };
+        match iter.next() {
+            Some(Token{ ty: TokenT::Empty, end, ..}) => {
+                let node = ASTNode::Condition {
+                    op,
+                    left: Box::new(ASTNode::Length(field)),
+                    right: Box::new(ASTNode::Number(0.0)),
+                };
+                let span = SpanNode::covering(vec![left_span, SpanNode::leaf(is_idx..end)]);
+                Ok((node, span))
+            }
+            Some(Token{ idx, ..}) => Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: idx}),
+            None => Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+        }
+    }
+
+    /// `all [a, 3, elem_match((qty > 5))]`, `left` being the field in front of it.
+    /// `elem_match` conditions are on the array elements, like `match` on documents
+    fn parse_all<I>(
+        iter: &mut PeekNth<I>,
+        left: ASTNode,
+        left_span: SpanNode,
+        left_idx: usize,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let field = match left {
+            ASTNode::Literal(field) => {
+                Self::validate_field_name(&field, left_idx)?;
+                field
+            }
+            _ => return Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: left_idx}),
+        };
+        let all_idx = Self::peek_idx(iter);
+        iter.next();
+        match iter.next() {
+            Some(Token{ ty: TokenT::OpenBracket, ..}) => {}
+            Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::MissingOpenBracket, cursor: idx}),
+            None => return Err(ParseError{ ty: ParseErrorT::MissingOpenBracket, cursor: all_idx}),
+        }
+        let mut values = Vec::new();
+        let mut spans = vec![left_span];
+        if let Some(Token{ ty: TokenT::CloseBracket, end, ..}) = iter.peek() {
+            let span = spans[0].span.start..*end;
+            iter.next();
+            return Ok((ASTNode::All { field, values }, SpanNode { span, children: spans }));
+        }
+        let close_end = loop {
+            let (value, span) = match iter.next() {
+                Some(Token{ ty: TokenT::ElemMatch, idx, ..}) => {
+                    Self::expect(iter, TokenT::OpenParen)?;
+                    let (condition, condition_span) = Self::parse_predicate(iter)?;
+                    let end = Self::expect(iter, TokenT::CloseParen)?.end;
+                    (ASTNode::ElemMatch(Box::new(condition)), condition_span.widened(idx..end))
+                }
+                Some(Token{ ty: TokenT::Literal(v), idx, end}) => (ASTNode::Literal(v), SpanNode::leaf(idx..end)),
+                Some(Token{ ty: TokenT::Str(v), idx, end}) => (ASTNode::Str(v), SpanNode::leaf(idx..end)),
+                Some(Token{ ty: TokenT::Number(n), idx, end}) => (ASTNode::Number(n), SpanNode::leaf(idx..end)),
+                Some(Token{ ty: TokenT::Bool(b), idx, end}) => (ASTNode::Bool(b), SpanNode::leaf(idx..end)),
+                Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: idx}),
+                None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+            };
+            values.push(value);
+            spans.push(span);
+            match iter.next() {
+                Some(Token{ ty: TokenT::Comma, ..}) => continue,
+                Some(Token{ ty: TokenT::CloseBracket, end, ..}) => break end,
+                Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::UnmatchedBracket, cursor: idx}),
+                None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+            }
+        };
+        let span = spans[0].span.start..close_end;
+        Ok((ASTNode::All { field, values }, SpanNode { span, children: spans }))
+    }
+
+    /// `has value`, `left` being the field in front of it. `value` isn't a keyword, it
+    /// only means anything right after `has`, so fields can still be called `value`
+    fn parse_has_value<I>(
+        iter: &mut PeekNth<I>,
+        left: ASTNode,
+        left_span: SpanNode,
+        left_idx: usize,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let field = match left {
+            ASTNode::Literal(field) => {
+                Self::validate_field_name(&field, left_idx)?;
+                field
+            }
+            _ => return Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: left_idx}),
+        };
+        let has_idx = Self::peek_idx(iter);
+        iter.next();
+        match iter.next() {
+            Some(Token{ ty: TokenT::Literal(word), end, ..}) if word == "value" => {
+                let span = SpanNode::covering(vec![left_span, SpanNode::leaf(has_idx..end)]);
+                Ok((ASTNode::HasValue(field), span))
+            }
+            Some(Token{ idx, ..}) => Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: idx}),
+            None => Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+        }
+    }
+
+    /// `matches "pattern"`, `matches $other` or `matches field(other)`, `left` being
+    /// the field in front of it. like `value`, `field` is only special right here
+    fn parse_matches<I>(
+        iter: &mut PeekNth<I>,
+        left: ASTNode,
+        left_span: SpanNode,
+        left_idx: usize,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let field = match left {
+            ASTNode::Literal(field) => {
+                Self::validate_field_name(&field, left_idx)?;
+                field
+            }
+            _ => return Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: left_idx}),
+        };
+        iter.next();
+        let (pattern, pattern_span) = match iter.next() {
+            Some(Token{ ty: TokenT::Str(pattern), idx, end}) => (ASTNode::Str(pattern), idx..end),
+            Some(Token{ ty: TokenT::Literal(other), idx, end}) if other.starts_with('$') => {
+                let pattern = ASTNode::Literal(other);
+                Self::validate_field_ref(&pattern, idx)?;
+                (pattern, idx..end)
+            }
+            Some(Token{ ty: TokenT::Literal(word), idx, ..}) if word == "field" => {
+                Self::expect(iter, TokenT::OpenParen)?;
+                let other_idx = Self::peek_idx(iter);
+                let other = match iter.next() {
+                    Some(Token{ ty: TokenT::Literal(other), ..}) => other,
+                    Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: idx}),
+                    None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+                };
+                Self::validate_field_name(&other, other_idx)?;
+                let end = Self::expect(iter, TokenT::CloseParen)?.end;
+                (ASTNode::Literal(format!("${}", other)), idx..end)
+            }
+            Some(Token{ ty: TokenT::Literal(word), idx, ..}) if word == "regex" => {
+                Self::expect(iter, TokenT::OpenParen)?;
+                let pattern = match iter.next() {
+                    Some(Token{ ty: TokenT::Str(pattern), ..}) => pattern,
+                    Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: idx}),
+                    None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+                };
+                let mut options = String::new();
+                if let Some(TokenT::Comma) = Self::peek_kind(iter) {
+                    iter.next();
+                    options = match iter.next() {
+                        Some(Token{ ty: TokenT::Str(options), ..}) if Self::valid_regex_options(&options) => options,
+                        Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::InvalidRegexOptions, cursor: idx}),
+                        None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+                    };
+                }
+                let end = Self::expect(iter, TokenT::CloseParen)?.end;
+                (ASTNode::Regex { pattern, options }, idx..end)
+            }
+            Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: idx}),
+            None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+        };
+        let span = SpanNode::covering(vec![left_span, SpanNode::leaf(pattern_span)]);
+        Ok((ASTNode::Matches { field, pattern: Box::new(pattern) }, span))
+    }
+
+    fn valid_regex_options(options: &str) -> bool {
+        options.chars().all(|c| matches!(c, 'i' | 'm' | 's' | 'x'))
+    }
+
+    /// whether the next token is the `type` of `value type string` or the `between` of
+    /// `age between [18, 65)`. like `value` they're not keywords, so fields can still
+    /// be called that
+    fn at_operator_word<I>(iter: &mut PeekNth<I>) -> bool
+    where
+        I: Iterator<Item = Token>,
+    {
+        matches!(Self::peek_kind(iter), Some(TokenT::Literal(word)) if word == "type" || word == "between")
+    }
+
+    /// `between [lo, hi]`, `left` being the field in front of it. sugar for a pair of
+    /// comparisons, the brackets picking which ends are included like interval notation:
+    /// `[18, 65]` is `>= 18` and `<= 65`, `(18, 65)` is `> 18` and `< 65`, and
+    /// `[18, 65)` / `(18, 65]` mix the two. bounds with nothing between them are an error
+    fn parse_between<I>(
+        iter: &mut PeekNth<I>,
+        left: ASTNode,
+        left_span: SpanNode,
+        left_idx: usize,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        if let ASTNode::Literal(field) | ASTNode::Length(field) = &left {
+            Self::validate_field_name(field, left_idx)?;
+        } else {
+            return Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: left_idx});
+        }
+        let between_idx = Self::peek_idx(iter);
+        iter.next();
+        let lower_op = match iter.next() {
+            Some(Token{ ty: TokenT::OpenBracket, ..}) => Comparator::GTE,
+            Some(Token{ ty: TokenT::OpenParen, ..}) => Comparator::GT,
+            Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::MissingOpenBracket, cursor: idx}),
+            None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+        };
+        let lower_idx = Self::peek_idx(iter);
+        let lower = Self::parse_bound(iter)?;
+        Self::expect(iter, TokenT::Comma)?;
+        let upper = Self::parse_bound(iter)?;
+        let (upper_op, close_end) = match iter.next() {
+            Some(Token{ ty: TokenT::CloseBracket, end, ..}) => (Comparator::LTE, end),
+            Some(Token{ ty: TokenT::CloseParen, end, ..}) => (Comparator::LT, end),
+            Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::UnmatchedBracket, cursor: idx}),
+            None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+        };
+        // bounds the wrong way round, or the same one excluded, can't match anything
+        let inclusive = lower_op == Comparator::GTE && upper_op == Comparator::LTE;
+        let empty = Self::compare_constants(Comparator::GT, &lower, &upper) == Some(true)
+            || (!inclusive && Self::compare_constants(Comparator::EQ, &lower, &upper) == Some(true));
+        if empty {
+            return Err(ParseError{ ty: ParseErrorT::InvalidRange, cursor: lower_idx});
+        }
+        let node = ASTNode::ConditionalOperator {
+            op: ConditionalOperator::AND,
+            conditions: vec![
+                Box::new(ASTNode::Condition { op: lower_op, left: Box::new(left.clone()), right: Box::new(lower) }),
+                Box::new(ASTNode::Condition { op: upper_op, left: Box::new(left), right: Box::new(upper) }),
+            ],
+        };
+        let span = SpanNode::covering(vec![left_span, SpanNode::leaf(between_idx..close_end)]);
+        Ok((node, span))
+    }
+
+    /// one end of a `between`, a number or a string
+    fn parse_bound<I>(iter: &mut PeekNth<I>) -> Result<ASTNode, ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        match iter.next() {
+            Some(Token{ ty: TokenT::Number(n), ..}) => Ok(ASTNode::Number(n)),
+            Some(Token{ ty: TokenT::Str(v), ..}) => Ok(ASTNode::Str(v)),
+            Some(Token{ idx, ..}) => Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: idx}),
+            None => Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+        }
+    }
+
+    /// `type string` or `type [string, int]`, `left` being the field in front of it
+    fn parse_type<I>(
+        iter: &mut PeekNth<I>,
+        left: ASTNode,
+        left_span: SpanNode,
+        left_idx: usize,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let field = match left {
+            ASTNode::Literal(field) => {
+                Self::validate_field_name(&field, left_idx)?;
+                field
+            }
+            _ => return Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: left_idx}),
+        };
+        let type_idx = Self::peek_idx(iter);
+        iter.next();
+        let mut types = Vec::new();
+        let types_end = match iter.next() {
+            Some(Token{ ty: TokenT::OpenBracket, ..}) => loop {
+                types.push(Self::parse_type_name(iter)?);
+                match iter.next() {
+                    Some(Token{ ty: TokenT::Comma, ..}) => continue,
+                    Some(Token{ ty: TokenT::CloseBracket, end, ..}) => break end,
+                    Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::UnmatchedBracket, cursor: idx}),
+                    None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+                }
+            },
+            Some(Token{ ty: TokenT::Literal(name), idx, end}) => {
+                types.push(Self::check_type_name(name, idx)?);
+                end
+            }
+            Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::UnknownType, cursor: idx}),
+            None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+        };
+        let span = SpanNode::covering(vec![left_span, SpanNode::leaf(type_idx..types_end)]);
+        Ok((ASTNode::Type { field, types }, span))
+    }
+
+    /// one name inside `type [...]`
+    fn parse_type_name<I>(iter: &mut PeekNth<I>) -> Result<String, ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        match iter.next() {
+            Some(Token{ ty: TokenT::Literal(name), idx, ..}) => Self::check_type_name(name, idx),
+            Some(Token{ idx, ..}) => Err(ParseError{ ty: ParseErrorT::UnknownType, cursor: idx}),
+            None => Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+        }
+    }
+
+    fn check_type_name(name: String, idx: usize) -> Result<String, ParseError> {
+        match TYPE_NAMES.contains(&name.as_str()) {
+            true => Ok(name),
+            false => Err(ParseError{ ty: ParseErrorT::UnknownType, cursor: idx}),
+        }
+    }
+
+    /// `in [1, "a", b]` or `in range(1, 5)`, `left` being the field in front of it.
+    /// both ends of a range are included
+    fn parse_in<I>(
+        iter: &mut PeekNth<I>,
+        left: ASTNode,
+        left_span: SpanNode,
+        left_idx: usize,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let field = match left {
+            ASTNode::Literal(field) => {
+                Self::validate_field_name(&field, left_idx)?;
+                field
+            }
+            _ => return Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: left_idx}),
+        };
+        let in_idx = Self::peek_idx(iter);
+        iter.next();
+        let mut values = Vec::new();
+        let mut spans = vec![left_span];
+        match iter.next() {
+            Some(Token{ ty: TokenT::Range, idx: range_idx, ..}) => {
+                Self::expect(iter, TokenT::OpenParen)?;
+                let from = Self::parse_range_bound(iter)?;
+                Self::expect(iter, TokenT::Comma)?;
+                let to = Self::parse_range_bound(iter)?;
+                let close_end = Self::expect(iter, TokenT::CloseParen)?.end;
+                if from > to {
+                    return Err(ParseError{ ty: ParseErrorT::InvalidRange, cursor: range_idx});
+                }
+                if to - from >= MAX_RANGE_LEN as i64 {
+                    return Err(ParseError{ ty: ParseErrorT::RangeTooLarge, cursor: range_idx});
+                }
+                values.extend((from..=to).map(|n| ASTNode::Number(n as f64)));
+                spans.push(SpanNode::leaf(range_idx..close_end));
+            }
+            Some(Token{ ty: TokenT::OpenBracket, idx: open_idx, ..}) => {
+                let close_end = if let Some(Token{ ty: TokenT::CloseBracket, end, ..}) = iter.peek() {
+                    let end = *end;
+                    iter.next();
+                    end
+                } else {
+                    loop {
+                        let value = match iter.next() {
+                            Some(Token{ ty: TokenT::Literal(v), ..}) if v == "null" => ASTNode::Null,
+                            Some(Token{ ty: TokenT::Literal(v), ..}) => ASTNode::Literal(v),
+                            Some(Token{ ty: TokenT::Str(v), ..}) => ASTNode::Str(v),
+                            Some(Token{ ty: TokenT::Number(n), ..}) => ASTNode::Number(n),
+                            Some(Token{ ty: TokenT::Bool(b), ..}) => ASTNode::Bool(b),
+                            Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: idx}),
+                            None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+                        };
+                        values.push(value);
+                        match iter.next() {
+                            Some(Token{ ty: TokenT::Comma, ..}) => continue,
+                            Some(Token{ ty: TokenT::CloseBracket, end, ..}) => break end,
+                            Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::UnmatchedBracket, cursor: idx}),
+                            None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+                        }
+                    }
+                };
+                spans.push(SpanNode::leaf(open_idx..close_end));
+            }
+            Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::MissingOpenBracket, cursor: idx}),
+            None => return Err(ParseError{ ty: ParseErrorT::MissingOpenBracket, cursor: in_idx}),
+        }
+        Ok((ASTNode::In { field, values }, SpanNode::covering(spans)))
+    }
+
+    /// one end of a `range(a, b)`, which has to be a whole number
+    fn parse_range_bound<I>(iter: &mut PeekNth<I>) -> Result<i64, ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        match iter.next() {
+            Some(Token{ ty: TokenT::Number(n), ..}) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => Ok(n as i64),
+            Some(Token{ idx, ..}) => Err(ParseError{ ty: ParseErrorT::InvalidRange, cursor: idx}),
+            None => Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+        }
+    }
+
+    /// `text("coffee shop")`, optionally followed by `language: <lang>` and/or
+    /// `case_sensitive: <bool>`
+    fn parse_text<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let text_idx = Self::peek_idx(iter);
+        iter.next();
+        Self::expect(iter, TokenT::OpenParen)?;
+        let search = match iter.next() {
+            Some(Token{ ty: TokenT::Str(search), ..}) => search,
+            Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: idx}),
+            None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+        };
+        let mut language = None;
+        let mut case_sensitive = None;
+        let close_end = loop {
+            match iter.next() {
+                Some(Token{ ty: TokenT::CloseParen, end, ..}) => break end,
+                Some(Token{ ty: TokenT::Comma, ..}) => {}
+                Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::UnmatchedParenthesis, cursor: idx}),
+                None => return Err(ParseError{ ty: ParseErrorT::MissingCloseParen, cursor: 0/*TODO*/}),
+            }
+            let option = match iter.next() {
+                Some(Token{ ty: TokenT::Literal(option), idx, ..}) => (option, idx),
+                Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: idx}),
+                None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+            };
+            Self::expect(iter, TokenT::Colon)?;
+            match (option.0.as_str(), iter.next()) {
+                ("language", Some(Token{ ty: TokenT::Literal(lang) | TokenT::Str(lang), ..})) => language = Some(lang),
+                ("case_sensitive", Some(Token{ ty: TokenT::Bool(b), ..})) => case_sensitive = Some(b),
+                ("language" | "case_sensitive", Some(Token{ idx, ..})) => return Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: idx}),
+                (_, Some(_)) => return Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: option.1}),
+                (_, None) => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+            }
+        };
+        Ok((ASTNode::Text { search, language, case_sensitive }, SpanNode::leaf(text_idx..close_end)))
+    }
+
+    /// mongo only allows a single `$text` per query. errors at the second one
+    fn check_single_text(node: &ASTNode, span: &SpanNode, seen: &mut bool) -> Result<(), ParseError> {
+        match node {
+            ASTNode::Text { .. } => {
+                if *seen {
+                    return Err(ParseError{ ty: ParseErrorT::DuplicateText, cursor: span.span.start});
+                }
+                *seen = true;
+            }
+            ASTNode::ConditionalOperator { conditions, .. } => {
+                for (condition, child_span) in conditions.iter().zip(span.children.iter()) {
+                    Self::check_single_text(condition, child_span, seen)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// `set(total: price * quantity, tax: 0.2)`, each value is an arithmetic expression
+    fn parse_set<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let set_idx = Self::peek_idx(iter);
+        iter.next();
+        Self::expect(iter, TokenT::OpenParen)?;
+        let (assignments, span) = Self::parse_assignments(iter, set_idx, Self::parse_expression)?;
+        Ok((ASTNode::Set(assignments), span))
+    }
+
+    /// `project(email: 1, fullName: name, items: slice(5), _id: 0)`, the same
+    /// `field: expression` list as `set` where a value can also be a `slice`
+    fn parse_project<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let project_idx = Self::peek_idx(iter);
+        iter.next();
+        Self::expect(iter, TokenT::OpenParen)?;
+        let (fields, span) = Self::parse_assignments(iter, project_idx, Self::parse_projection_value)?;
+        Ok((ASTNode::Project(fields), span))
+    }
+
+    fn parse_projection_value<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        // `slice` isn't reserved, a field of that name is still fine
+        let at_slice = matches!(Self::peek_kind(iter), Some(TokenT::Literal(word)) if word == "slice")
+            && matches!(Self::peek_kind_nth(iter, 1), Some(TokenT::OpenParen));
+        if !at_slice {
+            return Self::parse_expression(iter);
+        }
+        let slice_idx = Self::peek_idx(iter);
+        iter.next();
+        iter.next();
+        let first = Self::parse_slice_arg(iter)?;
+        let (skip, count) = match Self::peek_kind(iter) {
+            Some(TokenT::Comma) => {
+                iter.next();
+                let count_idx = Self::peek_idx(iter);
+                let count = Self::parse_slice_arg(iter)?;
+                if count < 1 {
+                    return Err(ParseError{ ty: ParseErrorT::InvalidSlice, cursor: count_idx});
+                }
+                (Some(first), count)
+            }
+            _ => (None, first),
+        };
+        let end = Self::expect(iter, TokenT::CloseParen)?.end;
+        Ok((ASTNode::Slice { skip, count }, SpanNode::leaf(slice_idx..end)))
+    }
+
+    /// a whole number, `-` in front for a negative one
+    fn parse_slice_arg<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<i64, ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let idx = Self::peek_idx(iter);
+        let negative = matches!(Self::peek_kind(iter), Some(TokenT::ArithmeticOperator(ArithmeticOperator::SUB)));
+        if negative {
+            iter.next();
+        }
+        match iter.next() {
+            Some(Token{ ty: TokenT::Number(n), ..}) if n.fract() == 0.0 && n <= i64::MAX as f64 => Ok(if negative { -(n as i64) } else { n as i64 }),
+            Some(_) => Err(ParseError{ ty: ParseErrorT::InvalidSlice, cursor: idx}),
+            None => Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+        }
+    }
+
+    /// `a: value, b: value)` up to and including the `)`, each value read by `value`.
+    /// spans from `stage_idx` with a child span per value
+    fn parse_assignments<I, F>(
+        iter: &mut PeekNth<I>,
+        stage_idx: usize,
+        value: F,
+    ) -> Result<(Vec<(String, ASTNode)>, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+        F: Fn(&mut PeekNth<I>) -> Result<(ASTNode, SpanNode), ParseError>,
+    {
+        let mut assignments = Vec::new();
+        let mut spans = Vec::new();
+        let close_end = loop {
+            let field = match iter.next() {
+                Some(Token{ ty: TokenT::Literal(field), idx, ..}) => {
+                    Self::validate_field_name(&field, idx)?;
+                    field
+                }
+                Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: idx}),
+                None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+            };
+            Self::expect(iter, TokenT::Colon)?;
+            let (value, span) = value(iter)?;
+            assignments.push((field, value));
+            spans.push(span);
+            match iter.next() {
+                Some(Token{ ty: TokenT::Comma, ..}) => continue,
+                Some(Token{ ty: TokenT::CloseParen, end, ..}) => break end,
+                Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::UnmatchedParenthesis, cursor: idx}),
+                None => return Err(ParseError{ ty: ParseErrorT::MissingCloseParen, cursor: 0/*TODO*/}),
+            }
+        };
+        Ok((assignments, SpanNode { span: stage_idx..close_end, children: spans }))
+    }
+
+    /// `facet(name: [stage.stage], other: [stage])`, each pipeline chained with `.`s
+    /// like the top-level one
+    fn parse_facet<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let facet_idx = Self::peek_idx(iter);
+        iter.next();
+        Self::expect(iter, TokenT::OpenParen)?;
+        let mut branches = Vec::new();
+        let mut spans = Vec::new();
+        let close_end = loop {
+            let name = match iter.next() {
+                // the name becomes a top-level field of the output document
+                Some(Token{ ty: TokenT::Literal(name), idx, ..}) if !name.contains('.') => {
+                    Self::validate_field_name(&name, idx)?;
+                    if branches.iter().any(|(taken, _)| *taken == name) {
+                        return Err(ParseError{ ty: ParseErrorT::DuplicateFacetName, cursor: idx});
+                    }
+                    name
+                }
+                Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: idx}),
+                None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+            };
+            Self::expect(iter, TokenT::Colon)?;
+            let open_idx = match iter.next() {
+                Some(Token{ ty: TokenT::OpenBracket, idx, ..}) => idx,
+                Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::MissingOpenBracket, cursor: idx}),
+                None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+            };
+            let mut stages = Vec::new();
+            let mut stage_spans = Vec::new();
+            let branch_end = loop {
+                if let Some(TokenT::Facet) = Self::peek_kind(iter) {
+                    return Err(ParseError{ ty: ParseErrorT::NestedFacet, cursor: Self::peek_idx(iter)});
+                }
+                let (stage, span) = Self::parse_stage(iter)?;
+                stages.push(stage);
+                stage_spans.push(span);
+                match iter.next() {
+                    Some(Token{ ty: TokenT::Dot, idx, ..}) => {
+                        if let Some(TokenT::CloseBracket) | None = Self::peek_kind(iter) {
+                            return Err(ParseError{ ty: ParseErrorT::DanglingDot, cursor: idx});
+                        }
+                    }
+                    Some(Token{ ty: TokenT::CloseBracket, end, ..}) => break end,
+                    Some(Token{ ty: TokenT::Match | TokenT::Set | TokenT::Rename | TokenT::Unset | TokenT::Sort | TokenT::Limit | TokenT::Sample | TokenT::Require | TokenT::Project | TokenT::Facet | TokenT::Lookup | TokenT::Group, idx, ..}) => {
+                        return Err(ParseError{ ty: ParseErrorT::NoDotBetweenFns, cursor: idx});
+                    }
+                    Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::UnmatchedBracket, cursor: idx}),
+                    None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+                }
+            };
+            branches.push((name, stages));
+            spans.push(SpanNode { span: open_idx..branch_end, children: stage_spans });
+            match iter.next() {
+                Some(Token{ ty: TokenT::Comma, ..}) => continue,
+                Some(Token{ ty: TokenT::CloseParen, end, ..}) => break end,
+                Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::UnmatchedParenthesis, cursor: idx}),
+                None => return Err(ParseError{ ty: ParseErrorT::MissingCloseParen, cursor: 0/*TODO*/}),
+            }
+        };
+        Ok((ASTNode::Facet(branches), SpanNode { span: facet_idx..close_end, children: spans }))
+    }
+
+    /// `lookup(from: orders, local: _id, foreign: userId, as: orders)`, the arguments
+    /// in any order
+    fn parse_lookup<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        const ARGS: [&str; 4] = ["from", "local", "foreign", "as"];
+        let lookup_idx = Self::peek_idx(iter);
+        iter.next();
+        Self::expect(iter, TokenT::OpenParen)?;
+        let mut args: [Option<String>; 4] = Default::default();
+        let close = loop {
+            let slot = match iter.next() {
+                Some(Token{ ty: TokenT::Literal(arg), idx, ..}) => match ARGS.iter().position(|a| *a == arg) {
+                    Some(slot) if args[slot].is_none() => slot,
+                    _ => return Err(ParseError{ ty: ParseErrorT::InvalidLookup, cursor: idx}),
+                },
+                Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::InvalidLookup, cursor: idx}),
+                None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+            };
+            Self::expect(iter, TokenT::Colon)?;
+            let value = match iter.next() {
+                // `from` is a collection, the rest are fields
+                Some(Token{ ty: TokenT::Literal(value), idx, ..}) => {
+                    if slot > 0 {
+                        Self::validate_field_name(&value, idx)?;
+                    }
+                    value
+                }
+                Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: idx}),
+                None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+            };
+            args[slot] = Some(value);
+            match iter.next() {
+                Some(Token{ ty: TokenT::Comma, ..}) => continue,
+                Some(close @ Token{ ty: TokenT::CloseParen, ..}) => break close,
+                Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::UnmatchedParenthesis, cursor: idx}),
+                None => return Err(ParseError{ ty: ParseErrorT::MissingCloseParen, cursor: 0/*TODO*/}),
+            }
+        };
+        let [Some(from), Some(local), Some(foreign), Some(as_)] = args else {
+            return Err(ParseError{ ty: ParseErrorT::InvalidLookup, cursor: close.idx});
+        };
+        Ok((ASTNode::Lookup { from, local, foreign, as_ }, SpanNode::leaf(lookup_idx..close.end)))
+    }
+
+    /// `group(branch, count: sum(1))`, the key first, `[branch, year]` for several or
+    /// `[]` for none, then the accumulators named like `set` assignments
+    fn parse_group<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let group_idx = Self::peek_idx(iter);
+        iter.next();
+        Self::expect(iter, TokenT::OpenParen)?;
+        let mut keys = Vec::new();
+        if matches!(Self::peek_kind(iter), Some(TokenT::OpenBracket)) {
+            iter.next();
+            if matches!(Self::peek_kind(iter), Some(TokenT::CloseBracket)) {
+                iter.next();
+            } else {
+                loop {
+                    Self::parse_group_key(iter, &mut keys)?;
+                    match iter.next() {
+                        Some(Token{ ty: TokenT::Comma, ..}) => continue,
+                        Some(Token{ ty: TokenT::CloseBracket, ..}) => break,
+                        Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::UnmatchedBracket, cursor: idx}),
+                        None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+                    }
+                }
+            }
+        } else {
+            Self::parse_group_key(iter, &mut keys)?;
+        }
+        let mut accumulators: Vec<(String, ASTNode)> = Vec::new();
+        let mut spans = Vec::new();
+        let close_end = loop {
+            match iter.next() {
+                Some(Token{ ty: TokenT::Comma, ..}) => {}
+                Some(Token{ ty: TokenT::CloseParen, end, ..}) => break end,
+                Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::UnmatchedParenthesis, cursor: idx}),
+                None => return Err(ParseError{ ty: ParseErrorT::MissingCloseParen, cursor: 0/*TODO*/}),
+            }
+            let name = match iter.next() {
+                // the name becomes a top-level field of the output document, next to `_id`
+                Some(Token{ ty: TokenT::Literal(name), idx, ..}) if !name.contains('.') => {
+                    Self::validate_field_name(&name, idx)?;
+                    if name == "_id" || accumulators.iter().any(|(other, _)| *other == name) {
+                        return Err(ParseError{ ty: ParseErrorT::DuplicateGroupKey, cursor: idx});
+                    }
+                    name
+                }
+                Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: idx}),
+                None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+            };
+            Self::expect(iter, TokenT::Colon)?;
+            let (value, span) = Self::parse_accumulator(iter)?;
+            accumulators.push((name, value));
+            spans.push(span);
+        };
+        Ok((ASTNode::Group { keys, accumulators }, SpanNode { span: group_idx..close_end, children: spans }))
+    }
+
+    /// one field of a `group` key, which can't share its name with another
+    fn parse_group_key<I>(
+        iter: &mut PeekNth<I>,
+        keys: &mut Vec<String>,
+    ) -> Result<(), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        match iter.next() {
+            Some(Token{ ty: TokenT::Literal(key), idx, ..}) => {
+                Self::validate_field_name(&key, idx)?;
+                if keys.iter().any(|other| Self::group_key_name(other) == Self::group_key_name(&key)) {
+                    return Err(ParseError{ ty: ParseErrorT::DuplicateGroupKey, cursor: idx});
+                }
+                keys.push(key);
+                Ok(())
+            }
+            Some(Token{ idx, ..}) => Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: idx}),
+            None => Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+        }
+    }
+
+    /// `sum(price * quantity)`, an `AccumulatorOperator` around an expression
+    fn parse_accumulator<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let (op, idx) = match iter.next() {
+            Some(Token{ ty: TokenT::Literal(name), idx, ..}) => match AccumulatorOperator::ALL.into_iter().find(|op| op.name() == name) {
+                Some(op) => (op, idx),
+                None => return Err(ParseError{ ty: ParseErrorT::InvalidAccumulator, cursor: idx}),
+            },
+            Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::InvalidAccumulator, cursor: idx}),
+            None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+        };
+        Self::expect(iter, TokenT::OpenParen)?;
+        let (arg, span) = Self::parse_expression(iter)?;
+        let end = Self::expect(iter, TokenT::CloseParen)?.end;
+        Ok((ASTNode::Accumulator { op, arg: Box::new(arg) }, SpanNode { span: idx..end, children: vec![span] }))
+    }
+
+    /// what a `group` key is called inside `_id`, its last segment since mongo doesn't
+    /// allow dots there
+    fn group_key_name(key: &str) -> &str {
+        key.rsplit('.').next().unwrap_or(key)
+    }
+
+    /// `rename(old, new)`
+    fn parse_rename<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let rename_idx = Self::peek_idx(iter);
+        iter.next();
+        Self::expect(iter, TokenT::OpenParen)?;
+        let mut fields = Vec::new();
+        let mut close_end = rename_idx;
+        for expected_after in [TokenT::Comma, TokenT::CloseParen] {
+            match iter.next() {
+                Some(Token{ ty: TokenT::Literal(field), idx, ..}) => {
+                    Self::validate_field_name(&field, idx)?;
+                    fields.push(field);
+                }
+                Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: idx}),
+                None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+            }
+            match iter.next() {
+                Some(Token{ ty, end, ..}) if ty == expected_after => close_end = end,
+                Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: idx}),
+                None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+            }
+        }
+        let to = fields.pop().unwrap();
+        let from = fields.pop().unwrap();
+        Ok((ASTNode::Rename { from, to }, SpanNode::leaf(rename_idx..close_end)))
+    }
+
+    /// `unset(a, b)`
+    fn parse_unset<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let unset_idx = Self::peek_idx(iter);
+        iter.next();
+        Self::expect(iter, TokenT::OpenParen)?;
+        let (fields, close_end) = Self::parse_field_list(iter)?;
+        Ok((ASTNode::Unset(fields), SpanNode::leaf(unset_idx..close_end)))
+    }
+
+    /// `require(name, email)`
+    fn parse_require<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let require_idx = Self::peek_idx(iter);
+        iter.next();
+        Self::expect(iter, TokenT::OpenParen)?;
+        // `required` only names top-level properties, `a.b` would be one called "a.b"
+        let mut n = 0;
+        while let Some(Token{ ty: TokenT::Literal(_) | TokenT::Comma, ..}) = iter.peek_nth(n) {
+            if let Some(Token{ ty: TokenT::Literal(field), idx, ..}) = iter.peek_nth(n) {
+                if field.contains('.') {
+                    return Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: *idx});
+                }
+            }
+            n += 1;
+        }
+        let (listed, close_end) = Self::parse_field_list(iter)?;
+        // mongo rejects a `required` that names a field twice
+        let mut fields = Vec::with_capacity(listed.len());
+        for field in listed {
+            if !fields.contains(&field) {
+                fields.push(field);
+            }
+        }
+        Ok((ASTNode::Require(fields), SpanNode::leaf(require_idx..close_end)))
+    }
+
+    /// `sort(age desc, name)`, each field optionally followed by `asc` (the default) or `desc`
+    fn parse_sort<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let sort_idx = Self::peek_idx(iter);
+        iter.next();
+        Self::expect(iter, TokenT::OpenParen)?;
+        let mut keys = Vec::new();
+        let close_end = loop {
+            let field = match iter.next() {
+                Some(Token{ ty: TokenT::Literal(field), idx, ..}) => {
+                    Self::validate_field_name(&field, idx)?;
+                    field
+                }
+                Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: idx}),
+                None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+            };
+            let order = match iter.peek() {
+                Some(Token{ ty: TokenT::Literal(order), idx, ..}) => {
+                    let order = match order.as_str() {
+                        "asc" => SortOrder::ASC,
+                        "desc" => SortOrder::DESC,
+                        _ => return Err(ParseError{ ty: ParseErrorT::InvalidSortOrder, cursor: *idx}),
+                    };
+                    iter.next();
+                    order
+                }
+                _ => SortOrder::ASC,
+            };
+            keys.push((field, order));
+            match iter.next() {
+                Some(Token{ ty: TokenT::Comma, ..}) => continue,
+                Some(Token{ ty: TokenT::CloseParen, end, ..}) => break end,
+                Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::UnmatchedParenthesis, cursor: idx}),
+                None => return Err(ParseError{ ty: ParseErrorT::MissingCloseParen, cursor: 0/*TODO*/}),
+            }
+        };
+        Ok((ASTNode::Sort(keys), SpanNode::leaf(sort_idx..close_end)))
+    }
+
+    /// `limit(10)`
+    fn parse_limit<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let (n, span) = Self::parse_count_stage(iter, ParseErrorT::InvalidLimit)?;
+        Ok((ASTNode::Limit(n), span))
+    }
+
+    /// `sample(100)`
+    fn parse_sample<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let (n, span) = Self::parse_count_stage(iter, ParseErrorT::InvalidSampleSize)?;
+        Ok((ASTNode::Sample(n), span))
+    }
+
+    /// a stage keyword followed by `(n)`, `n` a positive whole number. `invalid` is what
+    /// anything else in the parens is reported as
+    fn parse_count_stage<I>(
+        iter: &mut PeekNth<I>,
+        invalid: ParseErrorT,
+    ) -> Result<(u64, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let stage_idx = Self::peek_idx(iter);
+        iter.next();
+        Self::expect(iter, TokenT::OpenParen)?;
+        let n = match iter.next() {
+            Some(Token{ ty: TokenT::Number(n), ..}) if n >= 1.0 && n.fract() == 0.0 && n <= u64::MAX as f64 => n as u64,
+            Some(Token{ idx, ..}) => return Err(ParseError{ ty: invalid, cursor: idx}),
+            None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+        };
+        let end = Self::expect(iter, TokenT::CloseParen)?.end;
+        Ok((n, SpanNode::leaf(stage_idx..end)))
+    }
+
+    /// `a, b.c, d)`, the open paren already consumed. at least one field. also hands
+    /// back where the closing paren ends
+    fn parse_field_list<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(Vec<String>, usize), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let mut fields = Vec::new();
+        let close_end = loop {
+            match iter.next() {
+                Some(Token{ ty: TokenT::Literal(field), idx, ..}) => {
+                    Self::validate_field_name(&field, idx)?;
+                    fields.push(field);
+                }
+                Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::InvalidFieldName, cursor: idx}),
+                None => return Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+            }
+            match iter.next() {
+                Some(Token{ ty: TokenT::Comma, ..}) => continue,
+                Some(Token{ ty: TokenT::CloseParen, end, ..}) => break end,
+                Some(Token{ idx, ..}) => return Err(ParseError{ ty: ParseErrorT::UnmatchedParenthesis, cursor: idx}),
+                None => return Err(ParseError{ ty: ParseErrorT::MissingCloseParen, cursor: 0/*TODO*/}),
+            }
+        };
+        Ok((fields, close_end))
+    }
+
+    /// `+`/`-` over terms, left associative
+    fn parse_expression<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let (first, first_span) = Self::parse_factor(iter)?;
+        Self::parse_expression_from(iter, first, first_span)
+    }
+
+    /// the rest of an expression whose leftmost factor, `first`, is already parsed
+    fn parse_expression_from<I>(
+        iter: &mut PeekNth<I>,
+        first: ASTNode,
+        first_span: SpanNode,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let (mut left, mut left_span) = Self::parse_term_from(iter, first, first_span)?;
+        while let Some(TokenT::ArithmeticOperator(op @ (ArithmeticOperator::ADD | ArithmeticOperator::SUB))) = Self::peek_kind(iter) {
+            let op = *op;
+            iter.next();
+            let (right, right_span) = Self::parse_term(iter)?;
+            left = ASTNode::Arithmetic { op, left: Box::new(left), right: Box::new(right) };
+            left_span = SpanNode::covering(vec![left_span, right_span]);
+        }
+        Ok((left, left_span))
+    }
+
+    /// `*`/`/` over factors, binds tighter than `+`/`-`
+    fn parse_term<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let (first, first_span) = Self::parse_factor(iter)?;
+        Self::parse_term_from(iter, first, first_span)
+    }
+
+    fn parse_term_from<I>(
+        iter: &mut PeekNth<I>,
+        first: ASTNode,
+        first_span: SpanNode,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        let (mut left, mut left_span) = (first, first_span);
+        while let Some(TokenT::ArithmeticOperator(op @ (ArithmeticOperator::MUL | ArithmeticOperator::DIV))) = Self::peek_kind(iter) {
+            let op = *op;
+            iter.next();
+            let (right, right_span) = Self::parse_factor(iter)?;
+            left = ASTNode::Arithmetic { op, left: Box::new(left), right: Box::new(right) };
+            left_span = SpanNode::covering(vec![left_span, right_span]);
+        }
+        Ok((left, left_span))
+    }
+
+    fn parse_factor<I>(
+        iter: &mut PeekNth<I>,
+    ) -> Result<(ASTNode, SpanNode), ParseError>
+    where
+        I: Iterator<Item = Token>,
+    {
+        match iter.next() {
+            // `.length` means the same as it does in a condition
+            Some(Token{ ty: TokenT::Literal(field), idx, end}) => match field.strip_suffix(".length") {
+                Some(array) if !array.is_empty() && !array.starts_with('$') => Ok((ASTNode::Length(array.to_string()), SpanNode::leaf(idx..end))),
+                _ => Ok((ASTNode::Literal(field), SpanNode::leaf(idx..end))),
+            },
+            Some(Token{ ty: TokenT::Number(num), idx, end}) => Ok((ASTNode::Number(num), SpanNode::leaf(idx..end))),
+            Some(Token{ ty: TokenT::Bool(b), idx, end}) => Ok((ASTNode::Bool(b), SpanNode::leaf(idx..end))),
+            Some(Token{ ty: TokenT::Str(value), idx, end}) => Ok((ASTNode::Str(value), SpanNode::leaf(idx..end))),
+            // `if (qty > 10) then price * 0.9 else price`
+            Some(Token{ ty: TokenT::If, idx: if_idx, ..}) => {
+                let condition_idx = Self::peek_idx(iter);
+                let (mut condition, mut condition_span) = Self::parse_condition(iter)?;
+                if let ASTNode::Literal(field) = condition {
+                    Self::validate_field_name(&field, condition_idx)?;
+                    (condition, condition_span) = Self::truthiness(field, true, condition_span, condition_idx);
+                }
+                Self::expect(iter, TokenT::Then)?;
+                let (then, then_span) = Self::parse_expression(iter)?;
+                Self::expect(iter, TokenT::Else)?;
+                let (otherwise, otherwise_span) = Self::parse_expression(iter)?;
+                let node = ASTNode::Cond {
+                    condition: Box::new(condition),
+                    then: Box::new(then),
+                    otherwise: Box::new(otherwise),
+                };
+                let span = SpanNode::covering(vec![condition_span, then_span, otherwise_span]).widened(if_idx..if_idx);
+                Ok((node, span))
+            }
+            Some(Token{ ty: TokenT::OpenParen, idx: open_idx, ..}) => {
+                let (inner, inner_span) = Self::parse_expression(iter)?;
+                let end = Self::expect(iter, TokenT::CloseParen)?.end;
+                Ok((inner, inner_span.widened(open_idx..end)))
+            }
+            Some(Token{ idx, ..}) => Err(ParseError{ ty: ParseErrorT::Unexpected, cursor: idx}),
+            None => Err(ParseError{ ty: ParseErrorT::EndOfTokenStream, cursor: 0/*TODO*/}),
+        }
+    }
+
+    pub fn parse_tokens(&mut self, tokens: &[Token]) -> Result<(), ParseError>{
+        self.parse_tokens_watched(tokens, &Cell::new(false))
+    }
+
+    /// `parse_tokens`, setting `ran_out` if the parser ever looked past the last token
+    fn parse_tokens_watched(&mut self, tokens: &[Token], ran_out: &Cell<bool>) -> Result<(), ParseError> {
+        if let Some(max) = self.opts.max_number_literal_digits {
+            if let Some(t) = tokens.iter().find(|t| matches!(t.ty, TokenT::Number(_)) && t.end - t.idx > max) {
+                return Err(ParseError {ty: ParseErrorT::NumberTooLong, cursor: t.idx});
+            }
+        }
+        if let Some(max) = self.opts.max_identifier_length {
+            if let Some(t) = tokens.iter().find(|t| matches!(&t.ty, TokenT::Literal(name) if name.chars().count() > max)) {
+                return Err(ParseError {ty: ParseErrorT::IdentifierTooLong, cursor: t.idx});
+            }
+        }
+        if let Some(max) = self.opts.max_tokens {
+            if let Some(t) = tokens.get(max) {
+                return Err(ParseError {ty: ParseErrorT::TooManyTokens, cursor: t.idx});
+            }
+        }
+        if let Some(max) = self.opts.max_range_len {
+            Self::check_range_lens(tokens, max)?;
+        }
+        Self::check_nesting(tokens)?;
+        let mut pipelines = Vec::new();
+        let mut first_spans = None;
+        let mut nodes = Vec::new();
+        let mut spans = Vec::new();
+        // across pipelines, for `max_stages`
+        let mut stage_count = 0;
+        let past_end = std::iter::from_fn(|| {
+            ran_out.set(true);
+            None
+        });
+        let mut iter = peek_nth(tokens.iter().cloned().map(|t| self.opts.resolve_alias(t)).chain(past_end));
+        while let Some(t) = iter.peek() {
+            // `;` ends a pipeline, the next one starts from scratch. a trailing one is fine
+            if let TokenT::Semicolon = t.ty {
+                if nodes.is_empty() {
+                    return Err(ParseError {ty: ParseErrorT::Unexpected, cursor: t.idx});
+                }
+                iter.next();
+                pipelines.push(std::mem::take(&mut nodes));
+                first_spans.get_or_insert(std::mem::take(&mut spans));
+                continue;
+            }
+            // every stage after the first has to be chained on with a `.`
+            if !nodes.is_empty() {
+                match t.ty {
+                    TokenT::Dot => {
+                        let dot_idx = t.idx;
+                        iter.next();
+                        if iter.peek().is_none() {
+                            return Err(ParseError {ty: ParseErrorT::DanglingDot, cursor: dot_idx});
+                        }
+                    }
+                    TokenT::Match | TokenT::Set | TokenT::Rename | TokenT::Unset | TokenT::Sort | TokenT::Limit | TokenT::Sample | TokenT::Require | TokenT::Project | TokenT::Facet | TokenT::Lookup | TokenT::Group => return Err(ParseError {ty: ParseErrorT::NoDotBetweenFns, cursor: t.idx}),
+                    _ => return Err(ParseError {ty: ParseErrorT::Unexpected, cursor: t.idx}),
+                }
+            }
+            if let Some(max) = self.opts.max_stages {
+                if stage_count >= max {
+                    return Err(ParseError {ty: ParseErrorT::TooManyStages, cursor: Self::peek_idx(&mut iter)});
+                }
+            }
+            let (node, span) = Self::parse_stage(&mut iter).map_err(|e| match e.ty {
+                // only the end of the input can leave a `(` unclosed, so point just past it
+                ParseErrorT::MissingCloseParen => ParseError {cursor: tokens.last().map_or(0, |t| t.end), ..e},
+                _ => e,
+            })?;
+            nodes.push(node);
+            spans.push(span);
+            stage_count += 1;
+        }
+        if !nodes.is_empty() || pipelines.is_empty() {
+            pipelines.push(nodes);
+            first_spans.get_or_insert(spans);
+        }
+        self.ast = pipelines[0].clone();
+        self.spans = first_spans.unwrap_or_default();
+        self.pipelines = pipelines;
+        Ok(())
+    }
+
+    /// `&((a == 1))` parses to an `$and` with a single condition, which mongo accepts
+    /// but is just noise. opt-in so the ast otherwise stays exactly what was written
+    pub fn collapse_single_child_groups(&mut self) {
+        for node in self.ast.iter_mut() {
+            node.collapse_single_child_groups();
+        }
+    }
+
+    /// see `ASTNode::sort_groups`. opt-in like `collapse_single_child_groups`
+    pub fn sort_groups(&mut self) {
+        for node in self.ast.iter_mut() {
+            node.sort_groups();
+        }
+    }
+
+    /// applies every safe rewrite in `OptimizeOptions`, see `optimize_with`
+    pub fn optimize(&mut self) {
+        self.optimize_with(&OptimizeOptions::default());
+    }
+
+    /// rewrites the pipeline into an equivalent one that's cheaper to run. hoisting
+    /// goes first so the matches it moves next to each other can then be merged
+    pub fn optimize_with(&mut self, opts: &OptimizeOptions) {
+        if opts.fold_constants {
+            self.fold_constants();
+        }
+        if opts.hoist_matches {
+            self.hoist_matches();
+        }
+        if opts.merge_matches {
+            self.merge_matches();
+        }
+        if opts.collapse_single_child_groups {
+            self.collapse_single_child_groups();
+        }
+        if opts.flatten_groups {
+            for node in self.ast.iter_mut() {
+                node.flatten_groups();
+            }
+        }
+        if opts.dedupe_groups {
+            for node in self.ast.iter_mut() {
+                node.dedupe_groups();
+            }
+            // flattening can line up duplicates, and dropping them can leave one behind
+            if opts.collapse_single_child_groups {
+                self.collapse_single_child_groups();
+            }
+        }
+        if opts.sort_groups {
+            self.sort_groups();
+        }
+    }
+
+    fn fold_constants(&mut self) {
+        for node in self.ast.iter_mut() {
+            node.fold_constants();
+        }
+        self.ast.retain(|node| !matches!(node, ASTNode::Match(condition) if condition.constant() == Some(true)));
+    }
+
+    fn hoist_matches(&mut self) {
+        for i in 1..self.ast.len() {
+            let mut at = i;
+            while at > 0 && Self::can_swap_match_before(&self.ast[at], &self.ast[at - 1]) {
+                self.ast.swap(at, at - 1);
+                at -= 1;
+            }
+        }
+    }
+
+    /// whether the `Match` in `node` can run before `stage` and still let the same
+    /// documents through, i.e. `stage` leaves every field it filters on as it was.
+    /// `false` for anything that isn't a match, and for stages it can't tell about
+    pub fn can_swap_match_before(node: &ASTNode, stage: &ASTNode) -> bool {
+        if !matches!(node, ASTNode::Match(_)) {
+            return false;
+        }
+        let referenced = node.referenced_fields();
+        let touched = match stage {
+            ASTNode::Project(fields) => return referenced.iter().all(|r| Self::survives_project(r, fields)),
+            ASTNode::Set(assignments) => assignments.iter().map(|(field, _)| field.as_str()).collect(),
+            ASTNode::Unset(fields) => fields.iter().map(|f| f.as_str()).collect(),
+            // reordering never changes which documents pass a filter
+            ASTNode::Sort(_) => Vec::new(),
+            ASTNode::Rename { from, to } => vec![from.as_str(), to.as_str()],
+            // only adds the one field
+            ASTNode::Lookup { as_, .. } => vec![as_.as_str()],
+            _ => return false,
+        };
+        !referenced.iter().any(|r| touched.iter().any(|t| Self::paths_overlap(r, t)))
+    }
+
+    /// whether `field` comes out of `project(fields)` unchanged. one listing keeps only
+    /// what it lists (and `_id`) as soon as it keeps or computes anything, otherwise it
+    /// only drops. a computed or sliced field, or a kept one deeper inside `field`,
+    /// isn't what it was
+    fn survives_project(field: &str, fields: &[(String, ASTNode)]) -> bool {
+        let inclusion = fields
+            .iter()
+            .any(|(f, v)| f != "_id" && !matches!(v, ASTNode::Slice { .. }) && Self::projection_flag(v) != Some(false));
+        let mut kept = !inclusion || field == "_id" || field.starts_with("_id.");
+        for (f, v) in fields.iter().filter(|(f, _)| Self::paths_overlap(field, f)) {
+            match Self::projection_flag(v) {
+                Some(true) if field.starts_with(f.as_str()) => kept = true,
+                _ => return false,
+            }
+        }
+        kept
+    }
+
+    /// the fields a match condition filters on
+    fn match_fields<'a>(node: &'a ASTNode, fields: &mut Vec<&'a str>) {
+        match node {
+            ASTNode::Condition { left, right, .. } if Self::needs_expr(left, right) => {
+                Self::expression_fields(left, fields);
+                match &**right {
+                    ASTNode::Literal(other) if other.starts_with('$') => fields.push(&other[1..]),
+                    ASTNode::Literal(_) => {}
+                    right => Self::expression_fields(right, fields),
+                }
+            }
+            ASTNode::Condition { left, right, .. } => match (&**left, &**right) {
+                // `a > $b` reads both
+                (ASTNode::Literal(field) | ASTNode::Length(field), ASTNode::Literal(other)) if other.starts_with('$') => {
+                    fields.push(field);
+                    fields.push(&other[1..]);
+                }
+                (ASTNode::Literal(field) | ASTNode::Length(field), _)
+                | (_, ASTNode::Literal(field) | ASTNode::Length(field)) => fields.push(field),
+                _ => {}
+            },
+            ASTNode::All { field, .. } | ASTNode::In { field, .. } | ASTNode::HasValue(field) | ASTNode::Type { field, .. } => {
+                fields.push(field)
+            }
+            ASTNode::Comment { condition, .. } => Self::match_fields(condition, fields),
+            ASTNode::Matches { field, pattern } => {
+                fields.push(field);
+                if let ASTNode::Literal(other) = &**pattern {
+                    fields.push(other.trim_start_matches('$'));
+                }
+            }
+            ASTNode::ConditionalOperator { conditions, .. } => {
+                for condition in conditions {
+                    Self::match_fields(condition, fields);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// the fields an arithmetic expression reads, every name in it being one
+    fn expression_fields<'a>(node: &'a ASTNode, fields: &mut Vec<&'a str>) {
+        match node {
+            ASTNode::Literal(field) => fields.push(field.trim_start_matches('$')),
+            ASTNode::Length(field) => fields.push(field),
+            ASTNode::Arithmetic { left, right, .. } => {
+                Self::expression_fields(left, fields);
+                Self::expression_fields(right, fields);
+            }
+            _ => {}
+        }
+    }
+
+    /// `a` and `a.b` overlap, `a` and `ab` don't
+    fn paths_overlap(a: &str, b: &str) -> bool {
+        a == b
+            || a.strip_prefix(b).is_some_and(|rest| rest.starts_with('.'))
+            || b.strip_prefix(a).is_some_and(|rest| rest.starts_with('.'))
+    }
+
+    fn merge_matches(&mut self) {
+        let mut merged: Vec<ASTNode> = Vec::with_capacity(self.ast.len());
+        for node in self.ast.drain(..) {
+            // a comment is about its own match, merging would make it about both. and
+            // mongo takes one `$text` per match, so two searches stay apart
+            if let (Some(ASTNode::Match(prev)), ASTNode::Match(next)) = (merged.last_mut(), &node) {
+                if matches!(**prev, ASTNode::Comment { .. })
+                    || matches!(**next, ASTNode::Comment { .. })
+                    || (prev.text_searches() > 0 && next.text_searches() > 0)
+                {
+                    merged.push(node);
+                    continue;
+                }
+                let mut conditions = match std::mem::replace(&mut **prev, ASTNode::Unexpected) {
+                    ASTNode::ConditionalOperator { op: ConditionalOperator::AND, conditions } => conditions,
+                    other => vec![Box::new(other)],
+                };
+                match *next.clone() {
+                    ASTNode::ConditionalOperator { op: ConditionalOperator::AND, conditions: more } => conditions.extend(more),
+                    other => conditions.push(Box::new(other)),
+                }
+                **prev = ASTNode::ConditionalOperator { op: ConditionalOperator::AND, conditions };
+                continue;
+            }
+            merged.push(node);
+        }
+        self.ast = merged;
+    }
+
+    /// stage kinds in order, plus the distinct fields and operators referenced,
+    /// each in order of first appearance
+    pub fn describe(&self) -> QueryShape {
+        let mut shape = QueryShape::default();
+        for node in self.ast.iter() {
+            shape.visit(node);
+        }
+        shape
+    }
+
+    /// plain-english walkthrough of the pipeline, one line per stage, e.g.
+    /// `Stage 1: keep documents where age > 18`
+    pub fn explain(&self) -> String {
+        let mut lines = Vec::new();
+        for (i, node) in self.ast.iter().enumerate() {
+            if let Some(line) = Self::explain_stage(node) {
+                lines.push(format!("Stage {}: {}", i + 1, line));
+            }
+        }
+        lines.join("\n")
+    }
+
+    /// one line of `explain`, without the `Stage n: ` in front
+    fn explain_stage(node: &ASTNode) -> Option<String> {
+        match node {
+            ASTNode::Match(inner) => Some(format!("keep documents where {}", Self::explain_condition(inner))),
+            ASTNode::Set(assignments) => {
+                let assignments: Vec<String> = assignments
+                    .iter()
+                    .map(|(field, value)| format!("{} to {}", field, Self::explain_condition(value)))
+                    .collect();
+                Some(format!("set {}", assignments.join(", ")))
+            }
+            ASTNode::Project(fields) => {
+                let mut kept = Vec::new();
+                let mut dropped = Vec::new();
+                for (field, value) in fields {
+                    match Self::projection_flag(value) {
+                        Some(true) => kept.push(field.clone()),
+                        Some(false) => dropped.push(field.clone()),
+                        None => kept.push(format!("{} as {}", field, Self::explain_condition(value))),
+                    }
+                }
+                let mut parts = Vec::new();
+                if !kept.is_empty() {
+                    parts.push(format!("keep only {}", kept.join(", ")));
+                }
+                if !dropped.is_empty() {
+                    parts.push(format!("drop {}", dropped.join(", ")));
+                }
+                Some(parts.join(", "))
+            }
+            ASTNode::Rename { from, to } => Some(format!("rename {} to {}", from, to)),
+            ASTNode::Group { keys, accumulators } => {
+                let mut line = match keys.as_slice() {
+                    [] => String::from("group everything together"),
+                    keys => format!("group by {}", keys.join(" and ")),
+                };
+                for (name, value) in accumulators {
+                    let _ = write!(line, ", {} as {}", name, Self::explain_condition(value));
+                }
+                Some(line)
+            }
+            ASTNode::Lookup { from, local, foreign, as_ } => {
+                Some(format!("put the documents in {} whose {} equals {} into {}", from, foreign, local, as_))
+            }
+            ASTNode::Unset(fields) => Some(format!("remove {}", fields.join(", "))),
+            ASTNode::Require(fields) => Some(format!("keep documents that have {}", fields.join(", "))),
+            ASTNode::Sort(keys) => {
+                let keys: Vec<String> = keys
+                    .iter()
+                    .map(|(field, order)| match order {
+                        SortOrder::ASC => format!("{} ascending", field),
+                        SortOrder::DESC => format!("{} descending", field),
+                    })
+                    .collect();
+                Some(format!("sort by {}", keys.join(", then ")))
+            }
+            ASTNode::Limit(n) => Some(format!("keep the first {} documents", n)),
+            ASTNode::Sample(n) => Some(format!("keep {} documents picked at random", n)),
+            ASTNode::Facet(branches) => {
+                let branches: Vec<String> = branches
+                    .iter()
+                    .map(|(name, stages)| {
+                        let stages: Vec<String> = stages.iter().filter_map(Self::explain_stage).collect();
+                        format!("{} ({})", name, stages.join("; "))
+                    })
+                    .collect();
+                Some(format!("split into {}", branches.join(", ")))
+            }
+            _ => None,
+        }
+    }
+
+    fn explain_condition(node: &ASTNode) -> String {
+        match node {
+            ASTNode::Comment { condition, comment } => format!("{} ({})", Self::explain_condition(condition), comment),
+            ASTNode::Condition { op, left, right } => format!(
+                "{} {} {}",
+                Self::explain_condition(left),
+                op.symbol(),
+                Self::explain_condition(right)
+            ),
+            ASTNode::ConditionalOperator { op, conditions } => {
+                let joiner = match op {
+                    ConditionalOperator::AND => " and ",
+                    ConditionalOperator::OR | ConditionalOperator::NOR => " or ",
+                };
+                let conditions: Vec<String> = conditions.iter().map(|c| Self::explain_condition(c)).collect();
+                match op {
+                    ConditionalOperator::NOR => format!("not ({})", conditions.join(joiner)),
+                    _ => format!("({})", conditions.join(joiner)),
+                }
+            }
+            ASTNode::Arithmetic { op, left, right } => format!(
+                "{} {} {}",
+                Self::explain_condition(left),
+                op.symbol(),
+                Self::explain_condition(right)
+            ),
+            ASTNode::Cond { condition, then, otherwise } => format!(
+                "(if {} then {} else {})",
+                Self::explain_condition(condition),
+                Self::explain_condition(then),
+                Self::explain_condition(otherwise)
+            ),
+            ASTNode::Text { search, .. } => format!("text matches {}", Self::quote(search)),
+            ASTNode::All { field, values } => {
+                let values: Vec<String> = values.iter().map(Self::explain_condition).collect();
+                format!("{} has all of [{}]", field, values.join(", "))
+            }
+            ASTNode::In { field, values } => {
+                let values: Vec<String> = values.iter().map(Self::explain_condition).collect();
+                format!("{} is one of [{}]", field, values.join(", "))
+            }
+            ASTNode::HasValue(field) => format!("{} has a value", field),
+            ASTNode::Type { field, types } => format!("{} is of type {}", field, types.join(" or ")),
+            ASTNode::Matches { field, pattern } => format!("{} matches {}", field, Self::explain_condition(pattern)),
+            ASTNode::ElemMatch(condition) => format!("an element where {}", Self::explain_condition(condition)),
+            ASTNode::Literal(l) => l.clone(),
+            ASTNode::Length(field) => format!("the length of {}", field),
+            ASTNode::Accumulator { op, arg } => format!("{} {}", op.describe(), Self::explain_condition(arg)),
+            ASTNode::Str(v) => Self::quote(v),
+            ASTNode::Number(n) => n.to_string(),
+            ASTNode::Bool(b) => b.to_string(),
+            ASTNode::Null => "null".to_string(),
+            ASTNode::Param(i) => format!(":p{}", i),
+            ASTNode::Slice { skip: None, count } if *count < 0 => format!("the last {} elements", -count),
+            ASTNode::Slice { skip: None, count } => format!("the first {} elements", count),
+            ASTNode::Slice { skip: Some(skip), count } => format!("{} elements from index {}", count, skip),
+            ASTNode::Regex { pattern, options } if options.is_empty() => Self::quote(pattern),
+            ASTNode::Regex { pattern, options } => format!("{} (options {})", Self::quote(pattern), options),
+            _ => String::from("?"),
+        }
+    }
+
+    pub fn ast2mql(&self) -> String {
+        self.ast2mql_with(&MqlOptions::default())
+    }
+
+    pub fn ast2mql_with(&self, opts: &MqlOptions) -> String {
+        Self::render_pipeline(&self.ast, opts)
+    }
+
+    /// `ast2mql` with the values the matches compare against pulled out into the
+    /// returned params and replaced by unquoted `:p0`, `:p1`, ... in source order, so
+    /// `match(age > 18 & name == "bob")` gives `{ age: { $gt: :p0 } }, { name: { $eq: :p1 } }`
+    /// and `[18, "bob"]`
+    pub fn ast2mql_parameterized(&self) -> (String, Vec<ASTNode>) {
+        self.ast2mql_parameterized_with(&MqlOptions::default())
+    }
+
+    pub fn ast2mql_parameterized_with(&self, opts: &MqlOptions) -> (String, Vec<ASTNode>) {
+        let mut stages = self.ast.clone();
+        let mut params = Vec::new();
+        for stage in stages.iter_mut() {
+            stage.extract_params(&mut params);
+        }
+        (Self::render_pipeline(&stages, opts), params)
+    }
+
+    /// `ast2mql` for each of `pipelines`, in source order
+    pub fn pipelines2mql(&self) -> Vec<String> {
+        self.pipelines2mql_with(&MqlOptions::default())
+    }
+
+    pub fn pipelines2mql_with(&self, opts: &MqlOptions) -> Vec<String> {
+        self.pipelines.iter().map(|pipeline| Self::render_pipeline(pipeline, opts)).collect()
+    }
+
+    /// the stage renderers `write!` straight into the one buffer rather than building a
+    /// string per stage. writing to a `String` can't fail, hence the `let _ =`s
+    fn render_pipeline(stages: &[ASTNode], opts: &MqlOptions) -> String {
+        let mut s = String::from("db.collection.aggregate([");
+        for node in stages.iter() {
+            Self::render_stage(node, &mut s, opts);
+            s.push(',');
+        }
+        s.push_str("])");
+        if opts.pretty {
+            s = Self::prettify(&s, &opts.indent);
+        }
+        s
+    }
+
+    /// appends the compact mql for one stage node to `out`, without a trailing comma.
+    /// some stages take more than one mql stage (`rename`), those come comma separated
+    fn render_stage(node: &ASTNode, out: &mut String, opts: &MqlOptions) {
+        match node {
+            ASTNode::Match(inner) => Self::render_match(inner, out, opts),
+            ASTNode::Set(assignments) => Self::render_set(assignments, out, opts),
+            ASTNode::Rename { from, to } => Self::render_rename(from, to, out, opts),
+            ASTNode::Unset(fields) => Self::render_unset(fields, out, opts),
+            ASTNode::Require(fields) => Self::render_require(fields, out, opts),
+            ASTNode::Project(fields) => Self::render_project(fields, out, opts),
+            ASTNode::Facet(branches) => Self::render_facet(branches, out, opts),
+            ASTNode::Group { keys, accumulators } => Self::render_group(keys, accumulators, out, opts),
+            ASTNode::Lookup { from, local, foreign, as_ } => {
+                let _ = write!(
+                    out,
+                    "{{ $lookup: {{ from: {}, localField: {}, foreignField: {}, as: {} }} }}",
+                    Self::quote(from),
+                    Self::quote(&Self::field_name(local, opts)),
+                    Self::quote(&Self::field_name(foreign, opts)),
+                    Self::quote(&Self::field_name(as_, opts))
+                );
+            }
+            ASTNode::Sort(keys) => Self::render_sort(keys, out, opts),
+            ASTNode::Limit(n) => {
+                let _ = write!(out, "{{ $limit: {} }}", n);
+            }
+            ASTNode::Sample(n) => {
+                let _ = write!(out, "{{ $sample: {{ size: {} }} }}", n);
+            }
+            _ => panic!("Unexpected node type!"),
+        }
+    }
+
+    fn render_match(condition: &ASTNode, out: &mut String, opts: &MqlOptions) {
+        if let ASTNode::Comment { condition, comment } = condition {
+            // `$comment` sits next to the top-level query fields, `{ a: ..., $comment: "" }`
+            let rendered = Self::render_condition(condition, opts);
+            let body = &rendered[..rendered.len() - 2];
+            let _ = write!(out, "{{ $match: {}, $comment: {} }} }}", body, Self::quote(comment));
+            return;
+        }
+        let _ = write!(out, "{{ $match: {} }}", Self::render_condition(condition, opts));
+    }
+
+    fn render_set(assignments: &[(String, ASTNode)], out: &mut String, opts: &MqlOptions) {
+        out.push_str("{ $set: { ");
+        for (i, (field, value)) in assignments.iter().enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            let _ = write!(out, "{}: {}", Self::render_path(field, opts), Self::render_expression(value, opts));
+        }
+        out.push_str(" } }");
+    }
+
+    fn render_facet(branches: &[(String, Vec<ASTNode>)], out: &mut String, opts: &MqlOptions) {
+        out.push_str("{ $facet: { ");
+        for (i, (name, stages)) in branches.iter().enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            let _ = write!(out, "{}: [", Self::render_path(name, opts));
+            for (j, stage) in stages.iter().enumerate() {
+                if j > 0 {
+                    out.push_str(", ");
+                }
+                Self::render_stage(stage, out, opts);
+            }
+            out.push(']');
+        }
+        out.push_str(" } }");
+    }
+
+    fn render_group(keys: &[String], accumulators: &[(String, ASTNode)], out: &mut String, opts: &MqlOptions) {
+        out.push_str("{ $group: { _id: ");
+        match keys {
+            [] => out.push_str("null"),
+            [key] => {
+                out.push_str(&Self::field_ref(key, opts));
+            }
+            keys => {
+                out.push_str("{ ");
+                for (i, key) in keys.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    let _ = write!(out, "{}: {}", Self::render_path(Self::group_key_name(key), opts), Self::field_ref(key, opts));
+                }
+                out.push_str(" }");
+            }
+        }
+        for (name, value) in accumulators {
+            let _ = write!(out, ", {}: {}", Self::render_path(name, opts), Self::render_expression(value, opts));
+        }
+        out.push_str(" } }");
+    }
+
+    fn render_project(fields: &[(String, ASTNode)], out: &mut String, opts: &MqlOptions) {
+        out.push_str("{ $project: { ");
+        for (i, (field, value)) in fields.iter().enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            let _ = write!(out, "{}: ", Self::render_path(field, opts));
+            match (Self::projection_flag(value), value) {
+                (Some(keep), _) => out.push(if keep { '1' } else { '0' }),
+                // any other number would read as a flag too, this keeps it a value
+                (None, ASTNode::Number(_)) => {
+                    let _ = write!(out, "{{ $literal: {} }}", Self::render_value(value, opts));
+                }
+                (None, ASTNode::Slice { skip: Some(skip), count }) => {
+                    let _ = write!(out, "{{ $slice: [{}, {}] }}", skip, count);
+                }
+                (None, ASTNode::Slice { skip: None, count }) => {
+                    let _ = write!(out, "{{ $slice: {} }}", count);
+                }
+                _ => out.push_str(&Self::render_expression(value, opts)),
+            }
+        }
+        out.push_str(" } }");
+    }
+
+    /// whether a `project` value keeps (`1`, `true`) or drops (`0`, `false`) its field,
+    /// `None` if it's an expression for the field's new value
+    fn projection_flag(value: &ASTNode) -> Option<bool> {
+        match value {
+            ASTNode::Number(n) if *n == 0.0 || *n == 1.0 => Some(*n == 1.0),
+            ASTNode::Bool(keep) => Some(*keep),
+            _ => None,
+        }
+    }
+
+    // there's no rename stage, so it takes two: copy the value over, then drop
+    // the old field. `{ $addFields: { b: "$a" } },{ $project: { a: 0 } }` by default,
+    // `{ $set: { b: "$a" } },{ $unset: "a" }` with `rename_via_unset`
+    fn render_rename(from: &str, to: &str, out: &mut String, opts: &MqlOptions) {
+        if opts.rename_via_unset {
+            let _ = write!(
+                out,
+                "{{ $set: {{ {}: {} }} }},{{ $unset: {} }}",
+                Self::render_path(to, opts),
+                Self::field_ref(from, opts),
+                Self::quote(&Self::field_name(from, opts))
+            );
+        } else {
+            let _ = write!(
+                out,
+                "{{ $addFields: {{ {}: {} }} }},{{ $project: {{ {}: 0 }} }}",
+                Self::render_path(to, opts),
+                Self::field_ref(from, opts),
+                Self::render_path(from, opts)
+            );
+        }
+    }
+
+    fn render_unset(fields: &[String], out: &mut String, opts: &MqlOptions) {
+        if let [field] = fields {
+            let _ = write!(out, "{{ $unset: {} }}", Self::quote(&Self::field_name(field, opts)));
+        } else {
+            out.push_str("{ $unset: [");
+            for (i, field) in fields.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                out.push_str(&Self::quote(&Self::field_name(field, opts)));
+            }
+            out.push_str("] }");
+        }
+    }
+
+    fn render_require(fields: &[String], out: &mut String, opts: &MqlOptions) {
+        out.push_str("{ $match: { $jsonSchema: { required: [");
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            out.push_str(&Self::quote(&Self::field_name(field, opts)));
+        }
+        out.push_str("] } } }");
+    }
+
+    fn render_sort(keys: &[(String, SortOrder)], out: &mut String, opts: &MqlOptions) {
+        out.push_str("{ $sort: { ");
+        for (i, (field, order)) in keys.iter().enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            let _ = write!(out, "{}: {}", Self::render_path(field, opts), order.mql_value());
+        }
+        out.push_str(" } }");
+    }
+
+    /// the stages as a strict json array (`[{"$match": ...}]`), for `JSON.parse` or a
+    /// driver rather than the shell: every key quoted and no trailing commas
+    pub fn to_pipeline_json_string(&self) -> String {
+        let shell = self.ast2mql();
+        let stages = shell
+            .strip_prefix("db.collection.aggregate(")
+            .and_then(|rest| rest.strip_suffix(')'))
+            .unwrap_or(&shell);
+        Self::jsonify(stages)
+    }
+
+    /// quotes the bare keys of compact shell output and drops its trailing commas.
+    /// string values pass through untouched
+    fn jsonify(shell: &str) -> String {
+        let mut out = String::new();
+        let mut in_string = false;
+        let mut escaped = false;
+        let mut chars = shell.chars().peekable();
+        while let Some(c) = chars.next() {
+            if in_string {
+                out.push(c);
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == '"' {
+                    in_string = false;
+                }
+                continue;
+            }
+            match c {
+                '"' => {
+                    in_string = true;
+                    out.push(c);
+                }
+                ',' => {
+                    let rest = chars.clone().find(|c| *c != ' ');
+                    if !matches!(rest, Some('}' | ']')) {
+                        out.push(c);
+                    }
+                }
+                c if c.is_alphanumeric() || matches!(c, '_' | '$' | '.' | '-') => {
+                    let mut word = String::from(c);
+                    while let Some(&c1) = chars.peek() {
+                        if c1.is_alphanumeric() || matches!(c1, '_' | '$' | '.' | '-') {
+                            word.push(c1);
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                    // a word followed by `:` is a key, anything else (numbers, `true`) is a value
+                    if chars.clone().find(|c| *c != ' ') == Some(':') {
+                        out.push_str(&Self::quote(&word));
+                    } else {
+                        out.push_str(&word);
+                    }
+                }
+                c => out.push(c),
+            }
+        }
+        out
+    }
+
+    /// re-lays compact output one key/element per line. only structure outside of
+    /// string values is touched
+    fn prettify(compact: &str, indent: &str) -> String {
+        let mut out = String::new();
+        let mut depth = 0;
+        let mut in_string = false;
+        let mut escaped = false;
+        let mut chars = compact.chars().peekable();
+        let newline = |out: &mut String, depth: usize| {
+            while out.ends_with(' ') {
+                out.pop();
+            }
+            out.push('\n');
+            out.push_str(&indent.repeat(depth));
+        };
+        while let Some(c) = chars.next() {
+            if in_string {
+                out.push(c);
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == '"' {
+                    in_string = false;
+                }
+                continue;
+            }
+            match c {
+                '"' => {
+                    in_string = true;
+                    out.push(c);
+                }
+                '{' | '[' => {
+                    let close = if c == '{' { '}' } else { ']' };
+                    while chars.peek() == Some(&' ') {
+                        chars.next();
+                    }
+                    out.push(c);
+                    if chars.peek() == Some(&close) {
+                        chars.next();
+                        out.push(close);
+                    } else {
+                        depth += 1;
+                        newline(&mut out, depth);
+                    }
+                }
+                '}' | ']' => {
+                    depth = depth.saturating_sub(1);
+                    // a closer after a trailing `,` already has its own line, just re-indent it
+                    if out.trim_end_matches([' ', '\t']).ends_with('\n') {
+                        while out.ends_with([' ', '\t']) {
+                            out.pop();
+                        }
+                        out.push_str(&indent.repeat(depth));
+                    } else {
+                        newline(&mut out, depth);
+                    }
+                    out.push(c);
+                }
+                ',' => {
+                    out.push(c);
+                    while chars.peek() == Some(&' ') {
+                        chars.next();
+                    }
+                    newline(&mut out, depth);
+                }
+                _ => out.push(c),
+            }
+        }
+        out
+    }
+
+    fn render_condition(node: &ASTNode, opts: &MqlOptions) -> String {
+        match node {
+            // arithmetic (`price * quantity > 100`) can only be evaluated in `$expr`
+            ASTNode::Condition { op, left, right } if Self::needs_expr(left, right) => format!(
+                "{{ $expr: {{ {}: [{}, {}] }} }}",
+                op.mql_op(),
+                Self::render_expression(left, opts),
+                Self::render_expression_value(right, opts)
+            ),
+            ASTNode::Condition { op, left, right } => {
+                // the field always goes on the left in mql, so `18 < age` is written as `age > 18`
+                let (field, op, value) = match (&**left, &**right) {
+                    (ASTNode::Number(_) | ASTNode::Str(_) | ASTNode::Bool(_) | ASTNode::Param(_), ASTNode::Literal(_) | ASTNode::Length(_)) => (right, op.flipped(), left),
+                    _ => (left, *op, right),
+                };
+                if let ASTNode::Length(array) = &**field {
+                    // `$size` only matches an exact length, anything else needs `$expr`
+                    if op == Comparator::EQ && Self::is_array_size(value) {
+                        return format!("{{ {}: {{ $size: {} }} }}", Self::render_key(&ASTNode::Literal(array.clone()), opts), Self::render_value(value, opts));
+                    }
+                    // `$size` inside `$expr` errors on a missing field, `$not` just matches it
+                    if op == Comparator::NEQ && Self::is_array_size(value) {
+                        return format!("{{ {}: {{ $not: {{ $size: {} }} }} }}", Self::render_key(&ASTNode::Literal(array.clone()), opts), Self::render_value(value, opts));
+                    }
+                    return format!(
+                        "{{ $expr: {{ {}: [{{ $size: {} }}, {}] }} }}",
+                        op.mql_op(),
+                        Self::field_ref(array, opts),
+                        Self::render_expression_value(value, opts)
+                    );
+                }
+                // comparing two fields (`a > $b`) can't be done in query form
+                if let (ASTNode::Literal(_), true) = (&**field, Self::is_field_ref(value)) {
+                    return format!(
+                        "{{ $expr: {{ {}: [{}, {}] }} }}",
+                        op.mql_op(),
+                        Self::render_expression(field, opts),
+                        Self::render_value(value, opts)
+                    );
+                }
+                if op == Comparator::EQ && opts.eq_shorthand {
+                    return format!("{{ {}: {} }}", Self::render_key(field, opts), Self::render_value(value, opts));
+                }
+                format!("{{ {}: {{ {}: {} }} }}", Self::render_key(field, opts), op.mql_op(), Self::render_value(value, opts))
+            }
+            ASTNode::ConditionalOperator { op, conditions } => {
+                let conditions: Vec<String> = conditions.iter().map(|c| Self::render_condition(c, opts)).collect();
+                format!("{{ {}: [{}] }}", op.mql_op(), conditions.join(", "))
+            }
+            ASTNode::Text { search, language, case_sensitive } => {
+                let mut text = format!("$search: {}", Self::quote(search));
+                if let Some(language) = language {
+                    text.push_str(&format!(", $language: {}", Self::quote(language)));
+                }
+                if let Some(case_sensitive) = case_sensitive {
+                    text.push_str(&format!(", $caseSensitive: {}", case_sensitive));
+                }
+                format!("{{ $text: {{ {} }} }}", text)
+            }
+            ASTNode::All { field, values } => {
+                let values: Vec<String> = values
+                    .iter()
+                    .map(|value| match value {
+                        ASTNode::ElemMatch(condition) => format!("{{ $elemMatch: {} }}", Self::render_condition(condition, opts)),
+                        value => Self::render_value(value, opts),
+                    })
+                    .collect();
+                format!("{{ {}: {{ $all: [{}] }} }}", Self::render_path(field, opts), values.join(", "))
+            }
+            ASTNode::In { field, values } => {
+                let values: Vec<String> = values.iter().map(|v| Self::render_value(v, opts)).collect();
+                format!("{{ {}: {{ $in: [{}] }} }}", Self::render_path(field, opts), values.join(", "))
+            }
+            // `$exists` alone lets an explicit `null` through
+            ASTNode::HasValue(field) => format!("{{ {}: {{ $exists: true, $ne: null }} }}", Self::render_path(field, opts)),
+            // `$regex` only takes a constant, a pattern from another field needs `$regexMatch`
+            ASTNode::Matches { field, pattern } if Self::is_field_ref(pattern) => format!(
+                "{{ $expr: {{ $regexMatch: {{ input: {}, regex: {} }} }} }}",
+                Self::field_ref(field, opts),
+                Self::render_value(pattern, opts)
+            ),
+            ASTNode::Matches { field, pattern } => match &**pattern {
+                ASTNode::Regex { pattern, options } if options.is_empty() => {
+                    format!("{{ {}: {{ $regex: {} }} }}", Self::render_path(field, opts), Self::quote(pattern))
+                }
+                ASTNode::Regex { pattern, options } => format!(
+                    "{{ {}: {{ $regex: {}, $options: {} }} }}",
+                    Self::render_path(field, opts),
+                    Self::quote(pattern),
+                    Self::quote(options)
+                ),
+                pattern => format!("{{ {}: {{ $regex: {} }} }}", Self::render_path(field, opts), Self::render_value(pattern, opts)),
+            },
+            ASTNode::Type { field, types } => {
+                let types: Vec<String> = types.iter().map(|ty| Self::quote(ty)).collect();
+                match types.as_slice() {
+                    [ty] => format!("{{ {}: {{ $type: {} }} }}", Self::render_path(field, opts), ty),
+                    types => format!("{{ {}: {{ $type: [{}] }} }}", Self::render_path(field, opts), types.join(", ")),
+                }
+            }
+            _ => panic!("Unexpected node type!"),
+        }
+    }
+
+    /// `field` the way it goes into the mql, lowercased with `normalize_field_case`
+    fn field_name<'a>(field: &'a str, opts: &MqlOptions) -> std::borrow::Cow<'a, str> {
+        if opts.normalize_field_case {
+            std::borrow::Cow::Owned(field.to_lowercase())
+        } else {
+            std::borrow::Cow::Borrowed(field)
+        }
+    }
+
+    /// a `$`-prefixed literal on the value side, i.e. another field
+    fn is_field_ref(node: &ASTNode) -> bool {
+        matches!(node, ASTNode::Literal(l) if l.starts_with('$'))
+    }
+
+    /// whether `value` can go in a query-form `$size`, which only takes a whole,
+    /// non-negative count. anything else is compared in `$expr`, where it just never matches
+    fn is_array_size(value: &ASTNode) -> bool {
+        matches!(value, ASTNode::Number(n) if n.fract() == 0.0 && *n >= 0.0) || matches!(value, ASTNode::Param(_))
+    }
+
+    fn render_key(node: &ASTNode, opts: &MqlOptions) -> String {
+        match node {
+            ASTNode::Literal(l) => Self::render_path(l, opts),
+            ASTNode::Number(n) => n.to_string(),
+            ASTNode::Str(v) => Self::quote(v),
+            ASTNode::Bool(b) => b.to_string(),
+            _ => panic!("Unexpected node type!"),
+        }
+    }
+
+    /// a field name as an object key, quoted unless it's a plain identifier, so dotted
+    /// paths (`"items.0.price"`) and backtick-quoted names with spaces stay valid
+    fn render_path(field: &str, opts: &MqlOptions) -> String {
+        let field = Self::field_name(field, opts);
+        let field = field.as_ref();
+        let plain = field.starts_with(|c: char| c.is_alphabetic() || c == '_')
+            && field.chars().all(|c| c.is_alphanumeric() || c == '_');
+        if plain {
+            field.to_string()
+        } else {
+            Self::quote(field)
+        }
+    }
+
+    fn render_value(node: &ASTNode, opts: &MqlOptions) -> String {
+        match node {
+            ASTNode::Literal(l) if l.starts_with('$') => Self::quote(&Self::field_name(l, opts)),
+            ASTNode::Literal(l) => Self::quote(l),
+            ASTNode::Str(v) => Self::quote(v),
+            ASTNode::Number(n) if opts.integral_floats && n.fract() == 0.0 => format!("{:.1}", n),
+            ASTNode::Number(n) => n.to_string(),
+            ASTNode::Bool(b) => b.to_string(),
+            ASTNode::Null => "null".to_string(),
+            ASTNode::Param(i) => format!(":p{}", i),
+            _ => panic!("Unexpected node type!"),
+        }
+    }
+
+    /// `"$field"`, a reference to `field`'s value in an expression
+    fn field_ref(field: &str, opts: &MqlOptions) -> String {
+        Self::quote(&format!("${}", Self::field_name(field, opts)))
+    }
+
+    /// `v` as a double-quoted string literal with `"`, `\` and control characters escaped
+    fn quote(v: &str) -> String {
+        let mut quoted = String::from("\"");
+        for c in v.chars() {
+            match c {
+                '"' => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                '\n' => quoted.push_str("\\n"),
+                '\t' => quoted.push_str("\\t"),
+                '\r' => quoted.push_str("\\r"),
+                c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+                c => quoted.push(c),
+            }
+        }
+        quoted.push('"');
+        quoted
+    }
+
+    /// like `render_value`, except bare literals are field references (`"$price"`)
+    fn render_expression(node: &ASTNode, opts: &MqlOptions) -> String {
+        match node {
+            ASTNode::Literal(l) if l.starts_with('$') => Self::quote(&Self::field_name(l, opts)),
+            ASTNode::Literal(l) => Self::field_ref(l, opts),
+            ASTNode::Length(array) => format!("{{ $size: {} }}", Self::field_ref(array, opts)),
+            ASTNode::Arithmetic { op, left, right } => format!(
+                "{{ {}: [{}, {}] }}",
+                op.mql_op(),
+                Self::render_expression(left, opts),
+                Self::render_expression(right, opts)
+            ),
+            ASTNode::Cond { condition, then, otherwise } => format!(
+                "{{ $cond: [{}, {}, {}] }}",
+                Self::render_expression_condition(condition, opts),
+                Self::render_expression(then, opts),
+                Self::render_expression(otherwise, opts)
+            ),
+            ASTNode::Accumulator { op, arg } => format!("{{ {}: {} }}", op.mql_op(), Self::render_expression(arg, opts)),
+            // a quoted `"$x"` is text, not the field `x` it would read as here
+            ASTNode::Str(s) if s.starts_with('$') => format!("{{ $literal: {} }}", Self::quote(s)),
+            _ => Self::render_value(node, opts),
+        }
+    }
+
+    /// the value side of a comparison in expression form. arithmetic and lengths get
+    /// computed, a bare name stays a string like it is in query form
+    fn render_expression_value(node: &ASTNode, opts: &MqlOptions) -> String {
+        match node {
+            ASTNode::Arithmetic { .. } | ASTNode::Length(_) | ASTNode::Str(_) => Self::render_expression(node, opts),
+            _ => Self::render_value(node, opts),
+        }
+    }
+
+    fn is_computed(node: &ASTNode) -> bool {
+        matches!(node, ASTNode::Arithmetic { .. })
+    }
+
+    /// whether a comparison only works in `$expr`: arithmetic on either side, a length
+    /// on the value side of anything but a constant (`a == b.length`, `a.length < b.length`),
+    /// or two constants (what `fold_constants` leaves of a group it decided), none of
+    /// which query form has a way to say. a length against a constant is `$size` in
+    /// query form
+    fn needs_expr(left: &ASTNode, right: &ASTNode) -> bool {
+        const CONSTANT: fn(&ASTNode) -> bool = |node| matches!(node, ASTNode::Number(_) | ASTNode::Str(_) | ASTNode::Bool(_));
+        Self::is_computed(left)
+            || Self::is_computed(right)
+            || (matches!(right, ASTNode::Length(_)) && !CONSTANT(left) && !matches!(left, ASTNode::Param(_)))
+            || (CONSTANT(left) && CONSTANT(right))
+    }
+
+    /// a condition in aggregation-expression form, `{ $gt: ["$age", 18] }` rather
+    /// than the query form `{ age: { $gt: 18 } }`
+    fn render_expression_condition(node: &ASTNode, opts: &MqlOptions) -> String {
+        match node {
+            ASTNode::Condition { op, left, right } => {
+                let (field, op, value) = match (&**left, &**right) {
+                    (ASTNode::Number(_) | ASTNode::Str(_) | ASTNode::Bool(_), ASTNode::Literal(_)) => (right, op.flipped(), left),
+                    _ => (left, *op, right),
+                };
+                format!("{{ {}: [{}, {}] }}", op.mql_op(), Self::render_expression(field, opts), Self::render_expression_value(value, opts))
+            }
+            ASTNode::ConditionalOperator { op, conditions } => {
+                let conditions: Vec<String> = conditions.iter().map(|c| Self::render_expression_condition(c, opts)).collect();
+                format!("{{ {}: [{}] }}", op.mql_op(), conditions.join(", "))
+            }
+            _ => Self::render_expression(node, opts),
+        }
+    }
+
+    /// things in `ast` that are legal but likely mistakes. never fails the build, it's
+    /// up to the caller whether to show them
+    pub fn warnings(&self) -> Vec<Warning> {
+        let mut warnings = Vec::new();
+        let mut compared = Vec::new();
+        for (stage, node) in self.ast.iter().enumerate() {
+            if let ASTNode::Match(condition) = node {
+                let condition = match &**condition {
+                    ASTNode::Comment { condition, .. } => condition,
+                    condition => condition,
+                };
+                Self::find_always_false(condition, stage, &mut warnings);
+                Self::find_mixed_types(condition, stage, &mut compared, &mut warnings);
+            } else {
+                Self::forget_rewritten(node, &mut compared);
+            }
+        }
+        warnings
+    }
+
+    /// drops the types `compared` holds for fields `stage` may hand on changed, so a
+    /// later match comparing them to something else isn't a mix
+    fn forget_rewritten(stage: &ASTNode, compared: &mut Vec<(&str, &'static str)>) {
+        let written: Vec<&str> = match stage {
+            ASTNode::Project(fields) => return compared.retain(|(field, _)| Self::survives_project(field, fields)),
+            ASTNode::Set(assignments) => assignments.iter().map(|(field, _)| field.as_str()).collect(),
+            ASTNode::Unset(fields) => fields.iter().map(|f| f.as_str()).collect(),
+            ASTNode::Rename { from, to } => vec![from.as_str(), to.as_str()],
+            ASTNode::Lookup { as_, .. } => vec![as_.as_str()],
+            ASTNode::Sort(_) | ASTNode::Limit(_) | ASTNode::Sample(_) | ASTNode::Require(_) => return,
+            // new documents altogether
+            _ => return compared.clear(),
+        };
+        compared.retain(|(field, _)| !written.iter().any(|w| Self::paths_overlap(field, w)));
+    }
+
+    /// `compared` holds the first type each field was compared against, a field gets
+    /// one warning however many other types it's compared to after
+    fn find_mixed_types<'a>(node: &'a ASTNode, stage: usize, compared: &mut Vec<(&'a str, &'static str)>, warnings: &mut Vec<Warning>) {
+        match node {
+            ASTNode::Condition { left, right, .. } => {
+                let (field, value) = match (&**left, &**right) {
+                    (ASTNode::Literal(field), value) | (value, ASTNode::Literal(field)) if !field.starts_with('$') => (field.as_str(), value),
+                    _ => return,
+                };
+                let ty = match value {
+                    ASTNode::Number(_) => "number",
+                    // a bare word on the value side is a string too
+                    ASTNode::Str(_) => "string",
+                    ASTNode::Literal(v) if !v.starts_with('$') => "string",
+                    ASTNode::Bool(_) => "bool",
+                    _ => return,
+                };
+                match compared.iter().find(|(f, _)| *f == field) {
+                    None => compared.push((field, ty)),
+                    Some((_, first)) if *first != ty => {
+                        let warned = warnings.iter().any(|w| matches!(&w.ty, WarningT::MixedTypes { field: f, .. } if f == field));
+                        if !warned {
+                            warnings.push(Warning { ty: WarningT::MixedTypes { field: field.to_string(), first, second: ty }, stage });
+                        }
+                    }
+                    Some(_) => {}
+                }
+            }
+            ASTNode::ConditionalOperator { conditions, .. } => {
+                for condition in conditions {
+                    Self::find_mixed_types(condition, stage, compared, warnings);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn find_always_false(node: &ASTNode, stage: usize, warnings: &mut Vec<Warning>) {
+        let always_false = match node {
+            ASTNode::Condition { op, left, right } => Self::compare_constants(*op, left, right) == Some(false),
+            ASTNode::ConditionalOperator { op: ConditionalOperator::AND, conditions } => {
+                // `a == 1` and `a == 2` side by side can't both hold
+                let mut equalities: Vec<(&str, &ASTNode)> = Vec::new();
+                let mut clash = false;
+                for condition in conditions {
+                    if let ASTNode::Condition { op: Comparator::EQ, left, right } = &**condition {
+                        let (field, value) = match (&**left, &**right) {
+                            (ASTNode::Literal(field), value @ (ASTNode::Number(_) | ASTNode::Str(_) | ASTNode::Bool(_)))
+                            | (value @ (ASTNode::Number(_) | ASTNode::Str(_) | ASTNode::Bool(_)), ASTNode::Literal(field)) => (field.as_str(), value),
+                            _ => continue,
+                        };
+                        if equalities.iter().any(|(f, v)| *f == field && *v != value) {
+                            clash = true;
+                        }
+                        equalities.push((field, value));
+                    }
+                }
+                clash
+            }
+            _ => false,
+        };
+        if always_false {
+            warnings.push(Warning { ty: WarningT::AlwaysFalse(Predicate(node).to_string()), stage });
+            return;
+        }
+        match node {
+            ASTNode::ConditionalOperator { conditions, .. } => {
+                for condition in conditions {
+                    Self::find_always_false(condition, stage, warnings);
+                }
+            }
+            ASTNode::All { values, .. } => {
+                for value in values {
+                    if let ASTNode::ElemMatch(condition) = value {
+                        Self::find_always_false(condition, stage, warnings);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// the outcome of comparing two constants, `None` when either side isn't one or
+    /// they're of types that don't order against each other
+    fn compare_constants(op: Comparator, left: &ASTNode, right: &ASTNode) -> Option<bool> {
+        let ordering = match (left, right) {
+            (ASTNode::Number(a), ASTNode::Number(b)) => a.partial_cmp(b)?,
+            (ASTNode::Str(a), ASTNode::Str(b)) => a.cmp(b),
+            (ASTNode::Bool(a), ASTNode::Bool(b)) => a.cmp(b),
+            (ASTNode::Number(_) | ASTNode::Str(_) | ASTNode::Bool(_), ASTNode::Number(_) | ASTNode::Str(_) | ASTNode::Bool(_)) => {
+                // different types are never equal, how they order is mongo's business
+                return match op {
+                    Comparator::EQ => Some(false),
+                    Comparator::NEQ => Some(true),
+                    Comparator::GTE | Comparator::GT | Comparator::LT | Comparator::LTE => None,
+                };
+            }
+            _ => return None,
+        };
+        Some(match op {
+            Comparator::GTE => ordering.is_ge(),
+            Comparator::GT => ordering.is_gt(),
+            Comparator::EQ => ordering.is_eq(),
+            Comparator::NEQ => ordering.is_ne(),
+            Comparator::LT => ordering.is_lt(),
+            Comparator::LTE => ordering.is_le(),
+        })
+    }
+
+    /// whether `ast2mql` can render `ast` into valid mql, see `validate_mql` for why not
+    pub fn is_valid_mql(&self) -> bool {
+        self.validate_mql().is_ok()
+    }
+
+    /// checks `ast` has the shape the renderer expects: stages at the top, conditions
+    /// in a match, a field on one side of every comparison and a value on the other.
+    /// the parser never produces anything else, an ast built by hand can
+    pub fn validate_mql(&self) -> Result<(), MqlError> {
+        self.ast.iter().try_for_each(Self::validate_stage)
+    }
+
+    fn validate_stage(node: &ASTNode) -> Result<(), MqlError> {
+        match node {
+            ASTNode::Match(inner) if inner.text_searches() > 1 => Err(MqlError::DuplicateText),
+            ASTNode::Match(inner) => match &**inner {
+                ASTNode::Comment { condition, .. } => Self::validate_condition(condition),
+                inner => Self::validate_condition(inner),
+            },
+            ASTNode::Set(assignments) => {
+                if assignments.is_empty() {
+                    return Err(MqlError::EmptyStage(node.kind()));
+                }
+                assignments.iter().try_for_each(|(_, value)| Self::validate_expression(value))
+            }
+            ASTNode::Project(fields) => {
+                if fields.is_empty() {
+                    return Err(MqlError::EmptyStage(node.kind()));
+                }
+                // `_id` is the one field that can be dropped while others are kept. a slice
+                // goes with either
+                let (dropped, kept): (Vec<_>, Vec<_>) = fields
+                    .iter()
+                    .filter(|(field, value)| field != "_id" && !matches!(value, ASTNode::Slice { .. }))
+                    .partition(|(_, value)| Self::projection_flag(value) == Some(false));
+                if !dropped.is_empty() && !kept.is_empty() {
+                    return Err(MqlError::MixedProjection);
+                }
+                fields.iter().try_for_each(|(_, value)| match value {
+                    ASTNode::Slice { skip: Some(_), count } if *count < 1 => Err(MqlError::InvalidSlice),
+                    ASTNode::Slice { .. } => Ok(()),
+                    value => Self::validate_expression(value),
+                })
+            }
+            ASTNode::Facet(branches) => {
+                if branches.is_empty() || branches.iter().any(|(_, stages)| stages.is_empty()) {
+                    return Err(MqlError::EmptyStage(node.kind()));
+                }
+                branches.iter().flat_map(|(_, stages)| stages).try_for_each(|stage| match stage {
+                    ASTNode::Facet(_) => Err(MqlError::NestedFacet),
+                    stage => Self::validate_stage(stage),
+                })
+            }
+            ASTNode::Group { keys, accumulators } => {
+                for (i, key) in keys.iter().enumerate() {
+                    let name = Self::group_key_name(key);
+                    if keys[..i].iter().any(|other| Self::group_key_name(other) == name) {
+                        return Err(MqlError::DuplicateGroupKey(name.to_string()));
+                    }
+                }
+                for (i, (name, _)) in accumulators.iter().enumerate() {
+                    if name == "_id" || accumulators[..i].iter().any(|(other, _)| other == name) {
+                        return Err(MqlError::DuplicateGroupKey(name.clone()));
+                    }
+                }
+                accumulators.iter().try_for_each(|(_, value)| match value {
+                    ASTNode::Accumulator { arg, .. } => Self::validate_expression(arg),
+                    value => Err(MqlError::NotAnAccumulator(value.kind())),
+                })
+            }
+            ASTNode::Unset(fields) | ASTNode::Require(fields) if fields.is_empty() => Err(MqlError::EmptyStage(node.kind())),
+            ASTNode::Sort(keys) if keys.is_empty() => Err(MqlError::EmptyStage(node.kind())),
+            ASTNode::Rename { .. }
+            | ASTNode::Unset(_)
+            | ASTNode::Require(_)
+            | ASTNode::Sort(_)
+            | ASTNode::Limit(_)
+            | ASTNode::Sample(_)
+            | ASTNode::Lookup { .. } => Ok(()),
+            _ => Err(MqlError::NotAStage(node.kind())),
+        }
+    }
+
+    /// mirrors `render_condition`
+    fn validate_condition(node: &ASTNode) -> Result<(), MqlError> {
+        match node {
+            ASTNode::Condition { left, right, .. } if Self::needs_expr(left, right) => {
+                Self::validate_expression(left)?;
+                Self::validate_expression_value(right)
+            }
+            ASTNode::Condition { left, right, .. } => {
+                let (field, value) = match (&**left, &**right) {
+                    (ASTNode::Number(_) | ASTNode::Str(_) | ASTNode::Bool(_) | ASTNode::Param(_), ASTNode::Literal(_) | ASTNode::Length(_)) => (right, left),
+                    _ => (left, right),
+                };
+                if !matches!(**field, ASTNode::Literal(_) | ASTNode::Length(_)) {
+                    return Err(MqlError::NotAField(field.kind()));
+                }
+                Self::validate_value(value)
+            }
+            ASTNode::ConditionalOperator { op, conditions } => {
+                if conditions.is_empty() {
+                    return Err(MqlError::EmptyGroup(*op));
+                }
+                conditions.iter().try_for_each(|c| Self::validate_condition(c))
+            }
+            ASTNode::Text { .. } | ASTNode::HasValue(_) => Ok(()),
+            ASTNode::Type { types, .. } if types.is_empty() => Err(MqlError::EmptyTypes),
+            ASTNode::Type { .. } => Ok(()),
+            ASTNode::Matches { pattern, .. } => match &**pattern {
+                ASTNode::Str(_) => Ok(()),
+                ASTNode::Regex { options, .. } if !Self::valid_regex_options(options) => Err(MqlError::InvalidRegexOptions(options.clone())),
+                ASTNode::Regex { .. } => Ok(()),
+                pattern if Self::is_field_ref(pattern) => Ok(()),
+                pattern => Err(MqlError::NotAValue(pattern.kind())),
+            },
+            ASTNode::All { values, .. } => values.iter().try_for_each(|value| match value {
+                ASTNode::ElemMatch(condition) => Self::validate_condition(condition),
+                value => Self::validate_value(value),
+            }),
+            ASTNode::In { values, .. } => values.iter().try_for_each(Self::validate_value),
+            _ => Err(MqlError::NotACondition(node.kind())),
+        }
+    }
+
+    /// mirrors `render_expression`
+    fn validate_expression(node: &ASTNode) -> Result<(), MqlError> {
+        match node {
+            ASTNode::Length(_) => Ok(()),
+            ASTNode::Arithmetic { left, right, .. } => {
+                Self::validate_expression(left)?;
+                Self::validate_expression(right)
+            }
+            ASTNode::Cond { condition, then, otherwise } => {
+                Self::validate_expression_condition(condition)?;
+                Self::validate_expression(then)?;
+                Self::validate_expression(otherwise)
+            }
+            _ => Self::validate_value(node),
+        }
+    }
+
+    /// mirrors `render_expression_condition`
+    fn validate_expression_condition(node: &ASTNode) -> Result<(), MqlError> {
+        match node {
+            ASTNode::Condition { left, right, .. } => {
+                let (field, value) = match (&**left, &**right) {
+                    (ASTNode::Number(_) | ASTNode::Str(_) | ASTNode::Bool(_), ASTNode::Literal(_)) => (right, left),
+                    _ => (left, right),
+                };
+                Self::validate_expression(field)?;
+                Self::validate_expression_value(value)
+            }
+            ASTNode::ConditionalOperator { op, conditions } => {
+                if conditions.is_empty() {
+                    return Err(MqlError::EmptyGroup(*op));
+                }
+                conditions.iter().try_for_each(|c| Self::validate_expression_condition(c))
+            }
+            _ => Self::validate_expression(node),
+        }
+    }
+
+    /// mirrors `render_expression_value`
+    fn validate_expression_value(node: &ASTNode) -> Result<(), MqlError> {
+        match node {
+            ASTNode::Arithmetic { .. } | ASTNode::Length(_) => Self::validate_expression(node),
+            _ => Self::validate_value(node),
+        }
+    }
+
+    fn validate_value(node: &ASTNode) -> Result<(), MqlError> {
+        match node {
+            ASTNode::Literal(_) | ASTNode::Str(_) | ASTNode::Number(_) | ASTNode::Bool(_) | ASTNode::Null | ASTNode::Param(_) => Ok(()),
+            _ => Err(MqlError::NotAValue(node.kind())),
+        }
+    }
+
+    /// same as `ast2mql` but with a `.collation(...)` appended, so string comparisons
+    /// can be made case-insensitive (strength 1 or 2). strength must be in 1..=5
+    pub fn ast2mql_with_collation(&self, locale: &str, strength: u8) -> Result<String, MqlError> {
+        if !(1..=5).contains(&strength) {
+            return Err(MqlError::InvalidCollationStrength(strength));
+        }
+        let mut s = self.ast2mql();
+        let _ = write!(s, ".collation({{ locale: {}, strength: {} }})", Self::quote(locale), strength);
+        Ok(s)
+    }
+}
+
+/// what `IncrementalParser::feed` makes of the source so far
+#[derive(Debug)]
+pub enum ParseStatus {
+    /// it parses as it is
+    Complete,
+    /// it stops partway through something more input could still finish
+    Incomplete,
+    /// no amount of further input makes it parse
+    Invalid(ParseError),
+}
+
+/// for editors and repls, where the source arrives a piece at a time and running out
+/// partway through a query isn't an error yet. every `feed` parses the whole source
+/// again, queries are short enough for that not to matter
+#[derive(Debug, Default)]
+pub struct IncrementalParser {
+    source: String,
+    opts: ParseOptions,
+}
+
+impl IncrementalParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_options(opts: ParseOptions) -> Self {
+        Self { source: String::new(), opts }
+    }
+
+    /// appends `chunk` to the source and reports on all of it
+    pub fn feed(&mut self, chunk: &str) -> ParseStatus {
+        self.source.push_str(chunk);
+        match self.parse().build() {
+            Ok(()) => ParseStatus::Complete,
+            Err(e) if self.ran_out(&e) => ParseStatus::Incomplete,
+            Err(e) => ParseStatus::Invalid(e),
+        }
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// the input is over, parse it for real
+    pub fn finish(self) -> Result<MonGod, ParseError> {
+        let mut m = self.parse();
+        m.build()?;
+        Ok(m)
+    }
+
+    fn parse(&self) -> MonGod {
+        MonGod::with_options(self.source.clone(), self.opts.clone())
+    }
+
+    /// whether `e` is only down to the source stopping early
+    fn ran_out(&self, e: &ParseError) -> bool {
+        let source = &self.source;
+        let tokens = match MonGod::lex(source, false) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                return match e.ty {
+                    ParseErrorT::UnterminatedString | ParseErrorT::UnterminatedIdentifier => true,
+                    // the second half of a `==`/`!=` can still come
+                    ParseErrorT::UnexpectedCharacter => e.cursor + 1 == source.len() && source.ends_with(['=', '!']),
+                    // `items.` on its way to `items.price`
+                    ParseErrorT::InvalidFieldName => e.cursor + 1 == source.len() && source.ends_with('.'),
+                    _ => false,
+                };
+            }
+        };
+        // a word right at the end may not be typed out yet, `sor` on its way to `sort`,
+        // or the `.` in front of the next stage
+        if let Some(last) = tokens.last() {
+            let word = &source[last.span()];
+            if (last.idx..=last.end).contains(&e.cursor) && last.end == source.len() && (word.ends_with('.') || self.starts_word(word)) {
+                return true;
+            }
+        }
+        let ran_out = Cell::new(false);
+        let parsed = self.parse().parse_tokens_watched(&tokens, &ran_out);
+        parsed.is_err() && ran_out.get()
+    }
+
+    /// whether `prefix` is the start of a longer word (or `!=`) the language knows
+    fn starts_word(&self, prefix: &str) -> bool {
+        const CONTEXTUAL: &[&str] = &["value", "field", "regex", "type", "between", "slice", "asc", "desc", "from", "local", "foreign", "as", "sum", "avg", "min", "max", "push", "add_to_set", "!="];
+        let words = KEYWORDS.iter().map(|(keyword, _)| *keyword)
+            .chain(CONTEXTUAL.iter().copied())
+            .chain(TYPE_NAMES.iter().copied())
+            .chain(self.opts.aliases.iter().map(|(alias, _)| alias.as_str()));
+        !prefix.is_empty() && words.into_iter().any(|word| word.len() > prefix.len() && word.starts_with(prefix))
+    }
+}
+
+#[cfg(feature = "bson")]
+impl MonGod {
+    /// the same stages `ast2mql` renders, as documents the `mongodb` driver takes directly.
+    /// whole numbers come out as `Int32`/`Int64`, everything else as `Double`
+    pub fn to_bson_pipeline(&self) -> Vec<bson::Document> {
+        let mut stages = Vec::new();
+        for node in self.ast.iter() {
+            Self::bson_stage(node, &mut stages);
+        }
+        stages
+    }
+
+    /// mirrors `render_stage` with the default `MqlOptions`
+    fn bson_stage(node: &ASTNode, out: &mut Vec<bson::Document>) {
+        match node {
+            ASTNode::Match(inner) => match &**inner {
+                ASTNode::Comment { condition, comment } => {
+                    let mut query = Self::bson_condition(condition);
+                    query.insert("$comment", comment.clone());
+                    out.push(Self::bson_doc("$match", query));
+                }
+                inner => out.push(Self::bson_doc("$match", Self::bson_condition(inner))),
+            },
+            ASTNode::Set(assignments) => {
+                let mut set = bson::Document::new();
+                for (field, value) in assignments {
+                    set.insert(field.clone(), Self::bson_expression(value));
+                }
+                out.push(Self::bson_doc("$set", set));
+            }
+            ASTNode::Project(fields) => {
+                let mut project = bson::Document::new();
+                for (field, value) in fields {
+                    let value = match (Self::projection_flag(value), value) {
+                        (Some(keep), _) => bson::Bson::Int32(keep as i32),
+                        (None, ASTNode::Number(_)) => bson::Bson::Document(Self::bson_doc("$literal", Self::bson_value(value))),
+                        (None, ASTNode::Slice { skip: Some(skip), count }) => bson::Bson::Document(Self::bson_doc("$slice", vec![*skip, *count])),
+                        (None, ASTNode::Slice { skip: None, count }) => bson::Bson::Document(Self::bson_doc("$slice", *count)),
+                        _ => Self::bson_expression(value),
+                    };
+                    project.insert(field.clone(), value);
+                }
+                out.push(Self::bson_doc("$project", project));
+            }
+            ASTNode::Facet(branches) => {
+                let mut facet = bson::Document::new();
+                for (name, stages) in branches {
+                    let mut pipeline = Vec::new();
+                    for stage in stages {
+                        Self::bson_stage(stage, &mut pipeline);
+                    }
+                    facet.insert(name.clone(), pipeline);
+                }
+                out.push(Self::bson_doc("$facet", facet));
+            }
+            ASTNode::Group { keys, accumulators } => {
+                let id = match keys.as_slice() {
+                    [] => bson::Bson::Null,
+                    [key] => bson::Bson::String(format!("${}", key)),
+                    keys => {
+                        let mut id = bson::Document::new();
+                        for key in keys {
+                            id.insert(Self::group_key_name(key), format!("${}", key));
+                        }
+                        bson::Bson::Document(id)
+                    }
+                };
+                let mut group = Self::bson_doc("_id", id);
+                for (name, value) in accumulators {
+                    group.insert(name.clone(), Self::bson_expression(value));
+                }
+                out.push(Self::bson_doc("$group", group));
+            }
+            ASTNode::Lookup { from, local, foreign, as_ } => {
+                let mut lookup = bson::Document::new();
+                lookup.insert("from", from.clone());
+                lookup.insert("localField", local.clone());
+                lookup.insert("foreignField", foreign.clone());
+                lookup.insert("as", as_.clone());
+                out.push(Self::bson_doc("$lookup", lookup));
+            }
+            ASTNode::Rename { from, to } => {
+                out.push(Self::bson_doc("$addFields", Self::bson_doc(to, format!("${}", from))));
+                out.push(Self::bson_doc("$project", Self::bson_doc(from, 0)));
+            }
+            ASTNode::Unset(fields) => {
+                let unset = match fields.as_slice() {
+                    [field] => bson::Bson::String(field.clone()),
+                    fields => bson::Bson::Array(fields.iter().map(|f| bson::Bson::String(f.clone())).collect()),
+                };
+                out.push(Self::bson_doc("$unset", unset));
+            }
+            ASTNode::Require(fields) => {
+                let schema = Self::bson_doc("required", fields.clone());
+                out.push(Self::bson_doc("$match", Self::bson_doc("$jsonSchema", schema)));
+            }
+            ASTNode::Sort(keys) => {
+                let mut sort = bson::Document::new();
+                for (field, order) in keys {
+                    sort.insert(field.clone(), order.mql_value() as i32);
+                }
+                out.push(Self::bson_doc("$sort", sort));
+            }
+            ASTNode::Limit(n) => out.push(Self::bson_doc("$limit", *n as i64)),
+            ASTNode::Sample(n) => out.push(Self::bson_doc("$sample", Self::bson_doc("size", *n as i64))),
+            _ => panic!("Unexpected node type!"),
+        }
+    }
+
+    /// mirrors `render_condition`
+    fn bson_condition(node: &ASTNode) -> bson::Document {
+        match node {
+            ASTNode::Condition { op, left, right } if Self::needs_expr(left, right) => {
+                let operands = vec![Self::bson_expression(left), Self::bson_expression_value(right)];
+                Self::bson_doc("$expr", Self::bson_doc(op.mql_op(), operands))
+            }
+            ASTNode::Condition { op, left, right } => {
+                let (field, op, value) = match (&**left, &**right) {
+                    (ASTNode::Number(_) | ASTNode::Str(_) | ASTNode::Bool(_) | ASTNode::Param(_), ASTNode::Literal(_) | ASTNode::Length(_)) => (right, op.flipped(), left),
+                    _ => (left, *op, right),
+                };
+                if let ASTNode::Length(array) = &**field {
+                    if op == Comparator::EQ && Self::is_array_size(value) {
+                        return Self::bson_doc(array, Self::bson_doc("$size", Self::bson_value(value)));
+                    }
+                    if op == Comparator::NEQ && Self::is_array_size(value) {
+                        return Self::bson_doc(array, Self::bson_doc("$not", Self::bson_doc("$size", Self::bson_value(value))));
+                    }
+                    let size = Self::bson_doc("$size", format!("${}", array));
+                    return Self::bson_doc("$expr", Self::bson_doc(op.mql_op(), vec![bson::Bson::Document(size), Self::bson_expression_value(value)]));
+                }
+                if let (ASTNode::Literal(_), true) = (&**field, Self::is_field_ref(value)) {
+                    let operands = vec![Self::bson_expression(field), Self::bson_value(value)];
+                    return Self::bson_doc("$expr", Self::bson_doc(op.mql_op(), operands));
+                }
+                let key = match &**field {
+                    ASTNode::Literal(l) => l.clone(),
+                    ASTNode::Number(n) => n.to_string(),
+                    _ => panic!("Unexpected node type!"),
+                };
+                Self::bson_doc(&key, Self::bson_doc(op.mql_op(), Self::bson_value(value)))
+            }
+            ASTNode::ConditionalOperator { op, conditions } => {
+                let conditions: Vec<bson::Bson> = conditions.iter().map(|c| bson::Bson::Document(Self::bson_condition(c))).collect();
+                Self::bson_doc(op.mql_op(), conditions)
+            }
+            ASTNode::Text { search, language, case_sensitive } => {
+                let mut text = Self::bson_doc("$search", search.clone());
+                if let Some(language) = language {
+                    text.insert("$language", language.clone());
+                }
+                if let Some(case_sensitive) = case_sensitive {
+                    text.insert("$caseSensitive", *case_sensitive);
+                }
+                Self::bson_doc("$text", text)
+            }
+            ASTNode::All { field, values } => {
+                let values: Vec<bson::Bson> = values
+                    .iter()
+                    .map(|value| match value {
+                        ASTNode::ElemMatch(condition) => bson::Bson::Document(Self::bson_doc("$elemMatch", Self::bson_condition(condition))),
+                        value => Self::bson_value(value),
+                    })
+                    .collect();
+                Self::bson_doc(field, Self::bson_doc("$all", values))
+            }
+            ASTNode::In { field, values } => {
+                let values: Vec<bson::Bson> = values.iter().map(Self::bson_value).collect();
+                Self::bson_doc(field, Self::bson_doc("$in", values))
+            }
+            ASTNode::Matches { field, pattern } if Self::is_field_ref(pattern) => {
+                let mut regex_match = Self::bson_doc("input", format!("${}", field));
+                regex_match.insert("regex", Self::bson_value(pattern));
+                Self::bson_doc("$expr", Self::bson_doc("$regexMatch", regex_match))
+            }
+            ASTNode::Matches { field, pattern } => match &**pattern {
+                ASTNode::Regex { pattern, options } => {
+                    let mut regex = Self::bson_doc("$regex", pattern.clone());
+                    if !options.is_empty() {
+                        regex.insert("$options", options.clone());
+                    }
+                    Self::bson_doc(field, regex)
+                }
+                pattern => Self::bson_doc(field, Self::bson_doc("$regex", Self::bson_value(pattern))),
+            },
+            ASTNode::Type { field, types } => match types.as_slice() {
+                [ty] => Self::bson_doc(field, Self::bson_doc("$type", ty.as_str())),
+                types => Self::bson_doc(field, Self::bson_doc("$type", types.to_vec())),
+            },
+            ASTNode::HasValue(field) => {
+                let mut has_value = Self::bson_doc("$exists", true);
+                has_value.insert("$ne", bson::Bson::Null);
+                Self::bson_doc(field, has_value)
+            }
+            _ => panic!("Unexpected node type!"),
+        }
+    }
+
+    /// mirrors `render_value`
+    fn bson_value(node: &ASTNode) -> bson::Bson {
+        match node {
+            ASTNode::Literal(l) | ASTNode::Str(l) => bson::Bson::String(l.clone()),
+            ASTNode::Number(n) => Self::bson_number(*n),
+            ASTNode::Bool(b) => bson::Bson::Boolean(*b),
+            ASTNode::Null => bson::Bson::Null,
+            ASTNode::Param(i) => bson::Bson::String(format!(":p{}", i)),
+            _ => panic!("Unexpected node type!"),
+        }
+    }
+
+    fn bson_number(n: f64) -> bson::Bson {
+        if n.fract() == 0.0 && n >= i32::MIN as f64 && n <= i32::MAX as f64 {
+            bson::Bson::Int32(n as i32)
+        } else if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 {
+            bson::Bson::Int64(n as i64)
+        } else {
+            bson::Bson::Double(n)
+        }
+    }
+
+    /// mirrors `render_expression`
+    fn bson_expression(node: &ASTNode) -> bson::Bson {
+        match node {
+            ASTNode::Literal(l) if l.starts_with('$') => bson::Bson::String(l.clone()),
+            ASTNode::Literal(l) => bson::Bson::String(format!("${}", l)),
+            ASTNode::Length(array) => bson::Bson::Document(Self::bson_doc("$size", format!("${}", array))),
+            ASTNode::Arithmetic { op, left, right } => {
+                let operands = vec![Self::bson_expression(left), Self::bson_expression(right)];
+                bson::Bson::Document(Self::bson_doc(op.mql_op(), operands))
+            }
+            ASTNode::Cond { condition, then, otherwise } => {
+                let branches = vec![Self::bson_expression_condition(condition), Self::bson_expression(then), Self::bson_expression(otherwise)];
+                bson::Bson::Document(Self::bson_doc("$cond", branches))
+            }
+            ASTNode::Accumulator { op, arg } => bson::Bson::Document(Self::bson_doc(op.mql_op(), Self::bson_expression(arg))),
+            ASTNode::Str(s) if s.starts_with('$') => bson::Bson::Document(Self::bson_doc("$literal", s.clone())),
+            _ => Self::bson_value(node),
+        }
+    }
+
+    /// mirrors `render_expression_value`
+    fn bson_expression_value(node: &ASTNode) -> bson::Bson {
+        match node {
+            ASTNode::Arithmetic { .. } | ASTNode::Length(_) | ASTNode::Str(_) => Self::bson_expression(node),
+            _ => Self::bson_value(node),
+        }
+    }
+
+    /// mirrors `render_expression_condition`
+    fn bson_expression_condition(node: &ASTNode) -> bson::Bson {
+        match node {
+            ASTNode::Condition { op, left, right } => {
+                let (field, op, value) = match (&**left, &**right) {
+                    (ASTNode::Number(_) | ASTNode::Str(_) | ASTNode::Bool(_), ASTNode::Literal(_)) => (right, op.flipped(), left),
+                    _ => (left, *op, right),
+                };
+                let operands = vec![Self::bson_expression(field), Self::bson_expression_value(value)];
+                bson::Bson::Document(Self::bson_doc(op.mql_op(), operands))
+            }
+            ASTNode::ConditionalOperator { op, conditions } => {
+                let conditions: Vec<bson::Bson> = conditions.iter().map(|c| Self::bson_expression_condition(c)).collect();
+                bson::Bson::Document(Self::bson_doc(op.mql_op(), conditions))
+            }
+            _ => Self::bson_expression(node),
+        }
+    }
+
+    fn bson_doc(key: &str, value: impl Into<bson::Bson>) -> bson::Document {
+        let mut doc = bson::Document::new();
+        doc.insert(key, value);
+        doc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mql(source: &str) -> String {
+        built(source).ast2mql()
+    }
+
+    fn parse_err(source: &str) -> ParseError {
+        match MonGod::new(source.to_string()).build() {
+            Ok(()) => panic!("`{}` parsed", source),
+            Err(e) => e,
+        }
+    }
+
+    fn built(source: &str) -> MonGod {
+        let mut m = MonGod::new(source.to_string());
+        if let Err(e) = m.build() {
+            panic!("`{}` didn't parse: {:?}", source, e);
+        }
+        m
+    }
+
+    fn optimized(source: &str, opts: &OptimizeOptions) -> String {
+        let mut m = built(source);
+        m.optimize_with(opts);
+        m.ast2mql()
+    }
+
+    /// every rewrite off, to turn them back on one at a time
+    fn no_rewrites() -> OptimizeOptions {
+        OptimizeOptions::default()
+            .hoist_matches(false)
+            .merge_matches(false)
+            .collapse_single_child_groups(false)
+            .flatten_groups(false)
+            .fold_constants(false)
+            .sort_groups(false)
+            .dedupe_groups(false)
+    }
+
+    fn build_with(source: &str, opts: ParseOptions) -> Result<(), ParseError> {
+        MonGod::with_options(source.to_string(), opts).build()
+    }
+
+    fn pipeline(stages: &str) -> String {
+        format!("db.collection.aggregate([{},])", stages)
+    }
+
+    #[test]
+    fn backtick_names_are_quoted_as_values() {
+        assert_eq!(
+            mql("match(a == `x\", $where: \"1`)"),
+            pipeline(r#"{ $match: { a: { $eq: "x\", $where: \"1" } } }"#)
+        );
+        assert_eq!(mql("match(a in [`x\"y`])"), pipeline(r#"{ $match: { a: { $in: ["x\"y"] } } }"#));
+    }
+
+    #[test]
+    fn backtick_names_are_quoted_as_paths() {
+        assert_eq!(mql("unset(`a\"b`)"), pipeline(r#"{ $unset: "a\"b" }"#));
+        assert_eq!(mql("unset(`a\\b`, c)"), pipeline(r#"{ $unset: ["a\\b", "c"] }"#));
+        assert_eq!(
+            mql("rename(`a\"b`, c)"),
+            pipeline(r#"{ $addFields: { c: "$a\"b" } },{ $project: { "a\"b": 0 } }"#)
+        );
+        assert_eq!(mql("set(total: `a\"b` * 2)"), pipeline(r#"{ $set: { total: { $multiply: ["$a\"b", 2] } } }"#));
+    }
+
+    #[test]
+    fn backtick_reserved_words_are_fields() {
+        assert_eq!(mql("match(`match` == 1)"), pipeline("{ $match: { match: { $eq: 1 } } }"));
+        assert_eq!(mql("match(`first name` == \"bo\")"), pipeline(r#"{ $match: { "first name": { $eq: "bo" } } }"#));
+    }
+
+    #[test]
+    fn bare_fields_are_truthiness_tests() {
+        assert_eq!(mql("match(active)"), pipeline("{ $match: { active: { $eq: true } } }"));
+        assert_eq!(mql("match(!deleted)"), pipeline("{ $match: { deleted: { $eq: false } } }"));
+        assert_eq!(
+            mql("match(active & !deleted)"),
+            pipeline("{ $match: { $and: [{ active: { $eq: true } }, { deleted: { $eq: false } }] } }")
+        );
+    }
+
+    #[test]
+    fn comparisons_without_a_field_are_rejected() {
+        for (source, cursor) in [("match(5 < $b)", 6), ("match(\"a\" == $b)", 6), ("match((true == $b))", 7), ("match(a == 1 & 2 == $b)", 15)] {
+            let e = parse_err(source);
+            assert!(matches!(e.ty, ParseErrorT::InvalidFieldName), "{}: {:?}", source, e);
+            assert_eq!(e.cursor, cursor, "{}", source);
+        }
+        assert_eq!(mql("match(5 < age)"), pipeline("{ $match: { age: { $gt: 5 } } }"));
+        assert_eq!(mql("match(\"x\" == name)"), pipeline(r#"{ $match: { name: { $eq: "x" } } }"#));
+    }
+
+    /// what `fuzz/fuzz_targets/build_never_panics.rs` checks, over its corpus, so the
+    /// inputs that used to crash it stay fixed without a nightly toolchain
+    #[test]
+    fn fuzz_corpus_builds_or_errors() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus/build_never_panics");
+        let mut seen = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let Ok(source) = std::fs::read_to_string(&path) else {
+                continue;
+            };
+            let mut m = MonGod::new(source.clone());
+            match m.build() {
+                Ok(()) => {
+                    assert_eq!(m.validate_mql(), Ok(()), "{}", path.display());
+                    m.ast2mql();
+                    m.to_pipeline_json_string();
+                    m.explain();
+                }
+                Err(e) => {
+                    format_diagnostic(&source, &e);
+                    format_error(&source, &e);
+                }
+            }
+            token_stats(&source);
+            seen += 1;
+        }
+        assert!(seen > 0);
+    }
+
+    #[test]
+    fn constant_comparisons_go_in_expr() {
+        for (source, expected) in [
+            ("match(1 == 2)", "{ $match: { $expr: { $eq: [1, 2] } } }"),
+            ("match(true == false)", "{ $match: { $expr: { $eq: [true, false] } } }"),
+            ("match(\"a\" == \"b\")", r#"{ $match: { $expr: { $eq: ["a", "b"] } } }"#),
+            ("match((1 == 2))", "{ $match: { $expr: { $eq: [1, 2] } } }"),
+        ] {
+            assert_eq!(mql(source), pipeline(expected), "{}", source);
+            assert!(matches!(&built(source).warnings()[..], [Warning { ty: WarningT::AlwaysFalse(_), .. }]), "{}", source);
+        }
+        // and folding decides them
+        assert_eq!(optimized("match(a == 1 & 2 == 2)", &OptimizeOptions::default()), pipeline("{ $match: { a: { $eq: 1 } } }"));
+        assert_eq!(optimized("match(2 == 2).limit(1)", &OptimizeOptions::default()), pipeline("{ $limit: 1 }"));
+    }
+
+    #[test]
+    fn chained_comparisons_with_and_without_parens() {
+        let expected = pipeline("{ $match: { $and: [{ age: { $gt: 18 } }, { age: { $lt: 65 } }] } }");
+        assert_eq!(mql("match(18 < age < 65)"), expected);
+        assert_eq!(mql("match((18 < age < 65))"), expected);
+        assert_eq!(
+            mql("match(a == 1 & 1 < b <= 5)"),
+            pipeline("{ $match: { $and: [{ a: { $eq: 1 } }, { $and: [{ b: { $gt: 1 } }, { b: { $lte: 5 } }] }] } }")
+        );
+        // the second half has no field, it's a constant comparison like any other
+        assert_eq!(mql("match(a < 1 < 2)"), pipeline("{ $match: { $and: [{ a: { $lt: 1 } }, { $expr: { $lt: [1, 2] } }] } }"));
+        assert!(matches!(parse_err("match(a < 1 < $b)").ty, ParseErrorT::InvalidFieldName));
+    }
+
+    #[test]
+    fn collation_is_appended() {
+        let mut m = MonGod::new("match(name == \"bob\")".to_string());
+        m.build().unwrap();
+        let query = pipeline(r#"{ $match: { name: { $eq: "bob" } } }"#);
+        assert_eq!(m.ast2mql_with_collation("en", 2), Ok(format!(r#"{}.collation({{ locale: "en", strength: 2 }})"#, query)));
+        assert_eq!(
+            m.ast2mql_with_collation(r#"en", x: "1"#, 1),
+            Ok(format!(r#"{}.collation({{ locale: "en\", x: \"1", strength: 1 }})"#, query))
+        );
+        assert_eq!(m.ast2mql_with_collation("en", 0), Err(MqlError::InvalidCollationStrength(0)));
+        assert_eq!(m.ast2mql_with_collation("en", 6), Err(MqlError::InvalidCollationStrength(6)));
+    }
+
+    #[test]
+    fn match_accessors() {
+        let stage = ASTNode::match_(ASTNode::condition("a", Comparator::EQ, 1));
+        assert_eq!(stage.as_match(), Some(&ASTNode::condition("a", Comparator::EQ, 1)));
+        assert_eq!(stage.inner(), stage.as_match());
+        assert_eq!(ASTNode::limit(5).as_match(), None);
+        assert_eq!(ASTNode::limit(5).inner(), None);
+    }
+
+    #[test]
+    fn malformed_field_paths_are_rejected() {
+        for (source, cursor) in [("match(a..b == 1)", 8), ("match(.a == 1)", 6), ("match(a. == 1)", 7), ("match($a == 1)", 6), ("set($a: 1)", 4), ("unset($a)", 6)] {
+            let e = parse_err(source);
+            assert!(matches!(e.ty, ParseErrorT::InvalidFieldName), "{}: {:?}", source, e);
+            assert_eq!(e.cursor, cursor, "{}", source);
+        }
+        assert_eq!(mql("match(a.b.c == 1)"), pipeline(r#"{ $match: { "a.b.c": { $eq: 1 } } }"#));
+    }
+
+    #[test]
+    fn lookahead_helpers() {
+        let tokens = vec![Token::new(TokenT::Match, 0), Token::new(TokenT::OpenParen, 5), Token::new(TokenT::Literal("a".to_string()), 6)];
+        let mut iter = peek_nth(tokens);
+        assert_eq!(MonGod::peek_kind(&mut iter), Some(&TokenT::Match));
+        assert_eq!(MonGod::peek_kind_nth(&mut iter, 0), Some(&TokenT::Match));
+        assert_eq!(MonGod::peek_kind_nth(&mut iter, 2), Some(&TokenT::Literal("a".to_string())));
+        assert_eq!(MonGod::peek_kind_nth(&mut iter, 3), None);
+        assert_eq!(MonGod::peek_idx(&mut iter), 0);
+        assert!(MonGod::expect(&mut iter, TokenT::Match).is_ok());
+        let e = MonGod::expect(&mut iter, TokenT::Colon).unwrap_err();
+        assert!(matches!(e.ty, ParseErrorT::MissingColon));
+        assert_eq!(e.cursor, 5);
+        assert_eq!(MonGod::peek_idx(&mut iter), 6);
+        iter.next();
+        assert_eq!(MonGod::peek_kind(&mut iter), None);
+        assert_eq!(MonGod::peek_idx(&mut iter), 0);
+        assert!(matches!(MonGod::expect(&mut iter, TokenT::CloseParen).unwrap_err().ty, ParseErrorT::MissingCloseParen));
+    }
+
+    #[test]
+    fn parse_errors_convert_into_other_error_types() {
+        fn build(source: &str) -> anyhow::Result<String> {
+            let mut m = MonGod::new(source.to_string());
+            m.build()?;
+            Ok(m.ast2mql())
+        }
+        let e = build("match(a == 1).").unwrap_err();
+        assert_eq!(e.to_string(), "expected a stage after `.` at index 13");
+        assert!(matches!(e.downcast_ref::<ParseError>(), Some(ParseError { ty: ParseErrorT::DanglingDot, cursor: 13 })));
+        let e = std::io::Error::from(parse_err("match(a = 1)"));
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "unexpected character at index 8");
+        let e = anyhow::Error::from(MqlError::InvalidCollationStrength(9));
+        assert_eq!(e.to_string(), "collation strength must be between 1 and 5, got 9");
+    }
+
+    #[test]
+    fn dots_between_stages() {
+        for (source, cursor) in [("match(a==1).", 11), ("match(a==1).limit(5).", 20), ("match(a==1) . ", 12)] {
+            let e = parse_err(source);
+            assert!(matches!(e.ty, ParseErrorT::DanglingDot), "{}: {:?}", source, e);
+            assert_eq!(e.cursor, cursor, "{}", source);
+        }
+        for (source, cursor) in [("match(a==1)match(b==2)", 11), ("limit(5)sort(a asc)", 8)] {
+            let e = parse_err(source);
+            assert!(matches!(e.ty, ParseErrorT::NoDotBetweenFns), "{}: {:?}", source, e);
+            assert_eq!(e.cursor, cursor, "{}", source);
+        }
+        assert_eq!(mql("match(a==1).limit(5)"), pipeline("{ $match: { a: { $eq: 1 } } },{ $limit: 5 }"));
+    }
+
+    #[test]
+    fn describe_summarises_the_pipeline() {
+        let mut m = MonGod::new("match(age > 18 & (branch == CSE | age < 5)).sort(age desc, name asc)".to_string());
+        m.build().unwrap();
+        assert_eq!(
+            m.describe(),
+            QueryShape {
+                stages: vec!["match", "sort"],
+                fields: vec!["age".to_string(), "branch".to_string(), "name".to_string()],
+                comparators: vec![Comparator::GT, Comparator::EQ, Comparator::LT],
+                conditional_operators: vec![ConditionalOperator::AND, ConditionalOperator::OR],
+            }
+        );
+        assert_eq!(MonGod::new(String::new()).describe(), QueryShape::default());
+    }
+
+    #[test]
+    fn inner_only_peels_a_match() {
+        let mut m = MonGod::new("match(a == 1)".to_string());
+        let ast = m.build_ast().unwrap();
+        let condition = ast[0].inner().unwrap();
+        assert_eq!(condition, &ASTNode::condition("a", Comparator::EQ, 1));
+        assert_eq!(condition.inner(), None);
+    }
+
+    #[test]
+    fn explain_describes_each_stage() {
+        let mut m = MonGod::new("match(age > 18).limit(5)".to_string());
+        m.build().unwrap();
+        assert_eq!(m.explain(), "Stage 1: keep documents where age > 18\nStage 2: keep the first 5 documents");
+    }
+
+    #[test]
+    fn comparators_map_the_same_in_every_output() {
+        for (symbol, op) in [("==", "$eq"), ("!=", "$ne"), (">", "$gt"), (">=", "$gte"), ("<", "$lt"), ("<=", "$lte")] {
+            let mut m = MonGod::new(format!("match(a {} 1)", symbol));
+            m.build().unwrap();
+            assert_eq!(m.ast2mql(), pipeline(&format!("{{ $match: {{ a: {{ {}: 1 }} }} }}", op)));
+            assert_eq!(m.to_pipeline_json_string(), format!(r#"[{{ "$match": {{ "a": {{ "{}": 1 }} }} }}]"#, op));
+            #[cfg(feature = "bson")]
+            assert_eq!(m.to_bson_pipeline(), vec![bson::doc! { "$match": { "a": { op: 1 } } }]);
+        }
+    }
+
+    #[test]
+    fn pretty_indent_width() {
+        let mut m = MonGod::new("match(a == 1).limit(5)".to_string());
+        m.build().unwrap();
+        let two = "db.collection.aggregate([\n  {\n    $match: {\n      a: {\n        $eq: 1\n      }\n    }\n  },\n  {\n    $limit: 5\n  },\n])";
+        assert_eq!(m.ast2mql_with(&MqlOptions::default().pretty(true)), two);
+        assert_eq!(m.ast2mql_with(&MqlOptions::default().pretty(true).indent(2)), two);
+        assert_eq!(m.ast2mql_with(&MqlOptions::default().pretty(true).indent(4)), two.replace("  ", "    "));
+        assert_eq!(m.ast2mql_with(&MqlOptions::default().pretty(true).indent_tabs()), two.replace("  ", "\t"));
+    }
+
+    #[test]
+    fn set_computed_fields() {
+        assert_eq!(mql("set(total: price * quantity)"), pipeline(r#"{ $set: { total: { $multiply: ["$price", "$quantity"] } } }"#));
+        assert_eq!(
+            mql("set(total: price * quantity, tax: price * 0.2 + 1)"),
+            pipeline(r#"{ $set: { total: { $multiply: ["$price", "$quantity"] }, tax: { $add: [{ $multiply: ["$price", 0.2] }, 1] } } }"#)
+        );
+        assert!(matches!(parse_err("set(total price)").ty, ParseErrorT::MissingColon));
+    }
+
+    #[test]
+    fn rename_stages() {
+        assert_eq!(mql("rename(a, b)"), pipeline(r#"{ $addFields: { b: "$a" } },{ $project: { a: 0 } }"#));
+        let mut m = MonGod::new("rename(a, b)".to_string());
+        m.build().unwrap();
+        assert_eq!(
+            m.ast2mql_with(&MqlOptions::default().rename_via_unset(true)),
+            pipeline(r#"{ $set: { b: "$a" } },{ $unset: "a" }"#)
+        );
+    }
+
+    #[test]
+    fn unset_fields() {
+        assert_eq!(mql("unset(a)"), pipeline(r#"{ $unset: "a" }"#));
+        assert_eq!(mql("unset(a, b.c)"), pipeline(r#"{ $unset: ["a", "b.c"] }"#));
+        let e = parse_err("unset(a, $b)");
+        assert!(matches!(e.ty, ParseErrorT::InvalidFieldName));
+        assert_eq!(e.cursor, 9);
+    }
+
+    #[test]
+    fn build_ast_borrows_the_stages() {
+        let mut m = MonGod::new("match(a == 1).limit(5)".to_string());
+        assert_eq!(m.build_ast().unwrap(), &[ASTNode::match_(ASTNode::condition("a", Comparator::EQ, 1)), ASTNode::limit(5)]);
+        assert!(matches!(MonGod::new("limit(0)".to_string()).build_ast().unwrap_err().ty, ParseErrorT::InvalidLimit));
+    }
+
+    #[test]
+    fn ternaries_render_as_cond() {
+        assert_eq!(mql("set(x: if (a > 1) then 1 else 2)"), pipeline(r#"{ $set: { x: { $cond: [{ $gt: ["$a", 1] }, 1, 2] } } }"#));
+        assert_eq!(
+            mql("set(x: if (a > 1) then 1 else if (b == 2) then price * 2 else 3)"),
+            pipeline(r#"{ $set: { x: { $cond: [{ $gt: ["$a", 1] }, 1, { $cond: [{ $eq: ["$b", 2] }, { $multiply: ["$price", 2] }, 3] }] } } }"#)
+        );
+        let e = parse_err("set(x: if (a > 1) 1 else 2)");
+        assert!(matches!(e.ty, ParseErrorT::MissingThen));
+        assert_eq!(e.cursor, 18);
+        assert!(matches!(parse_err("set(x: if (a > 1) then 1)").ty, ParseErrorT::MissingElse));
+    }
+
+    #[test]
+    fn stage_count_limit() {
+        let opts = ParseOptions::default().max_stages(2);
+        assert!(build_with("limit(1).limit(2)", opts.clone()).is_ok());
+        let e = build_with("limit(1).limit(2).limit(3)", opts).unwrap_err();
+        assert!(matches!(e.ty, ParseErrorT::TooManyStages));
+        assert_eq!(e.cursor, 18);
+        assert!(build_with("limit(1).".repeat(50).trim_end_matches('.'), ParseOptions::default()).is_ok());
+        // the cap is over every pipeline together
+        let opts = ParseOptions::default().max_stages(3);
+        assert!(build_with("limit(1);limit(2).limit(3)", opts.clone()).is_ok());
+        let e = build_with(&"limit(1);".repeat(50), opts).unwrap_err();
+        assert!(matches!(e.ty, ParseErrorT::TooManyStages));
+        assert_eq!(e.cursor, 27);
+    }
+
+    #[test]
+    fn single_child_groups_collapse_on_request() {
+        let mut m = built("match(&((a == 1)))");
+        assert_eq!(m.ast2mql(), pipeline("{ $match: { $and: [{ a: { $eq: 1 } }] } }"));
+        m.collapse_single_child_groups();
+        assert_eq!(m.ast2mql(), pipeline("{ $match: { a: { $eq: 1 } } }"));
+
+        let mut m = built("match(&((a == 1)(b == 2)))");
+        m.collapse_single_child_groups();
+        assert_eq!(m.ast2mql(), pipeline("{ $match: { $and: [{ a: { $eq: 1 } }, { b: { $eq: 2 } }] } }"));
+
+        let mut m = built("match(nor((a == 1)))");
+        m.collapse_single_child_groups();
+        assert_eq!(m.ast2mql(), pipeline("{ $match: { $nor: [{ a: { $eq: 1 } }] } }"));
+    }
+
+    #[test]
+    fn spans_cover_the_source_of_each_node() {
+        let source = "match((age > 18) & b == 2)";
+        let m = built(source);
+        let stage = &m.spans[0];
+        assert_eq!(stage.span, 0..source.len());
+        let and = &stage.children[0];
+        assert_eq!(&source[and.span.clone()], "(age > 18) & b == 2");
+        let [first, second] = &and.children[..] else { panic!("{:?}", and) };
+        assert_eq!(&source[first.span.clone()], "(age > 18)");
+        assert_eq!(&source[first.children[0].span.clone()], "age");
+        assert_eq!(&source[first.children[1].span.clone()], "18");
+        assert_eq!(&source[second.span.clone()], "b == 2");
+    }
+
+    #[test]
+    fn trivia_tokens_rebuild_the_source() {
+        let source = "match(a == 1) // adults\n.limit(5)";
+        let tokens = MonGod::tokenize_with_trivia(source).unwrap();
+        let trivia: Vec<&TokenT> = tokens.iter().map(|t| t.ty()).filter(|ty| matches!(ty, TokenT::Trivia(_))).collect();
+        assert_eq!(
+            trivia,
+            [" ", " ", " ", "// adults", "\n"].map(|t| TokenT::Trivia(t.to_string())).iter().collect::<Vec<_>>()
+        );
+        assert_eq!(tokens.iter().map(|t| &source[t.span()]).collect::<String>(), source);
+        let plain = MonGod::tokenize(source).unwrap();
+        assert!(plain.iter().all(|t| !matches!(t.ty(), TokenT::Trivia(_))));
+        assert_eq!(plain.len(), tokens.len() - trivia.len());
+    }
+
+    #[test]
+    fn text_search() {
+        assert_eq!(mql(r#"match(text("coffee shop"))"#), pipeline(r#"{ $match: { $text: { $search: "coffee shop" } } }"#));
+        assert_eq!(
+            mql(r#"match(text("coffee shop", language: es, case_sensitive: true) & open == true)"#),
+            pipeline(r#"{ $match: { $and: [{ $text: { $search: "coffee shop", $language: "es", $caseSensitive: true } }, { open: { $eq: true } }] } }"#)
+        );
+        let e = parse_err(r#"match(text("a") & text("b"))"#);
+        assert!(matches!(e.ty, ParseErrorT::DuplicateText));
+        assert_eq!(e.cursor, 18);
+    }
+
+    #[test]
+    fn escape_regex_metacharacters() {
+        for c in ['.', '*', '+', '?', '(', ')', '[', ']', '{', '}', '^', '$', '|', '\\'] {
+            assert_eq!(escape_regex(&format!("a{}b", c)), format!("a\\{}b", c));
+        }
+        assert_eq!(escape_regex("1+1=2?"), r"1\+1=2\?");
+        assert_eq!(escape_regex("plain text é"), "plain text é");
+    }
+
+    #[test]
+    fn each_optimization_on_its_own() {
+        let source = "set(x: 1).match(a == 1).match(&((b == 2)))";
+        assert_eq!(
+            optimized(source, &no_rewrites()),
+            pipeline("{ $set: { x: 1 } },{ $match: { a: { $eq: 1 } } },{ $match: { $and: [{ b: { $eq: 2 } }] } }")
+        );
+        assert_eq!(
+            optimized(source, &no_rewrites().hoist_matches(true)),
+            pipeline("{ $match: { a: { $eq: 1 } } },{ $match: { $and: [{ b: { $eq: 2 } }] } },{ $set: { x: 1 } }")
+        );
+        assert_eq!(
+            optimized(source, &no_rewrites().merge_matches(true)),
+            pipeline("{ $set: { x: 1 } },{ $match: { $and: [{ a: { $eq: 1 } }, { b: { $eq: 2 } }] } }")
+        );
+        assert_eq!(
+            optimized(source, &no_rewrites().collapse_single_child_groups(true)),
+            pipeline("{ $set: { x: 1 } },{ $match: { a: { $eq: 1 } } },{ $match: { b: { $eq: 2 } } }")
+        );
+        assert_eq!(
+            optimized(source, &OptimizeOptions::default()),
+            pipeline("{ $match: { $and: [{ a: { $eq: 1 } }, { b: { $eq: 2 } }] } },{ $set: { x: 1 } }")
+        );
+    }
+
+    #[test]
+    fn matches_only_hoist_past_stages_that_leave_their_fields_alone() {
+        let hoist = no_rewrites().hoist_matches(true);
+        assert_eq!(optimized("project(a: 1, b: 1).match(a == 1)", &hoist), pipeline("{ $match: { a: { $eq: 1 } } },{ $project: { a: 1, b: 1 } }"));
+        assert_eq!(optimized("set(x: a).match(a.b == 1)", &hoist), pipeline(r#"{ $match: { "a.b": { $eq: 1 } } },{ $set: { x: "$a" } }"#));
+        for source in ["project(b: 1).match(a == 1)", "set(a: 2).match(a == 1)", "set(a.b: 2).match(a == 1)", "limit(5).match(a == 1)"] {
+            assert_eq!(optimized(source, &hoist), mql(source), "{}", source);
+        }
+    }
+
+    #[test]
+    fn hex_and_unicode_escapes() {
+        assert_eq!(mql(r#"match(a == "\x41")"#), pipeline(r#"{ $match: { a: { $eq: "A" } } }"#));
+        assert_eq!(mql(r#"match(a == "\u{1F600}!")"#), pipeline(r#"{ $match: { a: { $eq: "😀!" } } }"#));
+        for source in [r#"match(a == "\u{ZZZ}")"#, r#"match(a == "\u{}")"#, r#"match(a == "\u{110000}")"#, r#"match(a == "\u{D800}")"#, r#"match(a == "\x4")"#, r#"match(a == "\x80")"#] {
+            let e = parse_err(source);
+            assert!(matches!(e.ty, ParseErrorT::InvalidEscape), "{}: {:?}", source, e);
+            assert_eq!(e.cursor, 12, "{}", source);
+        }
+    }
+
+    #[test]
+    fn token_stats_counts_by_category() {
+        assert_eq!(
+            token_stats(r#"match(age > 18 & name == "bo" | flag == true).limit(5)"#),
+            TokenStats { literals: 3, strings: 1, numbers: 2, bools: 1, operators: 5, parens: 4, keywords: 2, punctuation: 1, complete: true }
+        );
+        assert_eq!(token_stats(r#"match(a == "open"#), TokenStats::default());
+    }
+
+    #[test]
+    fn array_length_comparisons() {
+        assert_eq!(mql("match(tags.length > 3)"), pipeline(r#"{ $match: { $expr: { $gt: [{ $size: "$tags" }, 3] } } }"#));
+        assert_eq!(mql("match(3 < tags.length)"), pipeline(r#"{ $match: { $expr: { $gt: [{ $size: "$tags" }, 3] } } }"#));
+        assert_eq!(mql("match(tags.length == 0)"), pipeline("{ $match: { tags: { $size: 0 } } }"));
+        assert_eq!(mql("match(tags.length != 2)"), pipeline("{ $match: { tags: { $not: { $size: 2 } } } }"));
+        assert_eq!(mql("match(tags.length == $n)"), pipeline(r#"{ $match: { $expr: { $eq: [{ $size: "$tags" }, "$n"] } } }"#));
+        // `$size` refuses anything but a whole count, so these can only be compared in `$expr`
+        assert_eq!(mql("match(tags.length == 1.5)"), pipeline(r#"{ $match: { $expr: { $eq: [{ $size: "$tags" }, 1.5] } } }"#));
+        assert_eq!(mql(r#"match(tags.length == "x")"#), pipeline(r#"{ $match: { $expr: { $eq: [{ $size: "$tags" }, "x"] } } }"#));
+    }
+
+    #[test]
+    fn operators_display_as_source() {
+        let comparators = [(Comparator::GTE, ">="), (Comparator::GT, ">"), (Comparator::EQ, "=="), (Comparator::NEQ, "!="), (Comparator::LT, "<"), (Comparator::LTE, "<=")];
+        for (op, symbol) in comparators {
+            assert_eq!(op.to_string(), symbol);
+        }
+        for (op, symbol) in [(ConditionalOperator::AND, "&"), (ConditionalOperator::OR, "|"), (ConditionalOperator::NOR, "nor")] {
+            assert_eq!(op.to_string(), symbol);
+        }
+    }
+
+    #[test]
+    fn conditions_parse_without_a_match() {
+        assert_eq!(parse_condition_str("age > 18").unwrap(), ASTNode::condition("age", Comparator::GT, 18));
+        assert_eq!(parse_condition_str("(age > 18)").unwrap(), ASTNode::condition("age", Comparator::GT, 18));
+        assert_eq!(
+            parse_condition_str("age > 18 & branch == CSE").unwrap(),
+            ASTNode::and(vec![ASTNode::condition("age", Comparator::GT, 18), ASTNode::condition("branch", Comparator::EQ, ASTNode::Literal("CSE".to_string()))])
+        );
+        for (source, cursor) in [("age > 18)", 8), ("match(a == 1)", 0)] {
+            let e = parse_condition_str(source).unwrap_err();
+            assert!(matches!(e.ty, ParseErrorT::Unexpected), "{}: {:?}", source, e);
+            assert_eq!(e.cursor, cursor, "{}", source);
+        }
+        assert!(matches!(parse_condition_str("").unwrap_err().ty, ParseErrorT::EndOfTokenStream));
+    }
+
+    #[test]
+    fn number_literal_length_limit() {
+        let opts = ParseOptions::default().max_number_literal_digits(5);
+        assert!(build_with("match(a == 12345)", opts.clone()).is_ok());
+        for source in ["match(a == 123456)", "match(a == 1.2345)"] {
+            let e = build_with(source, opts.clone()).unwrap_err();
+            assert!(matches!(e.ty, ParseErrorT::NumberTooLong), "{}: {:?}", source, e);
+            assert_eq!(e.cursor, 11, "{}", source);
+        }
+        assert!(build_with(&format!("match(a == {})", "9".repeat(300)), ParseOptions::default()).is_ok());
+        let e = build_with(&format!("match(a == {})", "9".repeat(400)), ParseOptions::default()).unwrap_err();
+        assert!(matches!(e.ty, ParseErrorT::InvalidNumber));
+        assert_eq!(e.cursor, 11);
+    }
+
+    #[test]
+    fn different_stages_chain_in_order() {
+        let mut m = MonGod::new("match(a == 1).sort(b asc).limit(10)".to_string());
+        let kinds: Vec<ASTNodeKind> = m.build_ast().unwrap().iter().map(|stage| stage.kind()).collect();
+        assert_eq!(kinds, [ASTNodeKind::Match, ASTNodeKind::Sort, ASTNodeKind::Limit]);
+        assert_eq!(
+            mql("match(a == 1).sort(b asc).limit(10)"),
+            pipeline("{ $match: { a: { $eq: 1 } } },{ $sort: { b: 1 } },{ $limit: 10 }")
+        );
+        assert_eq!(
+            mql("limit(10).set(x: 1).unset(y).match(a == 1)"),
+            pipeline(r#"{ $limit: 10 },{ $set: { x: 1 } },{ $unset: "y" },{ $match: { a: { $eq: 1 } } }"#)
+        );
+    }
+
+    #[test]
+    fn diagnostics_label_the_offending_source() {
+        let source = "set(total price)";
+        assert_eq!(
+            format_diagnostic(source, &parse_err(source)),
+            "error: expected `:` after field name\n  --> line 1, column 11\n  |\n1 | set(total price)\n  |           ^^^^^ expected `:` here\n"
+        );
+        let source = "match(a == 1 &\n  b == )";
+        assert_eq!(
+            format_diagnostic(source, &parse_err(source)),
+            "error: comparator has nothing on its right\n  --> line 2, column 5\n  |\n2 |   b == )\n  |     ^^ expected a value after this\n"
+        );
+        assert_eq!(
+            format_error(source, &parse_err(source)),
+            "ParseError::MissingRightOperand\n   --> line 2, column 5\n   |\n  2|   b == )\n   |     ^\n"
+        );
+    }
+
+    #[test]
+    fn numeric_path_segments() {
+        assert_eq!(mql("match(items.0.price > 10)"), pipeline(r#"{ $match: { "items.0.price": { $gt: 10 } } }"#));
+        assert_eq!(mql("sort(items.12 desc)"), pipeline(r#"{ $sort: { "items.12": -1 } }"#));
+        for source in ["match(items.01.price > 10)", "match(items.0a.price > 10)", "match(items.$.price > 10)"] {
+            let e = parse_err(source);
+            assert!(matches!(e.ty, ParseErrorT::InvalidFieldName), "{}: {:?}", source, e);
+            assert_eq!(e.cursor, 12, "{}", source);
+        }
+    }
+
+    #[test]
+    fn source_is_the_input() {
+        let source = "match(a == 1) // one\n.limit(5)";
+        let mut m = MonGod::new(source.to_string());
+        assert_eq!(m.source(), source);
+        m.build().unwrap();
+        assert_eq!(m.source(), source);
+        assert_eq!(MonGod::from_ast(vec![ASTNode::limit(5)]).source(), "limit(5)");
+    }
+
+    #[test]
+    fn kind_of_every_node() {
+        let s = || "a".to_string();
+        let b = |node: ASTNode| Box::new(node);
+        let nodes = [
+            (ASTNode::Literal(s()), ASTNodeKind::Literal),
+            (ASTNode::Length(s()), ASTNodeKind::Length),
+            (ASTNode::Str(s()), ASTNodeKind::Str),
+            (ASTNode::Number(1.0), ASTNodeKind::Number),
+            (ASTNode::Bool(true), ASTNodeKind::Bool),
+            (ASTNode::Null, ASTNodeKind::Null),
+            (ASTNode::Param(0), ASTNodeKind::Param),
+            (ASTNode::condition("a", Comparator::EQ, 1), ASTNodeKind::Condition),
+            (ASTNode::and(vec![]), ASTNodeKind::ConditionalOperator),
+            (ASTNode::Arithmetic { op: ArithmeticOperator::ADD, left: b(ASTNode::Number(1.0)), right: b(ASTNode::Number(2.0)) }, ASTNodeKind::Arithmetic),
+            (ASTNode::Cond { condition: b(ASTNode::Bool(true)), then: b(ASTNode::Number(1.0)), otherwise: b(ASTNode::Number(2.0)) }, ASTNodeKind::Cond),
+            (ASTNode::Text { search: s(), language: None, case_sensitive: None }, ASTNodeKind::Text),
+            (ASTNode::All { field: s(), values: vec![] }, ASTNodeKind::All),
+            (ASTNode::ElemMatch(b(ASTNode::Bool(true))), ASTNodeKind::ElemMatch),
+            (ASTNode::In { field: s(), values: vec![] }, ASTNodeKind::In),
+            (ASTNode::HasValue(s()), ASTNodeKind::HasValue),
+            (ASTNode::Matches { field: s(), pattern: b(ASTNode::Str(s())) }, ASTNodeKind::Matches),
+            (ASTNode::Type { field: s(), types: vec![] }, ASTNodeKind::Type),
+            (ASTNode::Comment { condition: b(ASTNode::Bool(true)), comment: s() }, ASTNodeKind::Comment),
+            (ASTNode::match_(ASTNode::Bool(true)), ASTNodeKind::Match),
+            (ASTNode::set(vec![]), ASTNodeKind::Set),
+            (ASTNode::Rename { from: s(), to: s() }, ASTNodeKind::Rename),
+            (ASTNode::Unset(vec![]), ASTNodeKind::Unset),
+            (ASTNode::sort(vec![]), ASTNodeKind::Sort),
+            (ASTNode::limit(1), ASTNodeKind::Limit),
+            (ASTNode::sample(1), ASTNodeKind::Sample),
+            (ASTNode::Require(vec![]), ASTNodeKind::Require),
+            (ASTNode::project(vec![]), ASTNodeKind::Project),
+            (ASTNode::Slice { skip: None, count: 1 }, ASTNodeKind::Slice),
+            (ASTNode::Regex { pattern: s(), options: String::new() }, ASTNodeKind::Regex),
+            (ASTNode::facet(vec![]), ASTNodeKind::Facet),
+            (ASTNode::Lookup { from: s(), local: s(), foreign: s(), as_: s() }, ASTNodeKind::Lookup),
+            (ASTNode::group_by(vec![], vec![]), ASTNodeKind::Group),
+            (ASTNode::accumulator(AccumulatorOperator::SUM, 1), ASTNodeKind::Accumulator),
+            (ASTNode::Unexpected, ASTNodeKind::Unexpected),
+        ];
+        for (node, kind) in nodes {
+            assert_eq!(node.kind(), kind, "{:?}", node);
+        }
+    }
+
+    #[test]
+    fn all_of_elem_matches() {
+        assert_eq!(
+            mql("match(items all [elem_match((qty > 5)), elem_match((size == M & qty < 2))])"),
+            pipeline(r#"{ $match: { items: { $all: [{ $elemMatch: { qty: { $gt: 5 } } }, { $elemMatch: { $and: [{ size: { $eq: "M" } }, { qty: { $lt: 2 } }] } }] } } }"#)
+        );
+        assert_eq!(
+            mql("match(items all [a, elem_match(1 < qty < 5)]).limit(2)"),
+            pipeline(r#"{ $match: { items: { $all: ["a", { $elemMatch: { $and: [{ qty: { $gt: 1 } }, { qty: { $lt: 5 } }] } }] } } },{ $limit: 2 }"#)
+        );
+        assert_eq!(
+            mql("match(tags all [a, b] & n == 1)"),
+            pipeline(r#"{ $match: { $and: [{ tags: { $all: ["a", "b"] } }, { n: { $eq: 1 } }] } }"#)
+        );
+    }
+
+    #[test]
+    fn carets_line_up_under_tabs() {
+        let source = "match(a == 1 &\n\t\tb = 2)";
+        let e = parse_err(source);
+        assert_eq!(format_error(source, &e), "ParseError::UnexpectedCharacter\n   --> line 2, column 5\n   |\n  2| \t\tb = 2)\n   | \t\t  ^\n");
+        assert!(format_diagnostic(source, &e).ends_with("2 | \t\tb = 2)\n  | \t\t  ^ not part of the language\n"));
+    }
+
+    #[test]
+    fn pipeline_json_is_json() {
+        let sources = [
+            r#"match(age > 18 & (name == "he said \"hi\"\n" | tags all [a, elem_match((qty > 5))]))"#,
+            r#"match(a == "\x01\t\\").sort(age desc).limit(5)"#,
+            "set(total: if (a > 1) then price * 2 else 0).project(total: 1, _id: 0)",
+            "group([branch, year], n: sum(1), names: push(name)).facet(top: [sort(n desc).limit(3)])",
+            "match(`first name` == 1.5 & tags.length > 2 & a == $b)",
+        ];
+        for source in sources {
+            let json = built(source).to_pipeline_json_string();
+            let parsed: serde_json::Value = serde_json::from_str(&json).unwrap_or_else(|e| panic!("{}: {}\n{}", source, e, json));
+            assert!(parsed.is_array(), "{}", source);
+        }
+        let json: serde_json::Value = serde_json::from_str(&built(r#"match(a == "x\"y")"#).to_pipeline_json_string()).unwrap();
+        assert_eq!(json, serde_json::json!([{ "$match": { "a": { "$eq": "x\"y" } } }]));
+    }
+
+    #[test]
+    fn nor_groups() {
+        assert_eq!(mql("match(nor((a==1)(b==2)))"), pipeline("{ $match: { $nor: [{ a: { $eq: 1 } }, { b: { $eq: 2 } }] } }"));
+        assert_eq!(mql("match(nor((a==1)))"), pipeline("{ $match: { $nor: [{ a: { $eq: 1 } }] } }"));
+        assert_eq!(
+            mql("match(a == 1 & nor((b==2)(c==3 & d==4)))"),
+            pipeline("{ $match: { $and: [{ a: { $eq: 1 } }, { $nor: [{ b: { $eq: 2 } }, { $and: [{ c: { $eq: 3 } }, { d: { $eq: 4 } }] }] }] } }")
+        );
+    }
+
+    #[test]
+    fn groups_render_flat_and_nest_with_balanced_parens() {
+        assert_eq!(
+            mql("match(&((a==1)(b==2)(c==3)))"),
+            pipeline("{ $match: { $and: [{ a: { $eq: 1 } }, { b: { $eq: 2 } }, { c: { $eq: 3 } }] } }")
+        );
+        let nested = "match(&((a == 1)(&((b == 2)(c == 3)))))";
+        assert_eq!(
+            mql(nested),
+            pipeline("{ $match: { $and: [{ a: { $eq: 1 } }, { $and: [{ b: { $eq: 2 } }, { c: { $eq: 3 } }] }] } }")
+        );
+        assert_eq!(
+            mql("match(nor((a==1)(nor((b==2)(c==3)))))"),
+            pipeline("{ $match: { $nor: [{ a: { $eq: 1 } }, { $nor: [{ b: { $eq: 2 } }, { c: { $eq: 3 } }] }] } }")
+        );
+        assert_eq!(optimized(nested, &no_rewrites().flatten_groups(true)), mql("match(&((a==1)(b==2)(c==3)))"));
+        // the dsl printed back nests the same way, and parses to the same ast
+        let m = built(nested);
+        assert_eq!(built(&m.to_dsl()).ast, m.ast);
+        // a nested group's `(` has to be closed like any other
+        let e = parse_err("match(|((a == 1)(&((b == 2)(c == 3))))");
+        assert!(matches!(e.ty, ParseErrorT::MissingCloseParen));
+    }
+
+    #[test]
+    fn pipelines_can_start_with_any_stage() {
+        assert_eq!(mql("sort(a asc).match(a == 1)"), pipeline("{ $sort: { a: 1 } },{ $match: { a: { $eq: 1 } } }"));
+        assert_eq!(mql("sample(3).unset(a)"), pipeline(r#"{ $sample: { size: 3 } },{ $unset: "a" }"#));
+        for source in ["a == 1", "(a == 1)", "foo(a == 1)", ".limit(5)"] {
+            assert!(matches!(parse_err(source).ty, ParseErrorT::Unexpected), "{}", source);
+        }
+    }
+
+    #[test]
+    fn fuzz_crashes_are_errors() {
+        let deep = format!("match({}", "(".repeat(200));
+        // every operator nests what's before it one deeper, the 128th is one too many
+        let long_sum = format!("set(x: 1{})", " + 1".repeat(5000));
+        let long_product = format!("match(a == 1{})", " * 1".repeat(5000));
+        let crashes = [
+            ("match((a > 1.2.3))", ParseErrorT::InvalidNumber, 11),
+            ("match((a == é))", ParseErrorT::UnexpectedCharacter, 12),
+            ("=é// ", ParseErrorT::UnexpectedCharacter, 0),
+            ("match(true)", ParseErrorT::MissingComparator, 10),
+            (deep.as_str(), ParseErrorT::TooDeeplyNested, 133),
+            (long_sum.as_str(), ParseErrorT::TooDeeplyNested, 9 + 4 * 127),
+            (long_product.as_str(), ParseErrorT::TooDeeplyNested, 13 + 4 * 127),
+        ];
+        for (source, ty, cursor) in crashes {
+            let e = parse_err(source);
+            assert_eq!(std::mem::discriminant(&e.ty), std::mem::discriminant(&ty), "{}: {:?}", source, e);
+            assert_eq!(e.cursor, cursor, "{}", source);
+            assert!(format_error(source, &e).contains(&format!("ParseError::{:?}", ty)));
+        }
+        // long chains are fine as long as each expression stays under the limit
+        let many_sums = format!("set({})", (0..100).map(|i| format!("x{}: 1{}", i, " + 1".repeat(100))).collect::<Vec<_>>().join(", "));
+        built(&many_sums);
+        built(&format!("match(a{} == 1{})", " - 1".repeat(100), " / 2".repeat(100)));
+    }
+
+    /// xorshift, so the round trip below sees the same "random" asts every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+
+        fn pick<T: Clone>(&mut self, items: &[T]) -> T {
+            items[self.below(items.len() as u64) as usize].clone()
+        }
+    }
+
+    fn gen_field(r: &mut Rng) -> String {
+        r.pick(&["a", "b.c", "items.0.price", "x_y", "`first name`"]).trim_matches('`').to_string()
+    }
+
+    fn gen_value(r: &mut Rng) -> ASTNode {
+        match r.below(4) {
+            0 => ASTNode::Literal(r.pick(&["CSE", "ece", "v.w"]).to_string()),
+            1 => ASTNode::Str(r.pick(&["", "he said \"hi\"", "tab\tnew\nline\\", "\u{1}ctl", "é😀"]).to_string()),
+            2 => ASTNode::Number(r.pick(&[0.0, 1.0, 2.5, 0.1, 1e-7, 12345678.0])),
+            _ => ASTNode::Bool(r.below(2) == 0),
+        }
+    }
+
+    fn gen_condition(r: &mut Rng, depth: u32, text_ok: &mut bool) -> ASTNode {
+        let comparators = [Comparator::GTE, Comparator::GT, Comparator::EQ, Comparator::NEQ, Comparator::LT, Comparator::LTE];
+        match r.below(if depth == 0 { 6 } else { 9 }) {
+            0 | 1 => {
+                let left = if r.below(4) == 0 { ASTNode::Length(gen_field(r)) } else { ASTNode::Literal(gen_field(r)) };
+                ASTNode::Condition { op: r.pick(&comparators), left: Box::new(left), right: Box::new(gen_value(r)) }
+            }
+            2 if *text_ok => {
+                *text_ok = false;
+                let language = (r.below(2) == 0).then(|| "es".to_string());
+                let case_sensitive = (r.below(2) == 0).then_some(true);
+                ASTNode::Text { search: "coffee \"shop\"".to_string(), language, case_sensitive }
+            }
+            2 => ASTNode::In { field: gen_field(r), values: (0..1 + r.below(3)).map(|_| if r.below(4) == 0 { ASTNode::Null } else { gen_value(r) }).collect() },
+            3 => ASTNode::HasValue(gen_field(r)),
+            4 => ASTNode::Type { field: gen_field(r), types: (0..1 + r.below(2)).map(|_| r.pick(&["string", "int", "array"]).to_string()).collect() },
+            5 => {
+                let pattern = match r.below(2) {
+                    0 => ASTNode::Str("^a.b".to_string()),
+                    _ => ASTNode::Regex { pattern: "^a".to_string(), options: r.pick(&["i", "im"]).to_string() },
+                };
+                ASTNode::Matches { field: gen_field(r), pattern: Box::new(pattern) }
+            }
+            6 => {
+                let values = (0..r.below(3))
+                    .map(|_| if r.below(2) == 0 { ASTNode::ElemMatch(Box::new(gen_condition(r, depth - 1, &mut false))) } else { gen_value(r) })
+                    .collect();
+                ASTNode::All { field: gen_field(r), values }
+            }
+            _ => {
+                let op = r.pick(&[ConditionalOperator::AND, ConditionalOperator::OR, ConditionalOperator::NOR]);
+                let conditions = (0..1 + r.below(3)).map(|_| Box::new(gen_condition(r, depth - 1, text_ok))).collect();
+                ASTNode::ConditionalOperator { op, conditions }
+            }
+        }
+    }
+
+    fn gen_expression(r: &mut Rng, depth: u32) -> ASTNode {
+        match r.below(if depth == 0 { 2 } else { 5 }) {
+            0 => ASTNode::Literal(gen_field(r)),
+            1 => gen_value(r),
+            2 | 3 => {
+                let op = r.pick(&[ArithmeticOperator::ADD, ArithmeticOperator::SUB, ArithmeticOperator::MUL, ArithmeticOperator::DIV]);
+                ASTNode::Arithmetic { op, left: Box::new(gen_expression(r, depth - 1)), right: Box::new(gen_expression(r, depth - 1)) }
+            }
+            _ => {
+                let condition = ASTNode::Condition { op: Comparator::GT, left: Box::new(ASTNode::Literal(gen_field(r))), right: Box::new(gen_value(r)) };
+                ASTNode::Cond { condition: Box::new(condition), then: Box::new(gen_expression(r, depth - 1)), otherwise: Box::new(gen_expression(r, depth - 1)) }
+            }
+        }
+    }
+
+    fn gen_stage(r: &mut Rng) -> ASTNode {
+        let accumulators = [AccumulatorOperator::SUM, AccumulatorOperator::AVG, AccumulatorOperator::MIN, AccumulatorOperator::MAX, AccumulatorOperator::PUSH, AccumulatorOperator::ADDTOSET];
+        match r.below(9) {
+            0 | 1 => ASTNode::Match(Box::new(gen_condition(r, 3, &mut true))),
+            2 => ASTNode::Set((0..1 + r.below(3)).map(|_| (gen_field(r), gen_expression(r, 3))).collect()),
+            3 => ASTNode::Rename { from: gen_field(r), to: gen_field(r) },
+            4 => ASTNode::Unset((0..1 + r.below(3)).map(|_| gen_field(r)).collect()),
+            5 => ASTNode::Sort((0..1 + r.below(3)).map(|_| (gen_field(r), r.pick(&[SortOrder::ASC, SortOrder::DESC]))).collect()),
+            6 => ASTNode::Limit(1 + r.below(1000)),
+            7 => ASTNode::Sample(1 + r.below(1000)),
+            _ => {
+                let keys = ["branch", "year", "a.b"][..r.below(4) as usize].iter().map(|k| k.to_string()).collect();
+                let accumulators = ["n", "total", "names"][..1 + r.below(3) as usize]
+                    .iter()
+                    .map(|name| (name.to_string(), ASTNode::accumulator(r.pick(&accumulators), gen_expression(r, 1))))
+                    .collect();
+                ASTNode::Group { keys, accumulators }
+            }
+        }
+    }
+
+    #[test]
+    fn dsl_round_trips_through_the_parser() {
+        let mut r = Rng(0x9E3779B97F4A7C15);
+        for _ in 0..5000 {
+            let ast: Vec<ASTNode> = (0..1 + r.below(4)).map(|_| gen_stage(&mut r)).collect();
+            let dsl = MonGod::from_ast(ast.clone()).to_dsl();
+            let mut m = MonGod::new(dsl.clone());
+            match m.build() {
+                Ok(()) => assert_eq!(m.ast, ast, "{}", dsl),
+                Err(e) => panic!("{}\n{}", dsl, format_diagnostic(&dsl, &e)),
+            }
+        }
+    }
+
+    #[test]
+    fn field_to_field_comparisons_use_expr() {
+        for (symbol, op) in [("==", "$eq"), ("!=", "$ne"), (">", "$gt"), (">=", "$gte"), ("<", "$lt"), ("<=", "$lte")] {
+            assert_eq!(
+                mql(&format!("match(a {} $b.c)", symbol)),
+                pipeline(&format!(r#"{{ $match: {{ $expr: {{ {}: ["$a", "$b.c"] }} }} }}"#, op)),
+                "{}",
+                symbol
+            );
+        }
+        assert!(matches!(parse_err("match(5 < $b)").ty, ParseErrorT::InvalidFieldName));
+    }
+
+    #[test]
+    fn stage_keyword_aliases() {
+        let opts = ParseOptions::default().alias("filter", "match").alias("take", "limit").alias("x", "nonsense");
+        let mut aliased = MonGod::with_options("filter(a == 1).take(2)".to_string(), opts.clone());
+        aliased.build().unwrap();
+        assert_eq!(aliased.ast, built("match(a == 1).limit(2)").ast);
+        assert!(matches!(parse_err("filter(a == 1)").ty, ParseErrorT::Unexpected));
+        // an alias is a keyword now, unless it's quoted
+        assert!(build_with("match(filter == 1)", opts.clone()).is_err());
+        assert!(build_with("match(`filter` == 1)", opts.clone()).is_ok());
+        assert!(build_with("match(x == 1)", opts).is_ok());
+    }
+
+    #[test]
+    fn each_stage_renders_on_its_own() {
+        let s = |v: &str| v.to_string();
+        let stages = [
+            (ASTNode::match_(ASTNode::condition("a", Comparator::EQ, 1)), "{ $match: { a: { $eq: 1 } } }"),
+            (ASTNode::set(vec![("x", ASTNode::Number(1.0))]), "{ $set: { x: 1 } }"),
+            (ASTNode::Rename { from: s("a"), to: s("b") }, r#"{ $addFields: { b: "$a" } },{ $project: { a: 0 } }"#),
+            (ASTNode::Unset(vec![s("a")]), r#"{ $unset: "a" }"#),
+            (ASTNode::sort(vec![("a", SortOrder::ASC), ("b", SortOrder::DESC)]), "{ $sort: { a: 1, b: -1 } }"),
+            (ASTNode::limit(5), "{ $limit: 5 }"),
+            (ASTNode::sample(5), "{ $sample: { size: 5 } }"),
+            (ASTNode::Require(vec![s("name"), s("email")]), r#"{ $match: { $jsonSchema: { required: ["name", "email"] } } }"#),
+            (ASTNode::project(vec![("email", ASTNode::Number(1.0)), ("full", ASTNode::Literal(s("name")))]), r#"{ $project: { email: 1, full: "$name" } }"#),
+            (ASTNode::facet(vec![("top", vec![ASTNode::limit(3)])]), "{ $facet: { top: [{ $limit: 3 }] } }"),
+            (
+                ASTNode::Lookup { from: s("orders"), local: s("_id"), foreign: s("userId"), as_: s("orders") },
+                r#"{ $lookup: { from: "orders", localField: "_id", foreignField: "userId", as: "orders" } }"#,
+            ),
+            (ASTNode::group_by(vec!["branch"], vec![("n", ASTNode::accumulator(AccumulatorOperator::SUM, 1))]), r#"{ $group: { _id: "$branch", n: { $sum: 1 } } }"#),
+        ];
+        for (stage, expected) in stages {
+            assert_eq!(MonGod::from_ast(vec![stage.clone()]).ast2mql(), pipeline(expected), "{:?}", stage);
+        }
+    }
+
+    #[test]
+    fn unterminated_backtick_names() {
+        let e = parse_err("match(`match == 1)");
+        assert!(matches!(e.ty, ParseErrorT::UnterminatedIdentifier));
+        assert_eq!(e.cursor, 6);
+        assert_eq!(mql("match(`in` in [1])"), pipeline("{ $match: { in: { $in: [1] } } }"));
+    }
+
+    #[test]
+    fn empty_array_sugar() {
+        assert_eq!(mql("match(tags is empty)"), pipeline("{ $match: { tags: { $size: 0 } } }"));
+        assert_eq!(mql("match(tags is not empty)"), pipeline("{ $match: { tags: { $not: { $size: 0 } } } }"));
+        assert_eq!(built("match(tags is empty)").ast, built("match(tags.length == 0)").ast);
+        let e = parse_err("match(tags is full)");
+        assert!(matches!(e.ty, ParseErrorT::Unexpected));
+        assert_eq!(e.cursor, 14);
+    }
+
+    #[test]
+    fn semicolons_separate_pipelines() {
+        let m = built("match(a == 1);sort(b asc).limit(2);");
+        assert_eq!(m.pipelines.len(), 2);
+        assert_eq!(m.ast, m.pipelines[0]);
+        assert_eq!(
+            m.pipelines2mql(),
+            [pipeline("{ $match: { a: { $eq: 1 } } }"), pipeline("{ $sort: { b: 1 } },{ $limit: 2 }")]
+        );
+        assert_eq!(parse_err(";match(a == 1)").cursor, 0);
+        let e = parse_err("match(a == 1);;limit(1)");
+        assert!(matches!(e.ty, ParseErrorT::Unexpected));
+        assert_eq!(e.cursor, 14);
+    }
+
+    #[test]
+    fn operators_parse_from_source_symbols() {
+        for op in [Comparator::GTE, Comparator::GT, Comparator::EQ, Comparator::NEQ, Comparator::LT, Comparator::LTE] {
+            assert_eq!(op.to_string().parse::<Comparator>(), Ok(op));
+        }
+        for op in [ConditionalOperator::AND, ConditionalOperator::OR, ConditionalOperator::NOR] {
+            assert_eq!(op.to_string().parse::<ConditionalOperator>(), Ok(op));
+        }
+        assert_eq!("=>".parse::<Comparator>(), Err(UnknownOperator("=>".to_string())));
+        assert_eq!("&&".parse::<ConditionalOperator>(), Err(UnknownOperator("&&".to_string())));
+        assert_eq!("$gt".parse::<Comparator>(), Err(UnknownOperator("$gt".to_string())));
+    }
+
+    #[test]
+    fn builders_make_renderable_queries() {
+        let query = MonGod::from_ast(vec![
+            ASTNode::match_(ASTNode::and(vec![
+                ASTNode::condition("age", Comparator::GTE, 18),
+                ASTNode::or(vec![ASTNode::condition("branch", Comparator::EQ, "CSE"), ASTNode::condition("active", Comparator::EQ, true)]),
+                ASTNode::condition("spent", Comparator::GT, ASTNode::field("$budget")),
+            ])),
+            ASTNode::sort(vec![("age", SortOrder::DESC)]),
+            ASTNode::limit(10),
+        ]);
+        assert_eq!(
+            query.ast2mql(),
+            pipeline(concat!(
+                r#"{ $match: { $and: [{ age: { $gte: 18 } }, { $or: [{ branch: { $eq: "CSE" } }, { active: { $eq: true } }] }, "#,
+                r#"{ $expr: { $gt: ["$spent", "$budget"] } }] } },{ $sort: { age: -1 } },{ $limit: 10 }"#
+            ))
+        );
+        assert_eq!(built(&query.to_dsl()).ast, query.ast);
+    }
+
+    #[test]
+    fn validate_hand_built_asts() {
+        let valid = MonGod::from_ast(vec![ASTNode::match_(ASTNode::condition("a", Comparator::EQ, 1)), ASTNode::limit(1)]);
+        assert_eq!(valid.validate_mql(), Ok(()));
+        assert!(valid.is_valid_mql());
+        // two constants were a `NotAField` until `fold_constants` started leaving them
+        // behind, they're a `$expr` now
+        let numbers = ASTNode::Condition { op: Comparator::EQ, left: Box::new(ASTNode::Number(5.0)), right: Box::new(ASTNode::Number(6.0)) };
+        let numbers = MonGod::from_ast(vec![ASTNode::match_(numbers)]);
+        assert_eq!(numbers.validate_mql(), Ok(()));
+        assert_eq!(numbers.ast2mql(), pipeline("{ $match: { $expr: { $eq: [5, 6] } } }"));
+        let null_field = ASTNode::Condition { op: Comparator::EQ, left: Box::new(ASTNode::Null), right: Box::new(ASTNode::Number(6.0)) };
+        let invalid = [
+            (vec![ASTNode::match_(null_field)], MqlError::NotAField(ASTNodeKind::Null)),
+            (vec![ASTNode::condition("a", Comparator::EQ, 1)], MqlError::NotAStage(ASTNodeKind::Condition)),
+            (vec![ASTNode::match_(ASTNode::limit(1))], MqlError::NotACondition(ASTNodeKind::Limit)),
+            (vec![ASTNode::match_(ASTNode::and(vec![]))], MqlError::EmptyGroup(ConditionalOperator::AND)),
+            (vec![ASTNode::match_(ASTNode::condition("a", Comparator::EQ, ASTNode::limit(1)))], MqlError::NotAValue(ASTNodeKind::Limit)),
+            (vec![ASTNode::set(vec![])], MqlError::EmptyStage(ASTNodeKind::Set)),
+        ];
+        for (ast, error) in invalid {
+            let m = MonGod::from_ast(ast);
+            assert_eq!(m.validate_mql(), Err(error));
+            assert!(!m.is_valid_mql());
+        }
+    }
+
+    #[test]
+    fn in_ranges_expand() {
+        assert_eq!(mql("match(id in range(1, 5))"), pipeline("{ $match: { id: { $in: [1, 2, 3, 4, 5] } } }"));
+        assert_eq!(mql("match(id in range(3, 3))"), pipeline("{ $match: { id: { $in: [3] } } }"));
+        let capped = ParseOptions::default().max_range_len(4);
+        assert!(build_with("match(id in range(1, 4))", capped.clone()).is_ok());
+        let e = build_with("match(id in range(1, 5))", capped).unwrap_err();
+        assert!(matches!(e.ty, ParseErrorT::RangeTooLarge));
+        assert_eq!(e.cursor, 12);
+        assert!(matches!(parse_err("match(id in range(0, 20000))").ty, ParseErrorT::RangeTooLarge));
+        let e = parse_err("match(id in range(5, 1))");
+        assert!(matches!(e.ty, ParseErrorT::InvalidRange));
+        assert_eq!(e.cursor, 12);
+        assert!(matches!(parse_err("match(id in range(1.5, 3))").ty, ParseErrorT::InvalidRange));
+    }
+
+    #[test]
+    fn always_false_clauses_warn() {
+        let m = built("match(a == 1 & a == 2)");
+        assert_eq!(m.warnings().len(), 1);
+        assert!(matches!(&m.warnings()[0].ty, WarningT::AlwaysFalse(clause) if clause == "&((a == 1)(a == 2))"));
+        assert_eq!(m.warnings()[0].to_string(), "stage 1: `&((a == 1)(a == 2))` is always false");
+
+        let constant = built("match(5 == 6)");
+        assert!(matches!(&constant.warnings()[0].ty, WarningT::AlwaysFalse(clause) if clause == "5 == 6"));
+
+        for fine in ["match(a == 1 | a == 2)", "match(a == 1 & a == 1)", "match(a == 1 & b == 2)", "match(a > 1 & a == 2)"] {
+            assert!(built(fine).warnings().is_empty(), "{}", fine);
+        }
+        // still only a warning, the query builds
+        assert!(mql("match(a == 1 & a == 2)").contains("$match"));
+    }
+
+    #[test]
+    fn windowed_error_cuts_long_lines_around_the_caret() {
+        let source = format!("match({} == 1 @ {} == 2)", "a".repeat(240), "b".repeat(240));
+        assert_eq!(source.len(), 500);
+        let e = parse_err(&source);
+        assert!(matches!(e.ty, ParseErrorT::UnexpectedCharacter));
+        assert_eq!(
+            format_error_windowed(&source, &e, 10),
+            "ParseError::UnexpectedCharacter\n   --> line 1, column 253\n   |\n  1| ...aaaa == 1 @ bbbbbbbbb...\n   |              ^\n"
+        );
+        // the unwindowed form still carries the whole line
+        assert!(format_error(&source, &e).contains(&source));
+
+        // multi-byte chars either side of the cut stay whole
+        let source = format!("match(é{} == 1)", "a".repeat(40));
+        let e = parse_err(&source);
+        assert_eq!(
+            format_error_windowed(&source, &e, 5),
+            "ParseError::UnexpectedCharacter\n   --> line 1, column 7\n   |\n  1| ...atch(éaaaaa...\n   |         ^\n"
+        );
+    }
+
+    #[test]
+    fn has_value_means_exists_and_not_null() {
+        assert_eq!(mql("match(a has value)"), pipeline("{ $match: { a: { $exists: true, $ne: null } } }"));
+        assert_eq!(
+            mql("match(a has value & b == 1)"),
+            pipeline("{ $match: { $and: [{ a: { $exists: true, $ne: null } }, { b: { $eq: 1 } }] } }")
+        );
+        let m = built("match(a has value)");
+        let json: serde_json::Value = serde_json::from_str(&m.to_pipeline_json_string()).unwrap();
+        assert_eq!(json, serde_json::json!([{ "$match": { "a": { "$exists": true, "$ne": null } } }]));
+        #[cfg(feature = "bson")]
+        assert_eq!(m.to_bson_pipeline(), vec![bson::doc! { "$match": { "a": { "$exists": true, "$ne": null } } }]);
+
+        assert!(matches!(parse_err("match(a has)").ty, ParseErrorT::Unexpected));
+        assert!(matches!(parse_err("match(a has values)").ty, ParseErrorT::Unexpected));
+    }
+
+    #[test]
+    fn operators_are_hash_map_keys() {
+        use std::collections::HashMap;
+        let comparators = [Comparator::GTE, Comparator::GT, Comparator::EQ, Comparator::NEQ, Comparator::LT, Comparator::LTE];
+        let counts: HashMap<Comparator, usize> = comparators.iter().enumerate().map(|(i, &op)| (op, i)).collect();
+        assert_eq!(counts.len(), comparators.len());
+        for (i, op) in comparators.iter().enumerate() {
+            assert_eq!(counts[op], i);
+        }
+
+        let mut groups = HashMap::new();
+        for op in [ConditionalOperator::AND, ConditionalOperator::OR, ConditionalOperator::NOR, ConditionalOperator::AND] {
+            *groups.entry(op).or_insert(0) += 1;
+        }
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups[&ConditionalOperator::AND], 2);
+        assert_eq!(groups[&ConditionalOperator::OR], 1);
+        assert_eq!(groups[&ConditionalOperator::NOR], 1);
+    }
+
+    #[cfg(feature = "bson")]
+    #[test]
+    fn bson_pipeline_types_values() {
+        assert_eq!(
+            built(r#"match(a == 5000000000 & b == 2.5 & c == true & d == "x" & e == 3)"#).to_bson_pipeline(),
+            vec![bson::doc! { "$match": { "$and": [
+                { "a": { "$eq": 5_000_000_000i64 } },
+                { "b": { "$eq": 2.5 } },
+                { "c": { "$eq": true } },
+                { "d": { "$eq": "x" } },
+                { "e": { "$eq": 3 } },
+            ] } }]
+        );
+        let pipeline = built(r#"match(a in [1, null, "x"]).limit(2)"#).to_bson_pipeline();
+        assert_eq!(pipeline[0], bson::doc! { "$match": { "a": { "$in": [1, bson::Bson::Null, "x"] } } });
+        assert_eq!(pipeline[0].get_document("$match").unwrap().get_document("a").unwrap().get_array("$in").unwrap()[0], bson::Bson::Int32(1));
+        assert_eq!(pipeline[1], bson::doc! { "$limit": 2i64 });
+    }
+
+    #[test]
+    fn commented_match() {
+        assert_eq!(mql(r#"match(a == 1).comment("why")"#), pipeline(r#"{ $match: { a: { $eq: 1 }, $comment: "why" } }"#));
+        assert_eq!(
+            mql(r#"match(a == 1 | b == 2).comment("say \"hi\"").limit(1)"#),
+            pipeline(r#"{ $match: { $or: [{ a: { $eq: 1 } }, { b: { $eq: 2 } }], $comment: "say \"hi\"" } },{ $limit: 1 }"#)
+        );
+        let json: serde_json::Value = serde_json::from_str(&built(r#"match(a == 1).comment("why")"#).to_pipeline_json_string()).unwrap();
+        assert_eq!(json, serde_json::json!([{ "$match": { "a": { "$eq": 1 }, "$comment": "why" } }]));
+        for bad in ["match(a == 1).comment(why)", "match(a == 1).comment(1)", "match(a == 1).comment()"] {
+            let e = parse_err(bad);
+            assert!(matches!(e.ty, ParseErrorT::InvalidComment), "{}", bad);
+            assert_eq!(e.cursor, 22, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn integral_floats_option() {
+        let m = built("match(a == 5 & b == 2.5 & c in [1, 2.5]).limit(3).set(x: y + 2)");
+        assert_eq!(
+            m.ast2mql_with(&MqlOptions::default()),
+            pipeline(r#"{ $match: { $and: [{ a: { $eq: 5 } }, { b: { $eq: 2.5 } }, { c: { $in: [1, 2.5] } }] } },{ $limit: 3 },{ $set: { x: { $add: ["$y", 2] } } }"#)
+        );
+        // counts like `$limit` are integers either way
+        assert_eq!(
+            m.ast2mql_with(&MqlOptions::default().integral_floats(true)),
+            pipeline(r#"{ $match: { $and: [{ a: { $eq: 5.0 } }, { b: { $eq: 2.5 } }, { c: { $in: [1.0, 2.5] } }] } },{ $limit: 3 },{ $set: { x: { $add: ["$y", 2.0] } } }"#)
+        );
+        assert_eq!(m.ast2mql_with(&MqlOptions::default()), m.ast2mql());
+    }
+
+    #[test]
+    fn regex_against_a_literal_or_a_field() {
+        assert_eq!(mql(r#"match(name matches "^a")"#), pipeline(r#"{ $match: { name: { $regex: "^a" } } }"#));
+        assert_eq!(
+            mql("match(name matches field(pattern))"),
+            pipeline(r#"{ $match: { $expr: { $regexMatch: { input: "$name", regex: "$pattern" } } } }"#)
+        );
+        assert_eq!(
+            mql(r#"match(name matches "^a" & x matches field(p.q))"#),
+            pipeline(r#"{ $match: { $and: [{ name: { $regex: "^a" } }, { $expr: { $regexMatch: { input: "$x", regex: "$p.q" } } }] } }"#)
+        );
+        for (bad, cursor) in [("match(name matches field(1))", 25), (r#"match(name matches field("p"))"#, 25), ("match(name matches field())", 25), ("match(1 matches field(p))", 6)] {
+            let e = parse_err(bad);
+            assert!(matches!(e.ty, ParseErrorT::InvalidFieldName), "{}", bad);
+            assert_eq!(e.cursor, cursor, "{}", bad);
+        }
+        assert!(matches!(parse_err("match(name matches field)").ty, ParseErrorT::MissingOpenParen));
+    }
+
+    #[test]
+    fn highlight_spans_and_kinds() {
+        use HighlightKind::*;
+        let source = "match(age >= 18 & name == \"x\") // adults\n.limit(2)";
+        let spans = highlight(source);
+        assert_eq!(
+            spans,
+            vec![
+                (0..5, Keyword), (5..6, Paren), (6..9, Literal), (10..12, Operator), (13..15, Number),
+                (16..17, Operator), (18..22, Literal), (23..25, Operator), (26..29, String), (29..30, Paren),
+                (31..40, Comment), (41..42, Punctuation), (42..47, Keyword), (47..48, Paren), (48..49, Number), (49..50, Paren),
+            ]
+        );
+        assert_eq!(&source[spans[10].0.clone()], "// adults");
+        assert_eq!(highlight("set(ok: true)")[4], (8..12, Bool));
+
+        // stops where lexing does
+        assert_eq!(highlight("match(a == 1 @ b)"), vec![(0..5, Keyword), (5..6, Paren), (6..7, Literal), (8..10, Operator), (11..12, Number)]);
+        assert_eq!(highlight("match(é == 1)"), vec![(0..5, Keyword), (5..6, Paren)]);
+        assert!(highlight("@").is_empty());
+    }
+
+    #[test]
+    fn type_takes_one_name_or_a_list() {
+        assert_eq!(mql("match(value type string)"), pipeline(r#"{ $match: { value: { $type: "string" } } }"#));
+        assert_eq!(mql("match(value type [string, int])"), pipeline(r#"{ $match: { value: { $type: ["string", "int"] } } }"#));
+        assert_eq!(
+            mql("match(value type [string, int] & a == 1)"),
+            pipeline(r#"{ $match: { $and: [{ value: { $type: ["string", "int"] } }, { a: { $eq: 1 } }] } }"#)
+        );
+        for (bad, cursor) in [
+            ("match(value type [string, bogus])", 26),
+            ("match(value type [])", 18),
+            ("match(value type [string,])", 25),
+            (r#"match(value type ["string"])"#, 18),
+            ("match(value type [1])", 18),
+        ] {
+            let e = parse_err(bad);
+            assert!(matches!(e.ty, ParseErrorT::UnknownType), "{}", bad);
+            assert_eq!(e.cursor, cursor, "{}", bad);
+        }
+        assert!(matches!(parse_err("match(value type [string int])").ty, ParseErrorT::UnmatchedBracket));
+    }
+
+    #[test]
+    fn pipeline_output_is_the_stages_in_order() {
+        assert_eq!(
+            mql("match(a == 1).set(t: if (a > 1) then b * 2 else 3).rename(a, b).unset(x, y).sort(q desc).limit(3)"),
+            pipeline(concat!(
+                "{ $match: { a: { $eq: 1 } } },",
+                r#"{ $set: { t: { $cond: [{ $gt: ["$a", 1] }, { $multiply: ["$b", 2] }, 3] } } },"#,
+                r#"{ $addFields: { b: "$a" } },{ $project: { a: 0 } },"#,
+                r#"{ $unset: ["x", "y"] },"#,
+                "{ $sort: { q: -1 } },",
+                "{ $limit: 3 }",
+            ))
+        );
+        // each stage renders the same inside a pipeline as on its own
+        let stages = vec![
+            ASTNode::match_(ASTNode::condition("a", Comparator::GT, 2)),
+            ASTNode::project(vec![("email", ASTNode::Number(1.0))]),
+            ASTNode::facet(vec![("top", vec![ASTNode::limit(3)])]),
+            ASTNode::group_by(vec!["branch"], vec![("n", ASTNode::accumulator(AccumulatorOperator::SUM, 1))]),
+            ASTNode::sample(5),
+        ];
+        let alone: Vec<String> = stages
+            .iter()
+            .map(|stage| {
+                let single = MonGod::from_ast(vec![stage.clone()]).ast2mql();
+                single["db.collection.aggregate([".len()..single.len() - "])".len()].to_string()
+            })
+            .collect();
+        assert_eq!(MonGod::from_ast(stages).ast2mql(), format!("db.collection.aggregate([{}])", alone.concat()));
+        assert_eq!(MonGod::from_ast(Vec::new()).ast2mql(), "db.collection.aggregate([])");
+    }
+
+    #[test]
+    fn commas_and_looser_than_infix_operators() {
+        let a = r#"{ a: { $eq: 1 } }"#;
+        let b = r#"{ b: { $eq: 2 } }"#;
+        let c = r#"{ c: { $eq: 3 } }"#;
+        let and_of = |parts: &[&str]| format!("{{ $and: [{}] }}", parts.join(", "));
+        let or_of = |parts: &[&str]| format!("{{ $or: [{}] }}", parts.join(", "));
+        for (source, expected) in [
+            ("match(a == 1, b == 2, c == 3)", and_of(&[a, b, c])),
+            ("match(a == 1, b == 2 | c == 3)", and_of(&[a, &or_of(&[b, c])])),
+            ("match(a == 1 | b == 2, c == 3)", and_of(&[&or_of(&[a, b]), c])),
+            ("match(a == 1 & b == 2, c == 3)", and_of(&[&and_of(&[a, b]), c])),
+            ("match(a == 1, (b == 2 | c == 3))", and_of(&[a, &or_of(&[b, c])])),
+            ("match((a == 1, b == 2), c == 3)", and_of(&[&and_of(&[a, b]), c])),
+            ("match(a == 1, |((b == 2)(c == 3)))", and_of(&[a, &or_of(&[b, c])])),
+        ] {
+            assert_eq!(mql(source), pipeline(&format!("{{ $match: {} }}", expected)), "{}", source);
+        }
+        for (bad, cursor) in [("match(a == 1,)", 13), ("match(, a == 1)", 6), ("match(a == 1,, b == 2)", 13), ("match(a == 1 & )", 15), ("match()", 6)] {
+            let e = parse_err(bad);
+            assert!(matches!(e.ty, ParseErrorT::Unexpected), "{}", bad);
+            assert_eq!(e.cursor, cursor, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn comparator_without_a_left_operand() {
+        for (source, cursor) in [("match(== 1)", 6), ("match((== 1))", 7), ("match(a == 1 & == 2)", 15)] {
+            let e = parse_err(source);
+            assert!(matches!(e.ty, ParseErrorT::MissingLeftOperand), "{}: {:?}", source, e);
+            assert_eq!(e.cursor, cursor, "{}", source);
+        }
+    }
+
+    #[test]
+    fn comparator_without_a_right_operand() {
+        for (source, cursor) in [("match(a ==)", 8), ("match(a == )", 8), ("match((a ==))", 9), ("match(a == 1 & b >)", 17), ("match(a == 1 & a ==", 17)] {
+            let e = parse_err(source);
+            assert!(matches!(e.ty, ParseErrorT::MissingRightOperand), "{}: {:?}", source, e);
+            assert_eq!(e.cursor, cursor, "{}", source);
+        }
+    }
+
+    #[test]
+    fn constant_children_decide_or_drop_out_of_groups() {
+        let constant = |a: f64, b: f64| ASTNode::Condition { op: Comparator::EQ, left: Box::new(ASTNode::Number(a)), right: Box::new(ASTNode::Number(b)) };
+        let a = || ASTNode::condition("a", Comparator::EQ, 1);
+        let b = || ASTNode::condition("b", Comparator::EQ, 2);
+        let folded = |condition: ASTNode| {
+            let mut m = MonGod::from_ast(vec![ASTNode::match_(condition)]);
+            m.optimize_with(&no_rewrites().fold_constants(true));
+            assert_eq!(m.validate_mql(), Ok(()));
+            m.ast2mql()
+        };
+        // a true `|` matches everything, so the match goes
+        assert_eq!(folded(ASTNode::or(vec![a(), constant(1.0, 1.0)])), "db.collection.aggregate([])");
+        // a false `&` matches nothing
+        assert_eq!(folded(ASTNode::and(vec![a(), constant(1.0, 2.0)])), pipeline("{ $match: { $expr: { $eq: [1, 2] } } }"));
+        // the other way round the constant is dropped
+        assert_eq!(folded(ASTNode::or(vec![a(), constant(1.0, 2.0)])), pipeline("{ $match: { $or: [{ a: { $eq: 1 } }] } }"));
+        assert_eq!(
+            folded(ASTNode::and(vec![a(), constant(1.0, 1.0), b()])),
+            pipeline("{ $match: { $and: [{ a: { $eq: 1 } }, { b: { $eq: 2 } }] } }")
+        );
+        assert_eq!(folded(ASTNode::and(vec![a(), ASTNode::or(vec![b(), constant(3.0, 3.0)])])), pipeline("{ $match: { $and: [{ a: { $eq: 1 } }] } }"));
+        // `nor` is left alone
+        let nor = ASTNode::ConditionalOperator { op: ConditionalOperator::NOR, conditions: vec![Box::new(a()), Box::new(constant(1.0, 1.0))] };
+        assert_eq!(folded(nor), pipeline("{ $match: { $nor: [{ a: { $eq: 1 } }, { $expr: { $eq: [1, 1] } }] } }"));
+        // off, nothing changes
+        let mut m = MonGod::from_ast(vec![ASTNode::match_(ASTNode::and(vec![a(), constant(1.0, 2.0)]))]);
+        m.optimize_with(&no_rewrites());
+        assert_eq!(m.ast2mql(), pipeline("{ $match: { $and: [{ a: { $eq: 1 } }, { $expr: { $eq: [1, 2] } }] } }"));
+    }
+
+    #[test]
+    fn between_bounds_follow_interval_notation() {
+        for (brackets, lower, upper) in [("[18, 65]", "$gte", "$lte"), ("(18, 65)", "$gt", "$lt"), ("[18, 65)", "$gte", "$lt"), ("(18, 65]", "$gt", "$lte")] {
+            assert_eq!(
+                mql(&format!("match(age between {})", brackets)),
+                pipeline(&format!("{{ $match: {{ $and: [{{ age: {{ {}: 18 }} }}, {{ age: {{ {}: 65 }} }}] }} }}", lower, upper)),
+                "{}",
+                brackets
+            );
+        }
+        assert_eq!(mql("match(age between [5, 5])"), pipeline("{ $match: { $and: [{ age: { $gte: 5 } }, { age: { $lte: 5 } }] } }"));
+        assert_eq!(
+            mql(r#"match(name between ["a", "m"))"#),
+            pipeline(r#"{ $match: { $and: [{ name: { $gte: "a" } }, { name: { $lt: "m" } }] } }"#)
+        );
+        for (empty, cursor) in [("match(age between (65, 18))", 19), ("match(age between (5, 5))", 19), ("match(age between [5, 5))", 19), (r#"match(name between ["m", "a"])"#, 20)] {
+            let e = parse_err(empty);
+            assert!(matches!(e.ty, ParseErrorT::InvalidRange), "{}", empty);
+            assert_eq!(e.cursor, cursor, "{}", empty);
+        }
+        assert!(matches!(parse_err("match(age between (18 65))").ty, ParseErrorT::Unexpected));
+        assert!(matches!(parse_err("match(age between (18, x))").ty, ParseErrorT::Unexpected));
+    }
+
+    #[test]
+    fn normalize_field_case_option() {
+        let m = built(r#"match(Name == "Bob" & Items.length == 2 & A > $B).set(Total: Price * Qty).sort(Total desc).group(Branch, N: sum(Qty))"#);
+        assert_eq!(
+            m.ast2mql(),
+            pipeline(concat!(
+                r#"{ $match: { $and: [{ Name: { $eq: "Bob" } }, { Items: { $size: 2 } }, { $expr: { $gt: ["$A", "$B"] } }] } },"#,
+                r#"{ $set: { Total: { $multiply: ["$Price", "$Qty"] } } },{ $sort: { Total: -1 } },{ $group: { _id: "$Branch", N: { $sum: "$Qty" } } }"#,
+            ))
+        );
+        // keys and references change, the values compared against don't
+        assert_eq!(
+            m.ast2mql_with(&MqlOptions::default().normalize_field_case(true)),
+            pipeline(concat!(
+                r#"{ $match: { $and: [{ name: { $eq: "Bob" } }, { items: { $size: 2 } }, { $expr: { $gt: ["$a", "$b"] } }] } },"#,
+                r#"{ $set: { total: { $multiply: ["$price", "$qty"] } } },{ $sort: { total: -1 } },{ $group: { _id: "$branch", n: { $sum: "$qty" } } }"#,
+            ))
+        );
+        let lookup = MonGod::from_ast(vec![ASTNode::Lookup { from: "Orders".to_string(), local: "_ID".to_string(), foreign: "UserId".to_string(), as_: "Out".to_string() }]);
+        assert_eq!(
+            lookup.ast2mql_with(&MqlOptions::default().normalize_field_case(true)),
+            pipeline(r#"{ $lookup: { from: "Orders", localField: "_id", foreignField: "userid", as: "out" } }"#)
+        );
+    }
+
+    #[test]
+    fn require_lists_top_level_fields() {
+        assert_eq!(mql("require(name, email)"), pipeline(r#"{ $match: { $jsonSchema: { required: ["name", "email"] } } }"#));
+        assert_eq!(mql("require(name, name, email)"), pipeline(r#"{ $match: { $jsonSchema: { required: ["name", "email"] } } }"#));
+        for (bad, cursor) in [("require()", 8), ("require(name,)", 13), (r#"require("name")"#, 8), ("require($x)", 8), ("require(a.b)", 8), ("require(x, a.b)", 11)] {
+            let e = parse_err(bad);
+            assert!(matches!(e.ty, ParseErrorT::InvalidFieldName), "{}", bad);
+            assert_eq!(e.cursor, cursor, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn sample_takes_a_positive_size() {
+        assert_eq!(mql("sample(100)"), pipeline("{ $sample: { size: 100 } }"));
+        assert_eq!(mql("sample(1).limit(1)"), pipeline("{ $sample: { size: 1 } },{ $limit: 1 }"));
+        for bad in ["sample(0)", "sample(-1)", "sample(1.5)", "sample()", "sample(x)", "sample(99999999999999999999)"] {
+            let e = parse_err(bad);
+            assert!(matches!(e.ty, ParseErrorT::InvalidSampleSize), "{}", bad);
+            assert_eq!(e.cursor, 7, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn expect_hits_each_arm() {
+        let tokens = |kinds: Vec<TokenT>| peek_nth(kinds.into_iter().enumerate().map(|(i, ty)| Token::new(ty, i * 2)).collect::<Vec<_>>());
+        let mut iter = tokens(vec![TokenT::OpenParen]);
+        let token = MonGod::expect(&mut iter, TokenT::OpenParen).unwrap();
+        assert_eq!((token.ty(), token.idx), (&TokenT::OpenParen, 0));
+
+        for (kind, missing) in [
+            (TokenT::OpenParen, ParseErrorT::MissingOpenParen),
+            (TokenT::CloseParen, ParseErrorT::UnmatchedParenthesis),
+            (TokenT::OpenBracket, ParseErrorT::MissingOpenBracket),
+            (TokenT::CloseBracket, ParseErrorT::UnmatchedBracket),
+            (TokenT::Colon, ParseErrorT::MissingColon),
+            (TokenT::Then, ParseErrorT::MissingThen),
+            (TokenT::Else, ParseErrorT::MissingElse),
+            (TokenT::Comma, ParseErrorT::Unexpected),
+        ] {
+            let mut iter = tokens(vec![TokenT::Dot, TokenT::Semicolon]);
+            iter.next();
+            let e = MonGod::expect(&mut iter, kind.clone()).unwrap_err();
+            assert_eq!(std::mem::discriminant(&e.ty), std::mem::discriminant(&missing), "{:?}", kind);
+            assert_eq!(e.cursor, 2, "{:?}", kind);
+        }
+
+        let mut iter = tokens(vec![]);
+        assert!(matches!(MonGod::expect(&mut iter, TokenT::CloseParen).unwrap_err().ty, ParseErrorT::MissingCloseParen));
+        assert!(matches!(MonGod::expect(&mut iter, TokenT::Colon).unwrap_err().ty, ParseErrorT::EndOfTokenStream));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let eq = |field: &str, n: u8| format!("{{ {}: {{ $eq: {} }} }}", field, n);
+        let (a, b, c, d) = (eq("a", 1), eq("b", 2), eq("c", 3), eq("d", 4));
+        assert_eq!(
+            mql("match(a==1 & b==2 | c==3 & d==4)"),
+            pipeline(&format!("{{ $match: {{ $or: [{{ $and: [{}, {}] }}, {{ $and: [{}, {}] }}] }} }}", a, b, c, d))
+        );
+        assert_eq!(
+            mql("match(a==1 | b==2 & c==3 | d==4)"),
+            pipeline(&format!("{{ $match: {{ $or: [{}, {{ $and: [{}, {}] }}, {}] }} }}", a, b, c, d))
+        );
+        assert_eq!(
+            mql("match(a==1 & b==2 & c==3 | d==4)"),
+            pipeline(&format!("{{ $match: {{ $or: [{{ $and: [{}, {}, {}] }}, {}] }} }}", a, b, c, d))
+        );
+        assert_eq!(built("match(a==1 & b==2 | c==3 & d==4)").ast, built("match(|((&((a==1)(b==2)))(&((c==3)(d==4)))))").ast);
+    }
+
+    #[test]
+    fn running_out_before_a_close_paren_differs_from_a_wrong_token() {
+        // ran out of input, reported at the end of it
+        for source in ["match(a==1", "match((a==1)", "match(a==1 & (b == 2)", "set(a: 1", "limit(3"] {
+            let e = parse_err(source);
+            assert!(matches!(e.ty, ParseErrorT::MissingCloseParen), "{}: {:?}", source, e);
+            assert_eq!(e.cursor, source.len(), "{}", source);
+        }
+        // something else where the `)` should be, reported at it
+        for source in ["match(a==1 b", "match(a==1 b == 2)"] {
+            let e = parse_err(source);
+            assert!(matches!(e.ty, ParseErrorT::UnmatchedParenthesis), "{}: {:?}", source, e);
+            assert_eq!(e.cursor, 11, "{}", source);
+        }
+    }
+
+    #[test]
+    fn project_mixes_aliases_and_inclusion_flags() {
+        assert_eq!(mql("project(fullName: $name, years: age)"), pipeline(r#"{ $project: { fullName: "$name", years: "$age" } }"#));
+        assert_eq!(mql("project(fullName: $name, email: 1)"), pipeline(r#"{ $project: { fullName: "$name", email: 1 } }"#));
+        assert_eq!(mql("project(_id: 0, a: $b, c: true)"), pipeline(r#"{ $project: { _id: 0, a: "$b", c: 1 } }"#));
+        // numbers other than the flags, and quoted text, stay values
+        assert_eq!(mql(r#"project(a: 2, b: "lit")"#), pipeline(r#"{ $project: { a: { $literal: 2 }, b: "lit" } }"#));
+        assert_eq!(built("project(a: 0, b: $c)").validate_mql(), Err(MqlError::MixedProjection));
+        assert!(matches!(parse_err("project(a)").ty, ParseErrorT::MissingColon));
+    }
+
+    #[test]
+    fn quoted_dollar_text_stays_text_in_expressions() {
+        assert_eq!(mql(r#"project(a: "$x")"#), pipeline(r#"{ $project: { a: { $literal: "$x" } } }"#));
+        assert_eq!(mql(r#"set(a: "$x")"#), pipeline(r#"{ $set: { a: { $literal: "$x" } } }"#));
+        assert_eq!(
+            mql(r#"set(a: if (b == "$y") then "$y" else 2)"#),
+            pipeline(r#"{ $set: { a: { $cond: [{ $eq: ["$b", { $literal: "$y" }] }, { $literal: "$y" }, 2] } } }"#)
+        );
+        assert_eq!(mql(r#"match(a + 1 == "$x")"#), pipeline(r#"{ $match: { $expr: { $eq: [{ $add: ["$a", 1] }, { $literal: "$x" }] } } }"#));
+        assert_eq!(mql(r#"match(a.length == "$x")"#), pipeline(r#"{ $match: { $expr: { $eq: [{ $size: "$a" }, { $literal: "$x" }] } } }"#));
+        // query form already treats it as text
+        assert_eq!(mql(r#"match(a == "$x")"#), pipeline(r#"{ $match: { a: { $eq: "$x" } } }"#));
+        #[cfg(feature = "bson")]
+        assert_eq!(built(r#"set(a: "$x")"#).to_bson_pipeline(), vec![bson::doc! { "$set": { "a": { "$literal": "$x" } } }]);
+    }
+
+    #[test]
+    fn incremental_parser_tells_incomplete_from_invalid() {
+        for prefix in ["match(a ==", "mat", "match(", "match(a == \"unterm", "match(a == 1).", "match(a == 1 &", "match(a in [1,", "match(`bac", "match(a == 1);lim"] {
+            assert!(matches!(IncrementalParser::new().feed(prefix), ParseStatus::Incomplete), "{}", prefix);
+        }
+        for (invalid, cursor) in [("match(a @", 8), ("match(a == 1))", 13), ("foo", 0), ("match(a == 1).x", 14)] {
+            match IncrementalParser::new().feed(invalid) {
+                ParseStatus::Invalid(e) => assert_eq!(e.cursor, cursor, "{}", invalid),
+                status => panic!("{}: {:?}", invalid, status),
+            }
+        }
+
+        let mut parser = IncrementalParser::new();
+        assert!(matches!(parser.feed("match(a "), ParseStatus::Incomplete));
+        assert!(matches!(parser.feed("== 1"), ParseStatus::Incomplete));
+        assert!(matches!(parser.feed(")"), ParseStatus::Complete));
+        assert_eq!(parser.source(), "match(a == 1)");
+        assert_eq!(parser.finish().unwrap().ast2mql(), mql("match(a == 1)"));
+        assert!(IncrementalParser::new().finish().is_ok());
+    }
+
+    #[test]
+    fn project_slices() {
+        for (source, slice) in [("slice(5)", "5"), ("slice(-3)", "-3"), ("slice(2, 5)", "[2, 5]"), ("slice(-2, 3)", "[-2, 3]")] {
+            assert_eq!(mql(&format!("project(items: {})", source)), pipeline(&format!("{{ $project: {{ items: {{ $slice: {} }} }} }}", slice)), "{}", source);
+        }
+        for (bad, cursor) in [("slice(2, 0)", 24), ("slice(1.5)", 21), ("slice(x)", 21), ("slice()", 21)] {
+            let e = parse_err(&format!("project(items: {})", bad));
+            assert!(matches!(e.ty, ParseErrorT::InvalidSlice), "{}", bad);
+            assert_eq!(e.cursor, cursor, "{}", bad);
+        }
+        assert!(matches!(parse_err("project(items: slice(1, 2, 3))").ty, ParseErrorT::UnmatchedParenthesis));
+    }
+
+    #[test]
+    fn arithmetic_on_the_left_switches_to_expr() {
+        assert_eq!(mql("match(price > 100)"), pipeline("{ $match: { price: { $gt: 100 } } }"));
+        for (source, expected) in [
+            ("match(price * quantity > 100)", r#"{ $gt: [{ $multiply: ["$price", "$quantity"] }, 100] }"#),
+            ("match(100 < price * quantity)", r#"{ $lt: [100, { $multiply: ["$price", "$quantity"] }] }"#),
+            ("match(a + b * c >= d - 1)", r#"{ $gte: [{ $add: ["$a", { $multiply: ["$b", "$c"] }] }, { $subtract: ["$d", 1] }] }"#),
+            ("match((a - b) / c == 2)", r#"{ $eq: [{ $divide: [{ $subtract: ["$a", "$b"] }, "$c"] }, 2] }"#),
+            ("match(((a - b)) > 2)", r#"{ $gt: [{ $subtract: ["$a", "$b"] }, 2] }"#),
+            ("match((a + 1 > 2))", r#"{ $gt: [{ $add: ["$a", 1] }, 2] }"#),
+        ] {
+            assert_eq!(mql(source), pipeline(&format!("{{ $match: {{ $expr: {} }} }}", expected)), "{}", source);
+        }
+        assert_eq!(
+            mql("match(x == 1 & (a * 2) < 3)"),
+            pipeline(r#"{ $match: { $and: [{ x: { $eq: 1 } }, { $expr: { $lt: [{ $multiply: ["$a", 2] }, 3] } }] } }"#)
+        );
+        // grouped arithmetic still needs comparing to something
+        assert!(matches!(parse_err("match((a - b))").ty, ParseErrorT::MissingComparator));
+    }
+
+    #[test]
+    fn identifier_length_and_token_count_caps() {
+        let short = ParseOptions::default().max_identifier_length(8);
+        assert!(build_with("match(abcdefgh == 1)", short.clone()).is_ok());
+        let e = build_with("match(a == 1 & abcdefghi == 1)", short.clone()).unwrap_err();
+        assert!(matches!(e.ty, ParseErrorT::IdentifierTooLong));
+        assert_eq!(e.cursor, 15);
+        // the backticks don't count, and neither do quoted values
+        assert!(build_with("match(`abcdefgh` == \"a much longer string\")", short.clone()).is_ok());
+        assert!(matches!(build_with("set(x: $abcdefghi)", short).unwrap_err().ty, ParseErrorT::IdentifierTooLong));
+
+        // `match ( a == 1 )` is six tokens
+        let few = ParseOptions::default().max_tokens(6);
+        assert!(build_with("match(a == 1) // comments are free", few.clone()).is_ok());
+        let e = build_with("match(a == 1).limit(1)", few).unwrap_err();
+        assert!(matches!(e.ty, ParseErrorT::TooManyTokens));
+        assert_eq!(e.cursor, 13);
+    }
+
+    #[test]
+    fn sorted_groups_give_one_canonical_output() {
+        let canonical = OptimizeOptions::default().sort_groups(true);
+        for (one, other) in [
+            ("match(b == 2 & a == 1 & a > 0)", "match(a > 0 & a == 1 & b == 2)"),
+            ("match(x == 1 | (c == 1 & b == 2))", "match((b == 2 & c == 1) | x == 1)"),
+            ("match(a == 1 & nor((c == 1)(b == 1)))", "match(nor((b == 1)(c == 1)) & a == 1)"),
+            (r#"match(a == "x" & a == 1)"#, r#"match(a == 1 & a == "x")"#),
+        ] {
+            assert_ne!(mql(one), mql(other));
+            assert_eq!(optimized(one, &canonical), optimized(other, &canonical), "{} / {}", one, other);
+        }
+        assert_eq!(
+            optimized("match(b == 2 & a == 1 & a > 0)", &canonical),
+            pipeline("{ $match: { $and: [{ a: { $eq: 1 } }, { a: { $gt: 0 } }, { b: { $eq: 2 } }] } }")
+        );
+        // off by default
+        assert_eq!(optimized("match(b == 2 & a == 1)", &OptimizeOptions::default()), mql("match(b == 2 & a == 1)"));
+    }
+
+    #[test]
+    fn in_arrays_keep_each_element_type() {
+        let m = built(r#"match(status in [active, null, 1, 2.5, true, "q\"x"])"#);
+        assert_eq!(m.ast2mql(), pipeline(r#"{ $match: { status: { $in: ["active", null, 1, 2.5, true, "q\"x"] } } }"#));
+        let json: serde_json::Value = serde_json::from_str(&m.to_pipeline_json_string()).unwrap();
+        assert_eq!(json, serde_json::json!([{ "$match": { "status": { "$in": ["active", null, 1, 2.5, true, "q\"x"] } } }]));
+        #[cfg(feature = "bson")]
+        assert_eq!(
+            m.to_bson_pipeline(),
+            vec![bson::doc! { "$match": { "status": { "$in": ["active", bson::Bson::Null, 1, 2.5, true, "q\"x"] } } }]
+        );
+        assert_eq!(mql("match(status in [])"), pipeline("{ $match: { status: { $in: [] } } }"));
+    }
+
+    #[test]
+    fn duplicate_conditions_collapse_when_asked() {
+        let dedupe = OptimizeOptions::default().dedupe_groups(true);
+        assert_eq!(optimized("match(a == 1 & a == 1)", &dedupe), pipeline("{ $match: { a: { $eq: 1 } } }"));
+        assert_eq!(optimized("match(a == 1 | b == 2 | a == 1)", &dedupe), pipeline("{ $match: { $or: [{ a: { $eq: 1 } }, { b: { $eq: 2 } }] } }"));
+        assert_eq!(
+            optimized("match((a == 1 & b == 2) | (a == 1 & b == 2))", &dedupe),
+            pipeline("{ $match: { $and: [{ a: { $eq: 1 } }, { b: { $eq: 2 } }] } }")
+        );
+        // only identical conditions count
+        assert_eq!(optimized("match(a == 1 & a >= 1)", &dedupe), pipeline("{ $match: { $and: [{ a: { $eq: 1 } }, { a: { $gte: 1 } }] } }"));
+        // off by default
+        assert_eq!(optimized("match(a == 1 & a == 1)", &OptimizeOptions::default()), pipeline("{ $match: { $and: [{ a: { $eq: 1 } }, { a: { $eq: 1 } }] } }"));
+    }
+
+    #[test]
+    fn eq_shorthand_option() {
+        let shorthand = MqlOptions::default().eq_shorthand(true);
+        let m = built("match(branch == CSE & age > 18 & 7 == n)");
+        assert_eq!(m.ast2mql(), pipeline(r#"{ $match: { $and: [{ branch: { $eq: "CSE" } }, { age: { $gt: 18 } }, { n: { $eq: 7 } }] } }"#));
+        assert_eq!(m.ast2mql_with(&shorthand), pipeline(r#"{ $match: { $and: [{ branch: "CSE" }, { age: { $gt: 18 } }, { n: 7 }] } }"#));
+        // forms that aren't a plain field against a value keep their operators
+        for source in ["match(a != 1)", "match(a == $b)", "match(a + 1 == 2)", "set(x: if (a == 1) then 1 else 2)"] {
+            assert_eq!(built(source).ast2mql_with(&shorthand), mql(source), "{}", source);
+        }
+    }
+
+    #[test]
+    fn trailing_and_doubled_dots_point_at_the_dot() {
+        for (source, cursor) in [
+            ("match(user. == 1)", 10),
+            ("match(user..name == 1)", 11),
+            ("match(`user.` == 1)", 11),
+            ("set(user.: 1)", 8),
+            ("sort(user. desc)", 9),
+            ("unset(a.)", 7),
+            ("project(a: user.)", 15),
+            ("set(x: user. + 1)", 11),
+        ] {
+            let e = parse_err(source);
+            assert!(matches!(e.ty, ParseErrorT::InvalidFieldName), "{}: {:?}", source, e);
+            assert_eq!(e.cursor, cursor, "{}", source);
+        }
+        assert_eq!(mql("match(user.name == 1)"), pipeline(r#"{ $match: { "user.name": { $eq: 1 } } }"#));
+    }
+
+    #[test]
+    fn facet_branches_are_pipelines() {
+        assert_eq!(
+            mql("facet(a: [match(x == 1).limit(2)], b: [match(y > 3)]).limit(1)"),
+            pipeline("{ $facet: { a: [{ $match: { x: { $eq: 1 } } }, { $limit: 2 }], b: [{ $match: { y: { $gt: 3 } } }] } },{ $limit: 1 }")
+        );
+        for (bad, kind, cursor) in [
+            ("facet(a: [limit(1)], a: [limit(2)])", ParseErrorT::DuplicateFacetName, 21),
+            ("facet(a: [facet(b: [limit(1)])])", ParseErrorT::NestedFacet, 10),
+            ("facet(a: match(x == 1))", ParseErrorT::MissingOpenBracket, 9),
+            ("facet(a: [match(x == 1) limit(1)])", ParseErrorT::NoDotBetweenFns, 24),
+            ("facet(a: [])", ParseErrorT::Unexpected, 10),
+            ("facet(a.b: [limit(1)])", ParseErrorT::InvalidFieldName, 6),
+            ("facet()", ParseErrorT::InvalidFieldName, 6),
+        ] {
+            let e = parse_err(bad);
+            assert_eq!(std::mem::discriminant(&e.ty), std::mem::discriminant(&kind), "{}: {:?}", bad, e);
+            assert_eq!(e.cursor, cursor, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn lookup_takes_four_named_arguments() {
+        let expected = pipeline(r#"{ $lookup: { from: "orders", localField: "_id", foreignField: "userId", as: "orders" } }"#);
+        assert_eq!(mql("lookup(from: orders, local: _id, foreign: userId, as: orders)"), expected);
+        assert_eq!(mql("lookup(as: orders, foreign: userId, local: _id, from: orders)"), expected);
+        for (bad, cursor) in [
+            ("lookup(from: orders, local: _id, foreign: userId)", 48),
+            ("lookup(from: orders, from: x, local: _id, foreign: userId, as: o)", 21),
+            ("lookup(from: orders, local: _id, foreign: userId, as: o, extra: 1)", 57),
+            ("lookup()", 7),
+        ] {
+            let e = parse_err(bad);
+            assert!(matches!(e.ty, ParseErrorT::InvalidLookup), "{}: {:?}", bad, e);
+            assert_eq!(e.cursor, cursor, "{}", bad);
+        }
+        assert!(matches!(parse_err("lookup(from: orders, local: a..b, foreign: userId, as: o)").ty, ParseErrorT::InvalidFieldName));
+    }
+
+    #[test]
+    fn mixed_types_warn() {
+        let mixed = |src: &str| -> Vec<Warning> {
+            built(src).warnings().into_iter().filter(|w| matches!(w.ty, WarningT::MixedTypes { .. })).collect()
+        };
+        let warnings = mixed(r#"match(age == 5 & age == "x")"#);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].stage, 0);
+        assert!(matches!(&warnings[0].ty, WarningT::MixedTypes { field, first: "number", second: "string" } if field == "age"));
+
+        // one warning per field, at the stage it first mixes
+        let warnings = mixed(r#"match(age == 5).limit(3).match(age == "x").match(age == true)"#);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].stage, 2);
+
+        assert!(mixed("match(age == 5 | age == 6)").is_empty());
+        assert!(mixed(r#"match(age == 5 & name == "x")"#).is_empty());
+
+        // a stage that rewrites the field starts it over
+        for src in [
+            r#"match(age == 5).set(age: "x").match(age == "x")"#,
+            r#"match(age == 5).unset(age).match(age == "x")"#,
+            r#"match(age == 5).rename(name, age).match(age == "x")"#,
+            r#"match(age == 5).project(age: name).match(age == "x")"#,
+            r#"match(age == 5).group(age, n: sum(1)).match(age == "x")"#,
+        ] {
+            assert!(mixed(src).is_empty(), "{}", src);
+        }
+        assert_eq!(mixed(r#"match(age == 5).set(name: "x").match(age == "x")"#).len(), 1);
+    }
+
+    #[test]
+    fn match_pushdown_safety() {
+        // the match is the second stage, checked against the first
+        let swaps = |src: &str| {
+            let god = built(src);
+            MonGod::can_swap_match_before(&god.ast[1], &god.ast[0])
+        };
+        assert_eq!(built("set(b: 1).match(a > $b | c.length > 1)").ast[1].referenced_fields(), vec!["a", "b", "c"]);
+        for src in [
+            "project(b: 0).match(a == 1)",
+            "project(a: 1, b: 1).match(a == 1)",
+            "project(b: 1).match(_id == 1)",
+            "set(b: 1).match(a == 1)",
+            "unset(b).match(a.b == 1)",
+            "sort(a).match(a == 1)",
+            "rename(b, c).match(a == 1)",
+            "lookup(from: o, local: a, foreign: b, as: x).match(a == 1)",
+        ] {
+            assert!(swaps(src), "{}", src);
+        }
+        for src in [
+            // projected away
+            "project(a: 0).match(a == 1)",
+            "project(b: 1).match(a == 1)",
+            "project(a.c: 1).match(a.b == 1)",
+            "project(a: slice(2)).match(a == 1)",
+            "set(b: 1).match(a > $b)",
+            "set(a.b: 1).match(a.length > 1)",
+            "unset(a).match(a.b == 1)",
+            "rename(b, c).match(c == 1)",
+            "lookup(from: o, local: a, foreign: b, as: x).match(x == 1)",
+            "group(a, n: sum(1)).match(a == 1)",
+            "limit(5).match(a == 1)",
+            "match(b == 1).match(a == 1)",
+        ] {
+            assert!(!swaps(src), "{}", src);
+        }
+        let god = built("set(b: 1).limit(1)");
+        assert!(!MonGod::can_swap_match_before(&god.ast[1], &god.ast[0]));
+    }
+
+    #[test]
+    fn regex_options() {
+        assert_eq!(mql(r#"match(a matches regex("^x", "im"))"#), pipeline(r#"{ $match: { a: { $regex: "^x", $options: "im" } } }"#));
+        assert_eq!(mql(r#"match(a matches regex("^x", "imsx"))"#), pipeline(r#"{ $match: { a: { $regex: "^x", $options: "imsx" } } }"#));
+        // no options at all renders like a plain pattern
+        for source in [r#"match(a matches regex("^x", ""))"#, r#"match(a matches regex("^x"))"#, r#"match(a matches "^x")"#] {
+            assert_eq!(mql(source), pipeline(r#"{ $match: { a: { $regex: "^x" } } }"#), "{}", source);
+        }
+        for source in [r#"match(a matches regex("^x", "q"))"#, r#"match(a matches regex("^x", "I"))"#, r#"match(a matches regex("^x", "i m"))"#, "match(a matches regex(\"^x\", im))"] {
+            let e = parse_err(source);
+            assert!(matches!(e.ty, ParseErrorT::InvalidRegexOptions), "{}: {:?}", source, e);
+            assert_eq!(e.cursor, 28, "{}", source);
+        }
+
+        let hand_built = |options: &str| {
+            let pattern = ASTNode::Regex { pattern: "^x".to_string(), options: options.to_string() };
+            MonGod::from_ast(vec![ASTNode::match_(ASTNode::Matches { field: "a".to_string(), pattern: Box::new(pattern) })])
+        };
+        assert_eq!(hand_built("ms").validate_mql(), Ok(()));
+        assert_eq!(hand_built("mq").validate_mql(), Err(MqlError::InvalidRegexOptions("mq".to_string())));
+    }
+
+    #[test]
+    fn every_comparator_has_its_own_operator() {
+        let all = [Comparator::GTE, Comparator::GT, Comparator::EQ, Comparator::NEQ, Comparator::LT, Comparator::LTE];
+        let ops: Vec<&str> = all.iter().map(|c| c.mql_op()).collect();
+        assert_eq!(ops, ["$gte", "$gt", "$eq", "$ne", "$lt", "$lte"]);
+        for (i, op) in ops.iter().enumerate() {
+            assert!(!ops[i + 1..].contains(op), "{} twice", op);
+        }
+        for c in all {
+            assert_eq!(c.to_string().parse::<Comparator>(), Ok(c));
+            assert_eq!(c.flipped().flipped(), c);
+        }
+        assert_eq!(mql("match(3 != a)"), pipeline("{ $match: { a: { $ne: 3 } } }"));
+        assert_eq!(mql("match(a + 1 != 3)"), pipeline(r#"{ $match: { $expr: { $ne: [{ $add: ["$a", 1] }, 3] } } }"#));
+        assert_eq!(mql("match(a.length != 3)"), pipeline("{ $match: { a: { $not: { $size: 3 } } } }"));
+    }
+
+    #[test]
+    fn computed_expressions_on_both_sides_use_expr() {
+        assert_eq!(
+            mql("match(a * 2 > b * 3)"),
+            pipeline(r#"{ $match: { $expr: { $gt: [{ $multiply: ["$a", 2] }, { $multiply: ["$b", 3] }] } } }"#)
+        );
+        assert_eq!(
+            mql("match(a.length == b + c)"),
+            pipeline(r#"{ $match: { $expr: { $eq: [{ $size: "$a" }, { $add: ["$b", "$c"] }] } } }"#)
+        );
+        assert_eq!(mql("match(a.length < b.length)"), pipeline(r#"{ $match: { $expr: { $lt: [{ $size: "$a" }, { $size: "$b" }] } } }"#));
+        assert_eq!(
+            mql("match((a + 1) * 2 >= b / 4)"),
+            pipeline(r#"{ $match: { $expr: { $gte: [{ $multiply: [{ $add: ["$a", 1] }, 2] }, { $divide: ["$b", 4] }] } } }"#)
+        );
+        let mut m = MonGod::new("match(a * 2 > b * 3)".to_string());
+        m.build().unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&m.to_pipeline_json_string()).unwrap(),
+            serde_json::json!([{ "$match": { "$expr": { "$gt": [{ "$multiply": ["$a", 2] }, { "$multiply": ["$b", 3] }] } } }])
+        );
+        #[cfg(feature = "bson")]
+        assert_eq!(
+            m.to_bson_pipeline(),
+            vec![bson::doc! { "$match": { "$expr": { "$gt": [{ "$multiply": ["$a", 2] }, { "$multiply": ["$b", 3] }] } } }]
+        );
+    }
+
+    #[test]
+    fn debug_tokens_one_line_each() {
+        let source = r#"match(age >= 18 & name == "é").limit(5)"#;
+        let tokens = MonGod::tokenize(source).unwrap();
+        assert_eq!(
+            debug_tokens(&tokens, source),
+            [
+                r#"0..5     Match               "match""#,
+                r#"5..6     OpenParen           "(""#,
+                r#"6..9     Literal             "age""#,
+                r#"10..12   Comparator          ">=""#,
+                r#"13..15   Number              "18""#,
+                r#"16..17   ConditionalOperator "&""#,
+                r#"18..22   Literal             "name""#,
+                r#"23..25   Comparator          "==""#,
+                r#"26..30   Str                 "\"é\"""#,
+                r#"30..31   CloseParen          ")""#,
+                r#"31..32   Dot                 ".""#,
+                r#"32..37   Limit               "limit""#,
+                r#"37..38   OpenParen           "(""#,
+                r#"38..39   Number              "5""#,
+                r#"39..40   CloseParen          ")""#,
+                "",
+            ]
+            .join("\n")
+        );
+        assert_eq!(debug_tokens(&[], source), "");
+        // spans that don't fit the source show an empty lexeme rather than panicking
+        assert_eq!(debug_tokens(&tokens[8..9], "short"), "26..30   Str                 \"\"\n");
+    }
+
+    #[test]
+    fn parameterized_pulls_out_compared_values() {
+        let (query, params) = built(r#"match(age > 18 & name == "bob")"#).ast2mql_parameterized();
+        assert_eq!(query, pipeline("{ $match: { $and: [{ age: { $gt: :p0 } }, { name: { $eq: :p1 } }] } }"));
+        assert_eq!(params, vec![ASTNode::Number(18.0), ASTNode::Str("bob".to_string())]);
+
+        // numbered across stages and facet branches, in source order
+        let (query, params) = built("match(a == true).facet(x: [match(b in [1, \"y\"])])").ast2mql_parameterized();
+        assert_eq!(query, pipeline("{ $match: { a: { $eq: :p0 } } },{ $facet: { x: [{ $match: { b: { $in: [:p1, :p2] } } }] } }"));
+        assert_eq!(params, vec![ASTNode::Bool(true), ASTNode::Number(1.0), ASTNode::Str("y".to_string())]);
+
+        // field references and stage arguments stay as they are
+        for source in ["match(a == $b)", "set(x: 5).limit(3)", r#"match(a matches "x")"#] {
+            let (query, params) = built(source).ast2mql_parameterized();
+            assert_eq!(query, mql(source), "{}", source);
+            assert!(params.is_empty(), "{}", source);
+        }
+        // and the ast itself is left alone
+        let god = built("match(a == 1)");
+        god.ast2mql_parameterized();
+        assert_eq!(god.ast2mql(), pipeline("{ $match: { a: { $eq: 1 } } }"));
+    }
+
+    #[test]
+    fn group_by_several_fields() {
+        assert_eq!(
+            mql("group([branch, year], count: sum(1))"),
+            pipeline(r#"{ $group: { _id: { branch: "$branch", year: "$year" }, count: { $sum: 1 } } }"#)
+        );
+        // keys are named after the last part of the path
+        assert_eq!(mql("group([a.b, c], n: sum(1))"), pipeline(r#"{ $group: { _id: { b: "$a.b", c: "$c" }, n: { $sum: 1 } } }"#));
+        assert_eq!(mql("group([a, b])"), pipeline(r#"{ $group: { _id: { a: "$a", b: "$b" } } }"#));
+        assert_eq!(mql("group([branch], count: sum(1))"), mql("group(branch, count: sum(1))"));
+        assert_eq!(mql("group([], count: sum(1))"), pipeline("{ $group: { _id: null, count: { $sum: 1 } } }"));
+        for (source, ty, cursor) in [
+            ("group([a, a], n: sum(1))", ParseErrorT::DuplicateGroupKey, 10),
+            ("group([a.b, x.b], n: sum(1))", ParseErrorT::DuplicateGroupKey, 12),
+            ("group([a, $b], n: sum(1))", ParseErrorT::InvalidFieldName, 10),
+            ("group([a,], n: sum(1))", ParseErrorT::InvalidFieldName, 9),
+        ] {
+            let e = parse_err(source);
+            assert_eq!(std::mem::discriminant(&e.ty), std::mem::discriminant(&ty), "{}: {:?}", source, e);
+            assert_eq!(e.cursor, cursor, "{}", source);
+        }
+    }
+
+    #[test]
+    fn push_and_add_to_set_collect_field_values() {
+        let source = "group(a, xs: push(b), ys: add_to_set(c.d))";
+        assert_eq!(mql(source), pipeline(r#"{ $group: { _id: "$a", xs: { $push: "$b" }, ys: { $addToSet: "$c.d" } } }"#));
+        let m = built(source);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&m.to_pipeline_json_string()).unwrap(),
+            serde_json::json!([{ "$group": { "_id": "$a", "xs": { "$push": "$b" }, "ys": { "$addToSet": "$c.d" } } }])
+        );
+        #[cfg(feature = "bson")]
+        assert_eq!(m.to_bson_pipeline(), vec![bson::doc! { "$group": { "_id": "$a", "xs": { "$push": "$b" }, "ys": { "$addToSet": "$c.d" } } }]);
+
+        assert_eq!(mql("group(a, xs: push($b))"), mql("group(a, xs: push(b))"));
+        // a quoted string is the value itself, not a field
+        assert_eq!(mql(r#"group(a, xs: add_to_set("b"))"#), pipeline(r#"{ $group: { _id: "$a", xs: { $addToSet: "b" } } }"#));
+        assert_eq!(mql("group(a, xs: push(b.length))"), pipeline(r#"{ $group: { _id: "$a", xs: { $push: { $size: "$b" } } } }"#));
+        let e = parse_err("group(a, xs: push())");
+        assert!(matches!(e.ty, ParseErrorT::Unexpected), "{:?}", e);
+        assert_eq!(e.cursor, 18);
+    }
+
+    #[test]
+    fn length_on_the_value_side_of_a_field() {
+        for (source, expected) in [
+            ("match(a == b.length)", r#"{ $match: { $expr: { $eq: ["$a", { $size: "$b" }] } } }"#),
+            ("match(a > b.length & c == 1)", r#"{ $match: { $and: [{ $expr: { $gt: ["$a", { $size: "$b" }] } }, { c: { $eq: 1 } }] } }"#),
+            // a constant still flips over to `$size` in query form
+            ("match(3 == a.length)", "{ $match: { a: { $size: 3 } } }"),
+        ] {
+            let m = built(source);
+            assert_eq!(m.ast2mql(), pipeline(expected), "{}", source);
+            assert_eq!(m.validate_mql(), Ok(()), "{}", source);
+            assert_eq!(built(&m.to_dsl()).ast, m.ast, "{}", source);
+        }
+        let m = built("match(a == b.length)");
+        assert_eq!(m.ast[0].referenced_fields(), vec!["a", "b"]);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&m.to_pipeline_json_string()).unwrap(),
+            serde_json::json!([{ "$match": { "$expr": { "$eq": ["$a", { "$size": "$b" }] } } }])
+        );
+        #[cfg(feature = "bson")]
+        assert_eq!(m.to_bson_pipeline(), vec![bson::doc! { "$match": { "$expr": { "$eq": ["$a", { "$size": "$b" }] } } }]);
+    }
+
+    #[test]
+    fn text_searches_stay_in_their_own_match() {
+        let source = r#"match(text("a")).match(text("b"))"#;
+        let m = optimized(source, &OptimizeOptions::default());
+        assert_eq!(m, mql(source));
+        let mut god = built(source);
+        god.optimize();
+        assert_eq!(god.validate_mql(), Ok(()));
+        assert_eq!(built(&god.to_dsl()).ast, god.ast);
+        // one search still merges with plain conditions
+        assert_eq!(
+            optimized(r#"match(text("a")).match(b == 1)"#, &no_rewrites().merge_matches(true)),
+            pipeline(r#"{ $match: { $and: [{ $text: { $search: "a" } }, { b: { $eq: 1 } }] } }"#)
+        );
+        let two = ASTNode::and(vec![
+            ASTNode::Text { search: "a".to_string(), language: None, case_sensitive: None },
+            ASTNode::Text { search: "b".to_string(), language: None, case_sensitive: None },
+        ]);
+        assert_eq!(MonGod::from_ast(vec![ASTNode::match_(two)]).validate_mql(), Err(MqlError::DuplicateText));
+    }
+
+    #[test]
+    fn error_renderers_agree_on_columns_and_survive_any_cursor() {
+        // one character, two bytes, before the error
+        let source = "match(a == \"é\" = 1)";
+        let e = parse_err(source);
+        assert_eq!(e.cursor, 16);
+        assert!(format_error(source, &e).contains("--> line 1, column 16\n"));
+        assert!(format_diagnostic(source, &e).contains("--> line 1, column 16\n"));
+        assert!(format_error_windowed(source, &e, 3).contains("--> line 1, column 16\n"));
+
+        // hand-built errors pointing inside a character or past the end
+        let source = "é\nab";
+        for cursor in [1, 100] {
+            let e = ParseError { ty: ParseErrorT::Unexpected, cursor };
+            format_error(source, &e);
+            format_error_windowed(source, &e, 1);
+            format_diagnostic(source, &e);
+        }
+        let inside = ParseError { ty: ParseErrorT::Unexpected, cursor: 1 };
+        assert!(format_diagnostic(source, &inside).contains("--> line 1, column 1\n"));
+        assert!(format_error(source, &inside).contains("--> line 1, column 1\n"));
+    }
+
+    #[test]
+    fn lengths_are_not_truthiness_tests() {
+        for (source, cursor) in [("match(a.length)", 14), ("match(!a.length)", 15), ("match((!a.length))", 16), ("set(x: if !a.length then 1 else 2)", 20)] {
+            let e = parse_err(source);
+            assert!(matches!(e.ty, ParseErrorT::MissingComparator), "{}: {:?}", source, e);
+            assert_eq!(e.cursor, cursor, "{}", source);
+        }
+        assert_eq!(mql("match(!a)"), pipeline("{ $match: { a: { $eq: false } } }"));
+        assert_eq!(mql("match(a.length == 0)"), pipeline("{ $match: { a: { $size: 0 } } }"));
+    }
+
+    #[test]
+    fn common_field_names_that_are_keywords_need_backticks() {
+        for word in ["text", "comment", "group", "range", "limit", "set", "all", "is", "in", "has", "not"] {
+            parse_err(&format!("match({} == 1)", word));
+            assert_eq!(
+                mql(&format!("match(`{}` == 1)", word)),
+                pipeline(&format!("{{ $match: {{ {}: {{ $eq: 1 }} }} }}", word))
+            );
+        }
+        assert_eq!(
+            mql("match(`range` in [1, 2] & `is` is empty & !`not`)"),
+            pipeline("{ $match: { $and: [{ range: { $in: [1, 2] } }, { is: { $size: 0 } }, { not: { $eq: false } }] } }")
+        );
+        assert_eq!(mql("set(`text`: `limit` + 1)"), pipeline(r#"{ $set: { text: { $add: ["$limit", 1] } } }"#));
+        assert_eq!(mql("sort(`group` desc)"), pipeline("{ $sort: { group: -1 } }"));
+        assert_eq!(
+            mql("group(`in`, n: sum(`all`))"),
+            pipeline(r#"{ $group: { _id: "$in", n: { $sum: "$all" } } }"#)
+        );
+    }
+}
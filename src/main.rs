@@ -1,43 +1,142 @@
-use mongorph::{MonGod, ParseError};
+use std::io::Read;
+use std::process::ExitCode;
 
-fn fmt_err(s: &str, e: ParseError) -> String {
-    let start = e.cursor;
-    let lines: Vec<&str> = s.lines().collect();
-    let mut line_num = 0;
-    let mut col_num = 0;
-    let mut current_index = 0;
+use mongorph::{format_diagnostic, MonGod, MqlOptions, TokenT};
 
-    for (i, line) in lines.iter().enumerate() {
-        if current_index + line.len() >= start {
-            line_num = i + 1;
-            col_num = start - current_index + 1;
-            break;
-        }
-        current_index += line.len() + 1;
-    }
-    let error_line = lines.get(line_num - 1).unwrap_or(&"");
-    let mut marker_line = String::new();
-    marker_line.extend(" ".repeat(col_num - 1).chars());
-    marker_line.push('^');
+const USAGE: &str = "usage: mongorph [--ast | --mql | --check | --format [--write]] [--pretty] [--file PATH | QUERY]
+
+reads QUERY from the arguments, from PATH with --file, or from stdin if neither is given.
+`;` separated pipelines in QUERY are each printed or checked in turn
 
-    format!(
-        "ParseError::{:?}\n   --> line {}, column {}\n   |\n{:3}| {}\n   | {}\n",
-        e.ty,
-        line_num,
-        col_num,
-        line_num,
-        error_line,
-        marker_line
-    )
+  --ast     print the parsed ast
+  --mql     print the mql (the default)
+  --check   print nothing, exit 1 if the query doesn't parse or render
+  --format  print the query in canonical form
+  --write   with --format and --file, rewrite PATH instead of printing
+  --pretty  spread the mql over multiple lines";
+
+enum Output {
+    Ast,
+    Mql,
+    Check,
+    Format,
 }
 
-fn main() {
-    let s = String::from("match(|((branch == ECE)(&((branch == CSE)(branch == AIML))))");
-    let mut m = MonGod::new(s.clone());
-    match m.build() {
-        Ok(_) => println!("\n\nAST: {:?}", m.ast),
-        Err(e) => println!("{}", fmt_err(&s, e))
+fn main() -> ExitCode {
+    let mut output = Output::Mql;
+    let mut pretty = false;
+    let mut write = false;
+    let mut file = None;
+    let mut query = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--ast" => output = Output::Ast,
+            "--mql" => output = Output::Mql,
+            "--check" => output = Output::Check,
+            "--format" => output = Output::Format,
+            "--write" => write = true,
+            "--pretty" => pretty = true,
+            "--file" => match args.next() {
+                Some(path) => file = Some(path),
+                None => {
+                    eprintln!("--file needs a path\n\n{}", USAGE);
+                    return ExitCode::from(2);
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            flag if flag.starts_with("--") => {
+                eprintln!("unknown flag `{}`\n\n{}", flag, USAGE);
+                return ExitCode::from(2);
+            }
+            _ if query.is_some() => {
+                eprintln!("more than one query given, quote it if it has spaces\n\n{}", USAGE);
+                return ExitCode::from(2);
+            }
+            _ => query = Some(arg),
+        }
+    }
+    if write && !(matches!(output, Output::Format) && file.is_some()) {
+        eprintln!("--write only goes with --format and --file\n\n{}", USAGE);
+        return ExitCode::from(2);
+    }
+    let query = match (query, &file) {
+        (Some(_), Some(_)) => {
+            eprintln!("give either a query or --file, not both\n\n{}", USAGE);
+            return ExitCode::from(2);
+        }
+        (Some(query), None) => query,
+        (None, Some(path)) => match std::fs::read_to_string(path) {
+            Ok(query) => query,
+            Err(e) => {
+                eprintln!("couldn't read {}: {}", path, e);
+                return ExitCode::FAILURE;
+            }
+        },
+        (None, None) => {
+            let mut query = String::new();
+            if let Err(e) = std::io::stdin().read_to_string(&mut query) {
+                eprintln!("couldn't read the query from stdin: {}", e);
+                return ExitCode::FAILURE;
+            }
+            query
+        }
     };
-    // let mql = m.ast2mql();
-    // println!("{}", mql);
-}
\ No newline at end of file
+
+    let mut m = MonGod::new(query);
+    if let Err(e) = m.build() {
+        eprint!("{}", format_diagnostic(m.source(), &e));
+        return ExitCode::FAILURE;
+    }
+    // every `;` separated pipeline, one after the other
+    match output {
+        Output::Ast => {
+            for pipeline in &m.pipelines {
+                println!("{:#?}", pipeline);
+            }
+        }
+        Output::Mql => {
+            for mql in m.pipelines2mql_with(&MqlOptions::default().pretty(pretty)) {
+                println!("{}", mql);
+            }
+        }
+        Output::Check => {
+            for pipeline in &m.pipelines {
+                if let Err(e) = MonGod::from_ast(pipeline.clone()).validate_mql() {
+                    eprintln!("error: {}", e);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        Output::Format => {
+            // the formatter works from the ast, which has nowhere to keep comments
+            let has_comments = MonGod::tokenize_with_trivia(m.source())
+                .unwrap_or_default()
+                .iter()
+                .any(|t| matches!(t.ty(), TokenT::Trivia(trivia) if trivia.starts_with("//")));
+            if has_comments {
+                eprintln!("error: the query has `//` comments, formatting would drop them");
+                return ExitCode::FAILURE;
+            }
+            let pipelines: Vec<String> = m
+                .pipelines
+                .iter()
+                .map(|pipeline| pipeline.iter().map(|stage| stage.to_string()).collect::<Vec<_>>().join("."))
+                .collect();
+            let formatted = format!("{}\n", pipelines.join(";\n"));
+            match (write, &file) {
+                (true, Some(path)) => {
+                    if let Err(e) = std::fs::write(path, formatted) {
+                        eprintln!("couldn't write {}: {}", path, e);
+                        return ExitCode::FAILURE;
+                    }
+                }
+                _ => print!("{}", formatted),
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,117 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn mongorph(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mongorph"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("the binary runs");
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> &str {
+    std::str::from_utf8(&output.stdout).unwrap()
+}
+
+fn stderr(output: &Output) -> &str {
+    std::str::from_utf8(&output.stderr).unwrap()
+}
+
+/// with the `trace` feature the parser logs to stderr first, whatever's expected comes after
+fn assert_stderr(output: &Output, expected: &str) {
+    if cfg!(feature = "trace") {
+        assert!(stderr(output).ends_with(expected), "{:?}", stderr(output));
+    } else {
+        assert_eq!(stderr(output), expected);
+    }
+}
+
+#[test]
+fn prints_mql_for_a_valid_query() {
+    let output = mongorph(&["match(a == 1)"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "db.collection.aggregate([{ $match: { a: { $eq: 1 } } },])\n");
+    assert_stderr(&output, "");
+
+    let output = mongorph(&[], "limit(2)");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "db.collection.aggregate([{ $limit: 2 },])\n");
+
+    let output = mongorph(&["--ast", "limit(2)"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "[\n    Limit(\n        2,\n    ),\n]\n");
+}
+
+#[test]
+fn fails_with_a_diagnostic_on_an_invalid_query() {
+    let output = mongorph(&["match(a ==)"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    assert_stderr(
+        &output,
+        "error: comparator has nothing on its right\n  --> line 1, column 9\n  |\n1 | match(a ==)\n  |         ^^ expected a value after this\n",
+    );
+
+    let output = mongorph(&["--bogus"], "");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).starts_with("unknown flag `--bogus`\n"));
+}
+
+#[test]
+fn check_is_silent_on_success_and_fails_with_the_error() {
+    let output = mongorph(&["--check", "match(a == 1)"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "");
+    assert_stderr(&output, "");
+
+    let output = mongorph(&["--check", "match(a = 1)"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    assert_stderr(
+        &output,
+        "error: unexpected character\n  --> line 1, column 9\n  |\n1 | match(a = 1)\n  |         ^ not part of the language\n",
+    );
+}
+
+#[test]
+fn format_prints_or_rewrites_the_canonical_form() {
+    let output = mongorph(&["--format"], "  match( a==1 &b==\"x\" ).limit( 3 );limit(2)");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "match(&((a == 1)(b == \"x\"))).limit(3);\nlimit(2)\n");
+
+    let path = std::env::temp_dir().join(format!("mongorph-cli-{}.mg", std::process::id()));
+    std::fs::write(&path, "match(a==1)  .limit(2)").unwrap();
+    let output = mongorph(&["--format", "--write", "--file", path.to_str().unwrap()], "");
+    let rewritten = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "");
+    assert_eq!(rewritten, "match(a == 1).limit(2)\n");
+
+    let output = mongorph(&["--format"], "match(a == 1) // keep me\n");
+    assert_eq!(output.status.code(), Some(1));
+    assert_stderr(&output, "error: the query has `//` comments, formatting would drop them\n");
+}
+
+#[test]
+fn every_pipeline_is_printed_and_checked() {
+    let output = mongorph(&["match(x == 1); limit(1)"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "db.collection.aggregate([{ $match: { x: { $eq: 1 } } },])\ndb.collection.aggregate([{ $limit: 1 },])\n");
+
+    let output = mongorph(&["--ast", "limit(1);limit(2)"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "[\n    Limit(\n        1,\n    ),\n]\n[\n    Limit(\n        2,\n    ),\n]\n");
+
+    let output = mongorph(&["--check"], "limit(1);\nmatch(a == 1)");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "");
+
+    let output = mongorph(&["--check"], "limit(1);\nmatch(a = 1)");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+}